@@ -0,0 +1,443 @@
+use std::fs;
+use std::process::Command;
+use std::time::SystemTime;
+
+use crate::executor::{execute, Backend, Environment};
+use crate::logging::format_utc;
+use crate::{daemon, debug_message, headless, nvidiagpu};
+
+/*
+ * autotune searches for the highest stable clock and memory offsets of the selected GPU:
+ *
+ *   gpu 0 autotune --test "./gpu_burn 60" --out gpu0.tgh
+ *
+ * Each offset is stepped up from 0 and the stability test is run after every step. A step fails if
+ * the test exits non-zero, prints one of the error texts, or the kernel log gains an NVIDIA Xid
+ * error while it runs. A failure goes back to the last stable offset and halves the step, until the
+ * step is below the smallest one. The clock is tuned first, then the memory with the clock applied.
+ *
+ * The result less a safety margin is tested once more and written as a run-file profile. The search
+ * works on the Tuner trait so it can be tested without a GPU.
+ *
+ * The test also gets the offsets it runs at, so with backend = dry-run, where the offsets are only
+ * printed, a scripted test can stand in for the GPU and fail at a chosen offset.
+ */
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Offset {
+    Core,
+    // In MHz of the memory clock, nvidia-settings is given double
+    Memory,
+}
+
+impl Offset {
+    fn name(&self) -> &'static str {
+        match self {
+            Offset::Core => "clock offset",
+            Offset::Memory => "memory offset",
+        }
+    }
+}
+
+pub trait Tuner {
+    // Sets an offset in MHz, an Err stops the search
+    fn apply(&mut self, offset: Offset, mhz: i32) -> Result<(), String>;
+    // Runs the stability test at the current offsets, an Err is why it failed
+    fn test(&mut self) -> Result<(), String>;
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Search {
+    pub max: i32,
+    pub step: i32,
+    pub min_step: i32,
+}
+
+const CORE_SEARCH: Search = Search { max: 300, step: 50, min_step: 10 };
+const MEMORY_SEARCH: Search = Search { max: 1500, step: 200, min_step: 25 };
+const DEFAULT_MARGIN: f64 = 10.0;
+const DEFAULT_ERROR_TEXTS: [&str; 3] = ["FAIL", "ERROR", "FAULTY"];
+
+// Returns the highest offset the test passed at, which is left applied
+pub fn search(tuner: &mut dyn Tuner, offset: Offset, search: &Search) -> Result<i32, String> {
+    let mut stable = 0;
+    let mut step = search.step;
+
+    while step >= search.min_step {
+        let next = (stable + step).min(search.max);
+        if next <= stable {
+            break;
+        }
+
+        tuner.apply(offset, next)?;
+
+        match tuner.test() {
+            Ok(()) => {
+                println!("{} +{} MHz: stable", offset.name(), next);
+                stable = next;
+            },
+            Err(reason) => {
+                println!("{} +{} MHz: failed, {}", offset.name(), next, reason);
+                tuner.apply(offset, stable)?;
+                step /= 2;
+            },
+        }
+    }
+
+    Ok(stable)
+}
+
+// Takes margin percent off, so the result is not right at the edge of stability
+pub fn with_margin(mhz: i32, margin: f64) -> i32 {
+    (mhz as f64 * (1.0 - margin / 100.0)).floor().max(0.0) as i32
+}
+
+/*
+ * Tunes the offsets that have a search, applies the results less the margin and tests them once
+ * more. Returns the clock and memory offsets, 0 for one that was not tuned.
+ */
+pub fn tune(tuner: &mut dyn Tuner, core: Option<&Search>, memory: Option<&Search>, margin: f64) -> Result<(i32, i32), String> {
+    let mut result = (0, 0);
+
+    if let Some(s) = core {
+        result.0 = with_margin(search(tuner, Offset::Core, s)?, margin);
+        tuner.apply(Offset::Core, result.0)?;
+    }
+
+    if let Some(s) = memory {
+        result.1 = with_margin(search(tuner, Offset::Memory, s)?, margin);
+        tuner.apply(Offset::Memory, result.1)?;
+    }
+
+    tuner.test().map_err(|reason| format!("The result, clock +{} MHz and memory +{} MHz, failed its final test: {}", result.0, result.1, reason))?;
+    Ok(result)
+}
+
+// The NVIDIA Xid lines of the kernel log, or None if it cannot be read
+fn kernel_xids(env: &Environment) -> Option<Vec<String>> {
+    ["dmesg", "journalctl -k -q -o cat --no-pager"].iter()
+        .filter_map(|cmd| execute(env, &cmd.to_string()).ok())
+        .find(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).lines().filter(|l| l.contains("Xid")).map(|l| l.to_string()).collect())
+}
+
+// Sets the offsets with nvidia-settings and runs a shell command as the stability test
+pub struct GpuTuner<'a> {
+    env: &'a Environment,
+    gpu: usize,
+    test: String,
+    error_texts: Vec<String>,
+    warned_xid: bool,
+    // The offsets applied so far, in MHz
+    core: i32,
+    memory: i32,
+}
+
+impl<'a> GpuTuner<'a> {
+    // An empty error_texts uses the defaults
+    pub fn new(env: &'a Environment, gpu: usize, test: &str, error_texts: Vec<String>) -> GpuTuner<'a> {
+        let error_texts = if error_texts.is_empty() { DEFAULT_ERROR_TEXTS.iter().map(|e| e.to_string()).collect() } else { error_texts };
+        GpuTuner { env, gpu, test: test.to_string(), error_texts, warned_xid: false, core: 0, memory: 0 }
+    }
+}
+
+impl Tuner for GpuTuner<'_> {
+    fn apply(&mut self, offset: Offset, mhz: i32) -> Result<(), String> {
+        match offset {
+            Offset::Core => {
+                daemon::set_through(self.env, self.gpu, "clockoffset", mhz.to_string(),
+                                    || debug_message(self.env, nvidiagpu::set_core_offset(self.env, &mut self.gpu, mhz), "Clock Offset"))?;
+                self.core = mhz;
+            },
+            Offset::Memory => {
+                daemon::set_through(self.env, self.gpu, "memoryoffset", format!("{}MHz", mhz),
+                                    || debug_message(self.env, nvidiagpu::set_memory_offset(self.env, &mut self.gpu, mhz * 2), "Memory Offset"))?;
+                self.memory = mhz;
+            },
+        }
+        Ok(())
+    }
+
+    fn test(&mut self) -> Result<(), String> {
+        let before = kernel_xids(self.env);
+        if before.is_none() && !self.warned_xid {
+            self.warned_xid = true;
+            println!("The kernel log cannot be read, so Xid errors are not checked. Run as root or allow dmesg to check them.");
+        }
+
+        // TGH_GPU tells the test which GPU is being tuned, in nvidia-smi's numbering, and the offsets are in MHz
+        let output = Command::new("sh").arg("-c").arg(&self.test)
+            .env("TGH_GPU", self.gpu.to_string())
+            .env("TGH_CLOCK_OFFSET", self.core.to_string())
+            .env("TGH_MEMORY_OFFSET", self.memory.to_string())
+            .output()
+            .map_err(|e| format!("the test could not be run: {}", e))?;
+
+        if let (Some(before), Some(after)) = (before, kernel_xids(self.env)) {
+            if let Some(xid) = after.iter().find(|l| !before.contains(l)) {
+                return Err(format!("the kernel logged {}", xid.trim()));
+            }
+        }
+
+        if !output.status.success() {
+            return Err(format!("the test exited with {}", output.status));
+        }
+
+        let text = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+        match text.lines().find(|l| self.error_texts.iter().any(|e| l.contains(e.as_str()))) {
+            Some(line) => Err(format!("the test printed '{}'", line.trim())),
+            None => Ok(()),
+        }
+    }
+}
+
+struct Options {
+    test: String,
+    out: String,
+    core: Option<Search>,
+    memory: Option<Search>,
+    margin: f64,
+    error_texts: Vec<String>,
+}
+
+fn parse_options(args: &[&String]) -> Result<Options, String> {
+    let mut test = None;
+    let mut out = None;
+    let mut core = CORE_SEARCH;
+    let mut memory = MEMORY_SEARCH;
+    let mut only = None;
+    let mut margin = DEFAULT_MARGIN;
+    let mut error_texts = Vec::new();
+
+    for pair in args.chunks(2) {
+        let (flag, value) = match pair {
+            [flag, value] => (flag.as_str(), value.as_str()),
+            _ => return Err(format!("'{}' needs a value.", pair[0])),
+        };
+        let mhz = || value.trim_start_matches('+').trim_end_matches("MHz").parse::<i32>().ok().filter(|n| *n > 0)
+            .ok_or_else(|| format!("'{}' is not a positive number of MHz.", value));
+
+        match flag {
+            "--test" => test = Some(value.to_string()),
+            "--out" => out = Some(value.to_string()),
+            "--core-max" => core.max = mhz()?,
+            "--core-step" => core.step = mhz()?,
+            "--memory-max" => memory.max = mhz()?,
+            "--memory-step" => memory.step = mhz()?,
+            "--margin" => margin = value.trim_end_matches('%').parse::<f64>().ok().filter(|m| (0.0..100.0).contains(m))
+                .ok_or_else(|| format!("'{}' is not a margin between 0% and 100%.", value))?,
+            "--only" => only = Some(match value {
+                "core" | "clock" => Offset::Core,
+                "memory" => Offset::Memory,
+                _ => return Err(format!("'{}' is not an offset to tune. Expected core or memory.", value)),
+            }),
+            "--error-text" => error_texts.push(value.to_string()),
+            _ => return Err(format!("'{}' is not an autotune option. Expected --test, --out, --core-max, --core-step, --memory-max, \
+                                     --memory-step, --margin, --only or --error-text.", flag)),
+        }
+    }
+
+    // The step never goes below min_step, so a small step given by the user lowers it too
+    core.min_step = core.min_step.min(core.step);
+    memory.min_step = memory.min_step.min(memory.step);
+
+    Ok(Options {
+        test: test.ok_or_else(|| String::from("autotune needs a stability test, e.g. autotune --test \"./gpu_burn 60\" --out gpu0.tgh"))?,
+        out: out.ok_or_else(|| String::from("autotune needs a profile to write, e.g. --out gpu0.tgh"))?,
+        core: if only.is_none_or(|o| o == Offset::Core) { Some(core) } else { None },
+        memory: if only.is_none_or(|o| o == Offset::Memory) { Some(memory) } else { None },
+        margin,
+        error_texts,
+    })
+}
+
+pub fn run(env: &mut Environment, gpu: &usize, args: &[&String]) -> Result<(), String> {
+    let options = parse_options(args)?;
+
+    let simulated = env.backend == Backend::DryRun;
+    if simulated {
+        println!("backend = dry-run only prints the offsets, so the test runs against the GPU's current settings. \
+                  It gets the offsets being tried in $TGH_CLOCK_OFFSET and $TGH_MEMORY_OFFSET.");
+    } else {
+        headless::ensure(env)?;
+    }
+
+    println!("Tuning GPU {} with '{}'. This runs the test after every step and can take a long time.", gpu, options.test);
+
+    let mut tuner = GpuTuner::new(env, *gpu, &options.test, options.error_texts);
+    let (core, memory) = match tune(&mut tuner, options.core.as_ref(), options.memory.as_ref(), options.margin) {
+        Ok(result) => result,
+        Err(e) => {
+            // Leave the GPU at its defaults rather than at whatever step failed
+            let _ = tuner.apply(Offset::Core, 0);
+            let _ = tuner.apply(Offset::Memory, 0);
+            return Err(format!("Autotune failed and the offsets were reset. {}", e));
+        }
+    };
+
+    let mut profile = format!("# Found by autotune on {} with: {}\n# {}% below the highest stable offsets\n",
+                              format_utc(SystemTime::now(), false), options.test, options.margin);
+    if simulated {
+        profile.push_str("# Simulated with backend = dry-run, these offsets were never applied to the GPU\n");
+    }
+    profile.push_str(&format!("gpu {}", gpu));
+    if options.core.is_some() {
+        profile.push_str(&format!(" clockoffset +{}MHz", core));
+    }
+    if options.memory.is_some() {
+        profile.push_str(&format!(" memoryoffset +{}MHz", memory));
+    }
+    profile.push('\n');
+
+    fs::write(&options.out, profile).map_err(|e| format!("Failed to write {}. Error: {}", options.out, e))?;
+
+    if simulated {
+        println!("The simulated GPU {} is stable at clock +{} MHz and memory +{} MHz.", gpu, core, memory);
+    } else {
+        println!("GPU {} is stable at clock +{} MHz and memory +{} MHz, which are applied now.", gpu, core, memory);
+    }
+    println!("Wrote {}, apply it again with 'run-file {}'.", options.out, options.out);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fails the test once an offset is over its limit, and the setter on call fail_apply_at
+    #[derive(Default)]
+    struct SimulatedGpu {
+        core_limit: i32,
+        memory_limit: i32,
+        fail_apply_at: Option<usize>,
+        core: i32,
+        memory: i32,
+        applied: Vec<(Offset, i32)>,
+        tests: u32,
+    }
+
+    impl Tuner for SimulatedGpu {
+        fn apply(&mut self, offset: Offset, mhz: i32) -> Result<(), String> {
+            if self.fail_apply_at == Some(self.applied.len()) {
+                return Err(String::from("setter failed"));
+            }
+            self.applied.push((offset, mhz));
+
+            match offset {
+                Offset::Core => self.core = mhz,
+                Offset::Memory => self.memory = mhz,
+            }
+            Ok(())
+        }
+
+        fn test(&mut self) -> Result<(), String> {
+            self.tests += 1;
+
+            if self.core > self.core_limit || self.memory > self.memory_limit {
+                Err(String::from("crashed"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn search_backs_off_and_narrows_down_to_the_limit() {
+        let mut gpu = SimulatedGpu { core_limit: 137, ..SimulatedGpu::default() };
+
+        let found = search(&mut gpu, Offset::Core, &Search { max: 300, step: 50, min_step: 10 }).unwrap();
+
+        assert_eq!(found, 137);
+        let steps: Vec<i32> = gpu.applied.iter().map(|(_, mhz)| *mhz).collect();
+        assert_eq!(steps, vec![50, 100, 150, 100, 125, 150, 125, 137, 149, 137]);
+        assert_eq!(gpu.core, 137);
+    }
+
+    #[test]
+    fn search_stops_at_the_maximum() {
+        let mut gpu = SimulatedGpu { core_limit: 1000, ..SimulatedGpu::default() };
+
+        assert_eq!(search(&mut gpu, Offset::Core, &Search { max: 120, step: 50, min_step: 10 }).unwrap(), 120);
+        assert_eq!(gpu.applied.last(), Some(&(Offset::Core, 120)));
+    }
+
+    #[test]
+    fn search_that_fails_the_first_step_stays_at_zero() {
+        let mut gpu = SimulatedGpu { core_limit: 5, ..SimulatedGpu::default() };
+
+        assert_eq!(search(&mut gpu, Offset::Core, &Search { max: 300, step: 50, min_step: 10 }).unwrap(), 0);
+        assert_eq!(gpu.core, 0);
+    }
+
+    #[test]
+    fn tune_applies_both_results_less_the_margin() {
+        let mut gpu = SimulatedGpu { core_limit: 137, memory_limit: 700, ..SimulatedGpu::default() };
+        let core = Search { max: 300, step: 50, min_step: 10 };
+        let memory = Search { max: 1500, step: 200, min_step: 25 };
+
+        let (core_offset, memory_offset) = tune(&mut gpu, Some(&core), Some(&memory), 10.0).unwrap();
+
+        assert_eq!((core_offset, memory_offset), (123, 630));
+        assert_eq!((gpu.core, gpu.memory), (123, 630));
+        // The memory is searched with the clock result already applied
+        assert!(gpu.applied.iter().position(|a| *a == (Offset::Core, 123)) < gpu.applied.iter().position(|a| a.0 == Offset::Memory));
+    }
+
+    #[test]
+    fn tune_only_searches_the_offsets_asked_for() {
+        let mut gpu = SimulatedGpu { core_limit: 1000, memory_limit: 400, ..SimulatedGpu::default() };
+
+        let result = tune(&mut gpu, None, Some(&Search { max: 1500, step: 200, min_step: 25 }), 0.0).unwrap();
+
+        assert_eq!(result, (0, 400));
+        assert!(gpu.applied.iter().all(|(offset, _)| *offset == Offset::Memory));
+    }
+
+    #[test]
+    fn failing_setter_stops_the_search() {
+        let mut gpu = SimulatedGpu { core_limit: 1000, fail_apply_at: Some(2), ..SimulatedGpu::default() };
+
+        let error = tune(&mut gpu, Some(&Search { max: 300, step: 50, min_step: 10 }), None, 10.0).err().unwrap();
+
+        assert_eq!(error, "setter failed");
+        assert_eq!(gpu.applied, vec![(Offset::Core, 50), (Offset::Core, 100)]);
+        assert_eq!(gpu.tests, 2);
+    }
+
+    #[test]
+    fn margin_rounds_down_and_never_goes_negative() {
+        assert_eq!(with_margin(137, 10.0), 123);
+        assert_eq!(with_margin(0, 10.0), 0);
+        assert_eq!(with_margin(100, 0.0), 100);
+    }
+
+    fn dry_run() -> Environment {
+        Environment { backend: Backend::DryRun, ..Environment::default() }
+    }
+
+    #[test]
+    fn gpu_tuner_finds_a_scripted_failure_point_in_dry_run() {
+        let env = dry_run();
+        let mut tuner = GpuTuner::new(&env, 0, "test \"$TGH_CLOCK_OFFSET\" -le 137 && test \"$TGH_MEMORY_OFFSET\" -le 700", Vec::new());
+        let core = Search { max: 300, step: 50, min_step: 10 };
+        let memory = Search { max: 1500, step: 200, min_step: 25 };
+
+        assert_eq!(tune(&mut tuner, Some(&core), Some(&memory), 10.0), Ok((123, 630)));
+        assert_eq!((tuner.core, tuner.memory), (123, 630));
+    }
+
+    #[test]
+    fn gpu_tuner_fails_a_step_on_an_error_text() {
+        let env = dry_run();
+        let script = "if [ \"$TGH_CLOCK_OFFSET\" -gt 100 ]; then echo 'GPU 0: FAULTY'; fi";
+        let mut tuner = GpuTuner::new(&env, 0, script, Vec::new());
+
+        assert_eq!(tuner.test(), Ok(()));
+        tuner.apply(Offset::Core, 150).unwrap();
+        assert_eq!(tuner.test(), Err(String::from("the test printed 'GPU 0: FAULTY'")));
+
+        let mut custom = GpuTuner::new(&env, 0, "echo 'mismatch at 0x10'", vec![String::from("mismatch")]);
+        assert!(custom.test().is_err());
+        assert_eq!(GpuTuner::new(&env, 0, "exit 3", Vec::new()).test().map_err(|e| e.starts_with("the test exited")), Err(true));
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 pub struct HelperCommand {
     pub(crate) name:String,
     pub(crate) aliases:Vec<String>,
@@ -15,3 +17,49 @@ impl Eq for HelperCommand {}
 pub fn new_command(i_name:String, i_aliases:Vec<String>, i_args:Vec<usize>) -> HelperCommand {
     HelperCommand {name:i_name, aliases:i_aliases, args: i_args}
 }
+
+// Every command the argument parser knows about, keyed by its name
+pub fn default_commands() -> HashMap<String, HelperCommand> {
+    let mut commands = HashMap::new();
+    commands.insert(String::from("help"), new_command(String::from("help"), vec![String::from("--help"), String::from("-h")], vec![0]));
+    commands.insert(String::from("version"), new_command(String::from("version"), vec![String::from("--version"), String::from("-v"), String::from("v")], vec![0]));
+    commands.insert(String::from("xauth"), new_command(String::from("xauth"), vec![String::from("xauthority"), String::from("xa"), String::from("--xauth")], vec![1]));
+    commands.insert(String::from("debug"), new_command(String::from("debug"), vec![String::from("debug"), String::from("--debug")], vec![1]));
+    commands.insert(String::from("display"), new_command(String::from("display"), vec![String::from("dp"), String::from("--display")], vec![1]));
+    commands.insert(String::from("gpu"), new_command(String::from("gpu"), vec![], vec![1]));
+    commands.insert(String::from("fan"), new_command(String::from("fan"), vec![], vec![1, 2]));
+    commands.insert(String::from("memoryoffset"), new_command(String::from("memoryoffset"), vec![String::from("moc"), String::from("--memoc"), String::from("--memory-offset")], vec![1]));
+    commands.insert(String::from("clockoffset"), new_command(String::from("clockoffset"), vec![String::from("--clockoc"), String::from("--clock-offset")], vec![1]));
+    commands.insert(String::from("clock"), new_command(String::from("clock"), vec![String::from("lgc"), String::from("--clock")], vec![1]));
+    commands.insert(String::from("memory"), new_command(String::from("memory"), vec![String::from("lmc"), String::from("--memory")], vec![1]));
+    commands.insert(String::from("power"), new_command(String::from("power"), vec![String::from("pl"), String::from("--power")], vec![1]));
+    commands.insert(String::from("reset"), new_command(String::from("reset"), vec![String::from("r"), String::from("--reset")], vec![0]));
+    commands.insert(String::from("autotune"), new_command(String::from("autotune"), vec![String::from("--autotune")], vec![4, 6, 8, 10, 12, 14, 16, 18, 20]));
+    commands.insert(String::from("sweep"), new_command(String::from("sweep"), vec![String::from("--sweep")], vec![4, 6, 8, 10]));
+    commands.insert(String::from("undervolt"), new_command(String::from("undervolt"), vec![String::from("--undervolt")], vec![1, 4, 6, 8]));
+    commands.insert(String::from("run-file"), new_command(String::from("run-file"), vec![String::from("runfile"), String::from("--run-file")], vec![1]));
+    commands.insert(String::from("shell"), new_command(String::from("shell"), vec![String::from("repl"), String::from("--shell")], vec![0]));
+    commands.insert(String::from("log"), new_command(String::from("log"), vec![String::from("--log")], vec![2, 4, 6, 8, 10]));
+    commands.insert(String::from("guard"), new_command(String::from("guard"), vec![String::from("--guard")], vec![2, 4, 6, 8, 10, 12, 14]));
+    commands.insert(String::from("info"), new_command(String::from("info"), vec![String::from("i"), String::from("--info")], vec![0]));
+    commands.insert(String::from("ecc"), new_command(String::from("ecc"), vec![String::from("--ecc")], vec![1]));
+    commands.insert(String::from("powermizer"), new_command(String::from("powermizer"), vec![String::from("--powermizer")], vec![1]));
+    commands.insert(String::from("persistence"), new_command(String::from("persistence"), vec![String::from("--persistence")], vec![1]));
+    commands.insert(String::from("computemode"), new_command(String::from("computemode"), vec![String::from("compute-mode"), String::from("--compute-mode")], vec![1]));
+    commands.insert(String::from("appclocks"), new_command(String::from("appclocks"), vec![String::from("app-clocks"), String::from("--app-clocks")], vec![1]));
+    commands.insert(String::from("status"), new_command(String::from("status"), vec![String::from("--status")], vec![0]));
+    commands.insert(String::from("health"), new_command(String::from("health"), vec![String::from("--health")], vec![0]));
+    commands.insert(String::from("procs"), new_command(String::from("procs"), vec![String::from("--procs")], vec![0]));
+    commands.insert(String::from("kill"), new_command(String::from("kill"), vec![String::from("--kill")], vec![1, 2, 3, 4, 5]));
+    commands.insert(String::from("wait-idle"), new_command(String::from("wait-idle"), vec![String::from("waitidle"), String::from("--wait-idle")], vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13]));
+    commands.insert(String::from("reserve"), new_command(String::from("reserve"), vec![], vec![0, 2]));
+    commands.insert(String::from("release"), new_command(String::from("release"), vec![String::from("--release")], vec![0]));
+    commands.insert(String::from("why-slow"), new_command(String::from("why-slow"), vec![String::from("whyslow"), String::from("--why-slow")], vec![0]));
+    commands.insert(String::from("doctor"), new_command(String::from("doctor"), vec![String::from("--doctor")], vec![0, 1]));
+    commands.insert(String::from("helper"), new_command(String::from("helper"), vec![String::from("--helper")], vec![0]));
+    commands.insert(String::from("daemon"), new_command(String::from("daemon"), vec![String::from("--daemon")], vec![0]));
+    commands.insert(String::from("exporter"), new_command(String::from("exporter"), vec![String::from("--exporter")], vec![0, 2]));
+    commands.insert(String::from("config"), new_command(String::from("config"), vec![String::from("--config")], vec![0, 1]));
+
+    commands
+}
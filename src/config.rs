@@ -0,0 +1,395 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::executor::{Backend, Environment, OutputFormat, Privilege, DEFAULT_DISPLAY, DEFAULT_XAUTHORITY};
+use crate::daemon;
+use crate::exporter;
+use crate::helper;
+use crate::reservation;
+use crate::state;
+use crate::json;
+
+/*
+ * Settings are layered, each layer overriding the ones before it:
+ *
+ *   1. Built-in defaults
+ *   2. /etc/teamgreenhelper.toml
+ *   3. ~/.config/teamgreenhelper/config.toml ($XDG_CONFIG_HOME is honoured)
+ *   4. TGH_* environment variables, e.g. TGH_DISPLAY or TGH_LIMITS_MAX_POWER
+ *   5. Arguments on the command line, e.g. display :1
+ *
+ * The X display and Xauthority are only discovered automatically when no layer sets them.
+ */
+
+pub const SYSTEM_CONFIG: &str = "/etc/teamgreenhelper.toml";
+
+// Every key that can be configured, its built-in default and what it does
+pub const KEYS: [(&str, &str, &str); 22] = [
+    ("gpu", "0", "GPU that commands apply to until 'gpu' is given"),
+    ("display", DEFAULT_DISPLAY, "X display passed to nvidia-settings"),
+    ("xauthority", DEFAULT_XAUTHORITY, "Xauthority file passed to nvidia-settings"),
+    ("headless", "auto", "auto starts a private X server when none is found, off never does"),
+    ("debug", "false", "Show the output of every command that is run"),
+    ("backend", "system", "system runs nvidia-smi/nvidia-settings, dry-run only prints the changes"),
+    ("output", "text", "Output format of readouts, text or json"),
+    ("privilege", "sudo", "How commands that need root are run: sudo, doas, pkexec, helper, root (already root) or none"),
+    ("helper.socket", helper::DEFAULT_SOCKET, "Unix socket of the privileged helper"),
+    ("helper.policy", helper::DEFAULT_POLICY, "Policy file the helper checks requests against"),
+    ("daemon.socket", daemon::DEFAULT_SOCKET, "Unix socket of the daemon, setters are sent to it while it runs"),
+    ("daemon.profiles", daemon::DEFAULT_PROFILES, "Root-owned directory of the profiles the daemon runs, as <name>.tgh"),
+    ("daemon.fan_curve", "", "Fan curve the daemon drives every fan with, as °C:% points, e.g. \"40:30, 60:50, 80:100\""),
+    ("daemon.schedule", "", "Profiles the daemon runs from a local time of day on, e.g. \"08:00 quiet, 18:30 performance\""),
+    ("exporter.listen", exporter::DEFAULT_LISTEN, "Address the Prometheus exporter listens on"),
+    ("state_dir", state::DEFAULT_DIR, "Machine-wide directory of the state shared by every user, the daemon and the exporter"),
+    ("reservation_dir", reservation::DEFAULT_DIR, "Shared directory of the GPU reservations made by reserve and wait-idle"),
+    ("limits.max_power", "", "Highest power limit in W that may be set"),
+    ("limits.max_core_clock", "", "Highest core clock in MHz that may be locked"),
+    ("limits.max_core_offset", "", "Highest core clock offset in MHz"),
+    ("limits.max_memory_offset", "", "Highest memory clock offset in MHz (half the transfer rate offset)"),
+    ("limits.min_fan_speed", "", "Lowest manual fan speed in %"),
+];
+
+// Each checked key, what a valid value looks like and the check itself
+type Check = (&'static str, &'static str, fn(&str) -> bool);
+
+const CHECKS: [Check; 11] = [
+    ("gpu", "an integer greater than or equal to 0", |v| v.parse::<usize>().is_ok()),
+    ("debug", "true or false", |v| v.parse::<bool>().is_ok()),
+    ("backend", "system or dry-run", |v| ["system", "dry-run"].contains(&v)),
+    ("headless", "auto or off", |v| ["auto", "off"].contains(&v)),
+    ("output", "text or json", |v| ["text", "json"].contains(&v)),
+    ("privilege", "sudo, doas, pkexec, helper, root or none", |v| ["sudo", "doas", "pkexec", "helper", "root", "none"].contains(&v)),
+    ("limits.max_power", "a number", is_limit),
+    ("limits.max_core_clock", "a number", is_limit),
+    ("limits.max_core_offset", "a number", is_limit),
+    ("limits.max_memory_offset", "a number", is_limit),
+    ("limits.min_fan_speed", "a number", is_limit),
+];
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    Variable(String),
+    Discovered,
+    Headless,
+    CommandLine,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Variable(name) => write!(f, "${}", name),
+            Source::Discovered => write!(f, "discovered"),
+            Source::Headless => write!(f, "headless X server"),
+            Source::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Limits {
+    pub max_power: Option<f64>,
+    pub max_core_clock: Option<f64>,
+    pub max_core_offset: Option<f64>,
+    pub max_memory_offset: Option<f64>,
+    pub min_fan_speed: Option<f64>,
+}
+
+#[derive(Clone, Default)]
+pub struct Config {
+    values: BTreeMap<String, (String, Source)>,
+}
+
+impl Config {
+    pub fn get(&self, key: &str) -> &str {
+        self.values.get(key).map_or("", |(value, _)| value.as_str())
+    }
+
+    pub fn source(&self, key: &str) -> Source {
+        self.values.get(key).map_or(Source::Default, |(_, source)| source.clone())
+    }
+
+    pub fn set(&mut self, key: &str, value: &str, source: Source) {
+        self.values.insert(key.to_string(), (value.to_string(), source));
+    }
+
+    /*
+     * Copies the effective values into env. An invalid value is replaced by its default, so a typo
+     * never stops a command from running. Returns a warning for each one, naming the layer the bad
+     * value came from so it can be found.
+     */
+    pub fn apply(&self, env: &mut Environment) -> Vec<String> {
+        let mut config = self.clone();
+        let mut warnings = Vec::new();
+
+        for (key, expected, valid) in CHECKS {
+            if !valid(config.get(key)) {
+                let default = KEYS.iter().find(|(k, _, _)| *k == key).map_or("", |(_, d, _)| d);
+                warnings.push(format!("Invalid {} '{}' from {}. Expected {}. Using the default '{}' instead.", key, config.get(key), config.source(key), expected, default));
+                config.set(key, default, Source::Default);
+            }
+        }
+
+        env.display = config.get("display").to_string();
+        env.xauthority = config.get("xauthority").to_string();
+        env.debug = config.get("debug") == "true";
+
+        env.backend = match config.get("backend") {
+            "dry-run" => Backend::DryRun,
+            _ => Backend::System,
+        };
+
+        env.output = match config.get("output") {
+            "json" => OutputFormat::Json,
+            _ => OutputFormat::Text,
+        };
+
+        env.privilege = match config.get("privilege") {
+            "doas" => Privilege::Doas,
+            "pkexec" => Privilege::Pkexec,
+            "helper" => Privilege::Helper,
+            "root" => Privilege::Root,
+            "none" => Privilege::None,
+            _ => Privilege::Sudo,
+        };
+
+        let limit = |key: &str| config.get(key).parse::<f64>().ok();
+
+        env.limits = Limits {
+            max_power: limit("limits.max_power"),
+            max_core_clock: limit("limits.max_core_clock"),
+            max_core_offset: limit("limits.max_core_offset"),
+            max_memory_offset: limit("limits.max_memory_offset"),
+            min_fan_speed: limit("limits.min_fan_speed"),
+        };
+
+        env.config = config;
+        warnings
+    }
+
+    pub fn default_gpu(&self) -> Option<usize> {
+        self.get("gpu").parse::<usize>().ok()
+    }
+}
+
+// Limits are optional, so empty is valid too
+fn is_limit(value: &str) -> bool {
+    value.is_empty() || value.parse::<f64>().is_ok()
+}
+
+pub fn user_config_path() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")),
+    }
+    .map(|dir| dir.join("teamgreenhelper").join("config.toml"))
+}
+
+// TGH_ followed by the key in upper case with dots as underscores, e.g. TGH_LIMITS_MAX_POWER
+pub fn variable_name(key: &str) -> String {
+    format!("TGH_{}", key.replace('.', "_").to_uppercase())
+}
+
+/*
+ * Builds the configuration from every layer. A file that does not exist is skipped, one that
+ * cannot be parsed is reported and skipped so a typo never stops the GPU from being reset.
+ */
+pub fn load() -> Config {
+    let mut files = vec![PathBuf::from(SYSTEM_CONFIG)];
+    files.extend(user_config_path());
+
+    layered(&files, |name| env::var(name).ok())
+}
+
+// The layers of load, with the files and variables passed in
+fn layered<F: Fn(&str) -> Option<String>>(files: &[PathBuf], variable: F) -> Config {
+    let mut config = Config::default();
+
+    for (key, default, _) in KEYS.iter() {
+        config.set(key, default, Source::Default);
+    }
+
+    for path in files {
+        let contents = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+
+        match parse_toml(&contents) {
+            Ok(pairs) => {
+                for (key, value) in pairs {
+                    if KEYS.iter().any(|(k, _, _)| *k == key) {
+                        config.set(&key, &value, Source::File(path.clone()));
+                    } else {
+                        println!("{}: Unknown setting '{}' was ignored.", path.display(), key);
+                    }
+                }
+            },
+            Err(e) => {
+                println!("{}: {}", path.display(), e);
+            }
+        }
+    }
+
+    for (key, _, _) in KEYS.iter() {
+        let name = variable_name(key);
+        if let Some(value) = variable(&name) {
+            config.set(key, &value, Source::Variable(name));
+        }
+    }
+
+    config
+}
+
+/*
+ * Reads the small subset of TOML the config file needs: comments, [section] headers and
+ * key = value pairs where the value is a string, number or boolean. Keys inside a section are
+ * returned as section.key.
+ */
+pub fn parse_toml(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let mut pairs = Vec::new();
+    let mut section = String::new();
+
+    for (number, raw) in contents.lines().enumerate() {
+        let line = strip_comment(raw).trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            section = line[1..line.len() - 1].trim().to_string();
+            continue;
+        }
+
+        let (key, value) = match line.split_once('=') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => return Err(format!("Line {} is not a key = value pair.", number + 1)),
+        };
+
+        let value = if value.len() >= 2 && ((value.starts_with('"') && value.ends_with('"')) || (value.starts_with('\'') && value.ends_with('\''))) {
+            value[1..value.len() - 1].to_string()
+        } else if value == "true" || value == "false" || value.parse::<f64>().is_ok() {
+            value.to_string()
+        } else {
+            return Err(format!("Line {} has a value that is not a string, number or boolean: {}", number + 1, value));
+        };
+
+        let key = if section.is_empty() { key.to_string() } else { format!("{}.{}", section, key) };
+        pairs.push((key, value));
+    }
+
+    Ok(pairs)
+}
+
+// A # starts a comment unless it is inside a quoted string
+fn strip_comment(line: &str) -> &str {
+    let mut quote: Option<char> = None;
+
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+    }
+
+    line
+}
+
+pub fn show(env: &Environment) {
+    let config = &env.config;
+
+    if env.output == OutputFormat::Json {
+        let settings: Vec<String> = KEYS.iter()
+            .map(|(key, _, _)| json::Object::new()
+                .string("key", key)
+                .string("value", config.get(key))
+                .string("source", &config.source(key).to_string())
+                .build())
+            .collect();
+        println!("{}", json::array(&settings));
+        return;
+    }
+
+    for (key, _, description) in KEYS.iter() {
+        let value = config.get(key);
+        println!("{:<26} = {:<32} ({})", key, if value.is_empty() { "(none)" } else { value }, config.source(key));
+        if env.debug {
+            println!("{:<26}   {}", "", description);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_sections_prefix_their_keys() {
+        let pairs = parse_toml("# system wide\ngpu = 1\ndisplay = \":1\" # the second seat\n\n[limits]\nmax_power = 250.5\n[helper]\nsocket = '/run/x#y.sock'\n").unwrap();
+
+        assert_eq!(pairs, vec![
+            (String::from("gpu"), String::from("1")),
+            (String::from("display"), String::from(":1")),
+            (String::from("limits.max_power"), String::from("250.5")),
+            (String::from("helper.socket"), String::from("/run/x#y.sock")),
+        ]);
+    }
+
+    #[test]
+    fn toml_rejects_lines_it_cannot_read() {
+        assert!(parse_toml("gpu").is_err());
+        assert!(parse_toml("output = json").is_err());
+        assert_eq!(parse_toml("debug = true").unwrap(), vec![(String::from("debug"), String::from("true"))]);
+    }
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let dir = std::env::temp_dir().join(format!("tgh-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let system = dir.join("system.toml");
+        let user = dir.join("user.toml");
+        fs::write(&system, "gpu = 1\noutput = \"json\"\n[limits]\nmax_power = 300\n").unwrap();
+        fs::write(&user, "output = \"text\"\n").unwrap();
+
+        let config = layered(&[system.clone(), user.clone(), dir.join("missing.toml")], |name| {
+            (name == "TGH_LIMITS_MAX_POWER").then(|| String::from("250"))
+        });
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(config.get("gpu"), "1");
+        assert_eq!(config.source("gpu"), Source::File(system));
+        assert_eq!(config.get("output"), "text");
+        assert_eq!(config.source("output"), Source::File(user));
+        assert_eq!(config.get("limits.max_power"), "250");
+        assert_eq!(config.source("limits.max_power"), Source::Variable(String::from("TGH_LIMITS_MAX_POWER")));
+        assert_eq!(config.get("backend"), "system");
+        assert_eq!(config.source("backend"), Source::Default);
+    }
+
+    #[test]
+    fn invalid_values_warn_and_fall_back_to_the_default() {
+        let mut config = layered(&[], |_| None);
+        config.set("debug", "yes", Source::Variable(String::from("TGH_DEBUG")));
+        config.set("limits.max_power", "lots", Source::CommandLine);
+        config.set("backend", "dry-run", Source::CommandLine);
+
+        let mut env = Environment::default();
+        let warnings = config.apply(&mut env);
+
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("'yes' from $TGH_DEBUG"));
+        assert!(!env.debug);
+        assert_eq!(env.limits.max_power, None);
+        assert_eq!(env.backend, Backend::DryRun);
+        assert_eq!(env.config.get("debug"), "false");
+    }
+}
@@ -0,0 +1,232 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/*
+ * Coolbits is the xorg.conf option that unlocks nvidia-settings controls. It is a bit mask and the
+ * bits that matter on current GPUs are:
+ *
+ *    4  manual fan control
+ *    8  clock offsets per performance level
+ *   16  overvoltage
+ *
+ * The active value is read from the Xorg log, which also names the config file that was used.
+ */
+
+pub const FEATURES: [(u32, &str, &str); 3] = [
+    (4, "fan control", "fan"),
+    (8, "clock offsets", "clockoffset, memoryoffset"),
+    (16, "overvoltage", "GPUOverVoltageOffset in nvidia-settings"),
+];
+
+// Every feature this program uses
+pub const RECOMMENDED: u32 = 28;
+
+pub const DROP_IN: &str = "20-teamgreenhelper-coolbits.conf";
+const DEFAULT_CONFIG_DIR: &str = "/etc/X11/xorg.conf.d";
+
+#[derive(Clone, Debug, Default)]
+pub struct XorgLog {
+    pub path: PathBuf,
+    pub config_file: Option<String>,
+    pub config_dir: Option<String>,
+    // Coolbits per NVIDIA screen, in the order the screens appear
+    pub coolbits: Vec<(usize, u32)>,
+    // PCI bus id per GPU-N
+    pub bus_ids: Vec<(usize, String)>,
+}
+
+impl XorgLog {
+    pub fn coolbits_for(&self, screen: usize) -> Option<u32> {
+        self.coolbits.iter().find(|(s, _)| *s == screen).map(|(_, v)| *v)
+    }
+}
+
+// Xorg.<n>.log is in /var/log for root servers and ~/.local/share/xorg for rootless ones
+pub fn find_log(display: &str) -> Option<PathBuf> {
+    let number = display.trim_start_matches(':').split('.').next().unwrap_or("0");
+    let name = format!("Xorg.{}.log", number);
+
+    let mut candidates = vec![PathBuf::from("/var/log").join(&name)];
+    if let Some(home) = env::var_os("HOME") {
+        candidates.push(PathBuf::from(home).join(".local/share/xorg").join(&name));
+    }
+
+    candidates.into_iter().filter(|p| p.exists()).max_by_key(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+}
+
+fn quoted(line: &str, after: &str) -> Option<String> {
+    let rest = &line[line.find(after)? + after.len()..];
+    let start = rest.find('"')? + 1;
+    let end = rest[start..].find('"')? + start;
+    Some(rest[start..end].to_string())
+}
+
+// The N in NVIDIA(N) or NVIDIA(GPU-N)
+fn nvidia_index(line: &str, prefix: &str) -> Option<usize> {
+    let rest = &line[line.find(prefix)? + prefix.len()..];
+    rest[..rest.find(')')?].parse::<usize>().ok()
+}
+
+pub fn parse_log(path: &Path, contents: &str) -> XorgLog {
+    let mut log = XorgLog { path: path.to_path_buf(), ..XorgLog::default() };
+
+    for line in contents.lines() {
+        if line.contains("Using config file:") {
+            log.config_file = quoted(line, "Using config file:");
+        } else if line.contains("Using config directory:") {
+            log.config_dir = quoted(line, "Using config directory:");
+        } else if line.contains("Option \"Coolbits\"") {
+            let screen = nvidia_index(line, "NVIDIA(");
+            let value = quoted(line, "Option \"Coolbits\"").and_then(|v| v.parse::<u32>().ok());
+
+            if let (Some(screen), Some(value)) = (screen, value) {
+                log.coolbits.retain(|(s, _)| *s != screen);
+                log.coolbits.push((screen, value));
+            }
+        } else if line.contains("NVIDIA(GPU-") && line.contains(" at PCI:") {
+            if let Some(gpu) = nvidia_index(line, "NVIDIA(GPU-") {
+                let bus = line[line.find(" at PCI:").unwrap_or(0) + 4..].split_whitespace().next().unwrap_or("").to_string();
+                log.bus_ids.retain(|(g, _)| *g != gpu);
+                log.bus_ids.push((gpu, bus));
+            }
+        }
+    }
+
+    log
+}
+
+pub fn read_log(display: &str) -> Option<XorgLog> {
+    let path = find_log(display)?;
+    let contents = fs::read_to_string(&path).ok()?;
+    Some(parse_log(&path, &contents))
+}
+
+/*
+ * Sets Option "Coolbits" in every Device section of an xorg.conf, replacing an existing value or
+ * adding it before EndSection. Everything else in the file is left as it was.
+ */
+pub fn set_in_config(contents: &str, coolbits: u32) -> String {
+    let mut out = Vec::new();
+    let mut in_device = false;
+    let mut has_option = false;
+    let mut indent = String::from("    ");
+
+    for line in contents.lines() {
+        let trimmed = line.trim();
+        let lower = trimmed.to_lowercase();
+
+        if lower.starts_with("section") && lower.contains("\"device\"") {
+            in_device = true;
+            has_option = false;
+        } else if in_device && lower.starts_with("endsection") {
+            if !has_option {
+                out.push(format!("{}Option \"Coolbits\" \"{}\"", indent, coolbits));
+            }
+            in_device = false;
+        } else if in_device && lower.starts_with("option") && lower.contains("\"coolbits\"") {
+            // Only the value changes so the file keeps its alignment
+            let end = line.to_lowercase().find("\"coolbits\"").unwrap_or(0) + "\"coolbits\"".len();
+            let rest = &line[end..];
+            let gap = &rest[..rest.len() - rest.trim_start().len()];
+            out.push(format!("{}{}\"{}\"", &line[..end], if gap.is_empty() { " " } else { gap }, coolbits));
+            has_option = true;
+            continue;
+        } else if in_device && !trimmed.is_empty() {
+            indent = line[..line.len() - line.trim_start().len()].to_string();
+        }
+
+        out.push(line.to_string());
+    }
+
+    out.join("\n") + "\n"
+}
+
+// A drop-in with one Device section per GPU, or a single one for every GPU if none were listed
+pub fn drop_in(bus_ids: &[String], coolbits: u32) -> String {
+    let mut conf = String::from("# Generated by teamgreenhelper to unlock fan control and clock offsets\n");
+    let any = [String::new()];
+
+    for (i, bus_id) in (if bus_ids.is_empty() { &any[..] } else { bus_ids }).iter().enumerate() {
+        conf.push_str(&format!("\nSection \"Device\"\n    Identifier \"teamgreenhelper{}\"\n    Driver \"nvidia\"\n", i));
+        if !bus_id.is_empty() {
+            conf.push_str(&format!("    BusID \"{}\"\n", bus_id));
+        }
+        conf.push_str(&format!("    Option \"Coolbits\" \"{}\"\nEndSection\n", coolbits));
+    }
+
+    conf
+}
+
+/*
+ * Works out which file to change and what it should contain. An xorg.conf that is in use gets its
+ * Device sections updated, otherwise a drop-in is added to the config directory.
+ */
+pub fn proposal(log: Option<&XorgLog>, bus_ids: &[String], coolbits: u32) -> (PathBuf, String, String) {
+    if let Some(file) = log.and_then(|l| l.config_file.as_ref()) {
+        if let Ok(current) = fs::read_to_string(file) {
+            if current.to_lowercase().contains("\"device\"") {
+                let updated = set_in_config(&current, coolbits);
+                return (PathBuf::from(file), current, updated);
+            }
+        }
+    }
+
+    let dir = log.and_then(|l| l.config_dir.clone()).unwrap_or_else(|| String::from(DEFAULT_CONFIG_DIR));
+    let path = Path::new(&dir).join(DROP_IN);
+    let current = fs::read_to_string(&path).unwrap_or_default();
+    (path, current, drop_in(bus_ids, coolbits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG: &str = r#"[    25.101] (==) Using config file: "/etc/X11/xorg.conf"
+[    25.101] (==) Using config directory: "/etc/X11/xorg.conf.d"
+[    25.502] (**) NVIDIA(0): Option "Coolbits" "4"
+[    25.502] (**) NVIDIA(1): Option "Coolbits" "12"
+[    25.610] (II) NVIDIA(GPU-0): NVIDIA GeForce RTX 3080 (GA102-A) at PCI:1:0:0 (GPU-0)
+[    25.611] (II) NVIDIA(GPU-1): NVIDIA GeForce RTX 3070 (GA104-A) at PCI:10:0:0 (GPU-1)
+[    30.002] (**) NVIDIA(0): Option "Coolbits" "28"
+"#;
+
+    #[test]
+    fn log_names_the_config_and_the_last_coolbits_of_each_screen() {
+        let log = parse_log(Path::new("/var/log/Xorg.0.log"), LOG);
+
+        assert_eq!(log.config_file.as_deref(), Some("/etc/X11/xorg.conf"));
+        assert_eq!(log.config_dir.as_deref(), Some("/etc/X11/xorg.conf.d"));
+        assert_eq!(log.coolbits_for(0), Some(28));
+        assert_eq!(log.coolbits_for(1), Some(12));
+        assert_eq!(log.coolbits_for(2), None);
+        assert_eq!(log.bus_ids, vec![(0, String::from("PCI:1:0:0")), (1, String::from("PCI:10:0:0"))]);
+    }
+
+    #[test]
+    fn coolbits_is_replaced_or_added_in_every_device_section() {
+        let conf = "Section \"Device\"\n\tIdentifier \"gpu0\"\n\tOption     \"Coolbits\" \"4\"\nEndSection\n\
+                    Section \"Device\"\n\tIdentifier \"gpu1\"\nEndSection\n\
+                    Section \"Screen\"\n\tIdentifier \"screen0\"\nEndSection\n";
+
+        let updated = set_in_config(conf, 28);
+
+        assert_eq!(updated, "Section \"Device\"\n\tIdentifier \"gpu0\"\n\tOption     \"Coolbits\" \"28\"\nEndSection\n\
+                             Section \"Device\"\n\tIdentifier \"gpu1\"\n\tOption \"Coolbits\" \"28\"\nEndSection\n\
+                             Section \"Screen\"\n\tIdentifier \"screen0\"\nEndSection\n");
+        assert_eq!(set_in_config(&updated, 28), updated);
+    }
+
+    #[test]
+    fn drop_in_has_a_device_per_gpu_or_one_for_all() {
+        let per_gpu = drop_in(&[String::from("PCI:1:0:0"), String::from("PCI:10:0:0")], 28);
+        assert_eq!(per_gpu.matches("Section \"Device\"").count(), 2);
+        assert!(per_gpu.contains("BusID \"PCI:10:0:0\""));
+        assert_eq!(per_gpu.matches("Option \"Coolbits\" \"28\"").count(), 2);
+
+        let any = drop_in(&[], 12);
+        assert_eq!(any.matches("Section \"Device\"").count(), 1);
+        assert!(!any.contains("BusID"));
+        assert!(any.contains("Option \"Coolbits\" \"12\""));
+    }
+}
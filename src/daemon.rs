@@ -0,0 +1,474 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::commands::{default_commands, HelperCommand};
+use crate::executor::{Backend, Environment};
+use crate::fancurve::{self, FanController};
+use crate::json::{self, Value};
+use crate::schedule::{self, Scheduler};
+use crate::{cmd_exists, headless, nvidiagpu, run_tokens, script, signals};
+
+/*
+ * The daemon owns the GPUs: while it runs, the setters of every other teamgreenhelper are sent to it
+ * instead of being run directly, so two tools never drive the same fan or clock at once. Requests
+ * are handled one at a time on a single thread, and the Environment (including a headless X server)
+ * lives as long as the daemon does. Between requests it drives the fan curve of daemon.fan_curve
+ * (see fancurve.rs) and runs the profiles of daemon.schedule (see schedule.rs).
+ *
+ * It speaks JSON-RPC 2.0 over a Unix socket, one message per line:
+ *
+ *   query          {"gpu": 0, "fields": "power.draw,fan.speed"}   both optional, every GPU by default
+ *   set            {"gpu": 0, "command": "power", "args": ["250W"]}
+ *   apply-profile  {"gpu": 0, "profile": "quiet"}   runs quiet.tgh from daemon.profiles
+ *   subscribe      {"interval": 5, "fields": "..."}   then sends "metrics" notifications every interval
+ *   unsubscribe
+ *
+ * Profiles are run-file scripts, but as the daemon usually runs as root they are only read from a
+ * root-owned directory nobody else can write to, and only gpu and the FORWARDED_COMMANDS work in them.
+ */
+
+pub const DEFAULT_SOCKET: &str = "/run/teamgreenhelper-daemon.sock";
+pub const DEFAULT_PROFILES: &str = "/etc/teamgreenhelper/profiles";
+
+// Commands that are sent to the daemon when it is running
+pub const FORWARDED_COMMANDS: [&str; 12] = ["fan", "clock", "memory", "memoryoffset", "clockoffset", "power", "powermizer", "persistence", "computemode",
+    "appclocks", "reset", "undervolt"];
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const SERVER_ERROR: i32 = -32000;
+
+struct Subscription {
+    interval: Duration,
+    fields: String,
+    next: Instant,
+}
+
+struct Client {
+    stream: UnixStream,
+    buffer: Vec<u8>,
+    subscription: Option<Subscription>,
+    closed: bool,
+}
+
+impl Client {
+    fn read(&mut self) {
+        let mut chunk = [0u8; 4096];
+
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.closed = true;
+                    return;
+                },
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    self.closed = true;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn next_line(&mut self) -> Option<String> {
+        let end = self.buffer.iter().position(|b| *b == b'\n')?;
+        let line: Vec<u8> = self.buffer.drain(..=end).collect();
+        Some(String::from_utf8_lossy(&line).trim().to_string())
+    }
+
+    // A client that stops reading fills the socket buffer and is dropped rather than stalling the daemon
+    fn send(&mut self, message: &str) {
+        if self.stream.write_all(format!("{}\n", message).as_bytes()).is_err() {
+            self.closed = true;
+        }
+    }
+}
+
+fn response(id: &Value, result: String) -> String {
+    json::Object::new().string("jsonrpc", "2.0").raw("id", id.encode()).raw("result", result).build()
+}
+
+fn error(id: &Value, code: i32, message: &str) -> String {
+    let error = json::Object::new().raw("code", code.to_string()).string("message", message).build();
+    json::Object::new().string("jsonrpc", "2.0").raw("id", id.encode()).raw("error", error).build()
+}
+
+// Numbers are sent as numbers, everything else (names, [N/A]) as strings
+fn encode_field(value: &str) -> String {
+    match value.parse::<f64>() {
+        Ok(n) => json::number(n),
+        Err(_) => json::string(value),
+    }
+}
+
+// One object per GPU with the index and every field asked for
+fn query(env: &Environment, gpu: Option<usize>, fields: &str) -> Result<Vec<String>, String> {
+    let rows = nvidiagpu::query_all_gpus(env, fields)?;
+    let names: Vec<&str> = fields.split(',').map(|f| f.trim()).collect();
+
+    Ok(rows.iter()
+        .filter(|row| gpu.is_none_or(|g| row[0] == g.to_string()))
+        .map(|row| {
+            let object = json::Object::new().raw("gpu", row[0].clone());
+            names.iter().zip(row.iter().skip(1)).fold(object, |o, (name, value)| o.raw(name, encode_field(value))).build()
+        })
+        .collect())
+}
+
+// The command table profiles run with, the setters and gpu. fan is left out while the fan curve runs.
+fn profile_commands(fan_curve: bool) -> HashMap<String, HelperCommand> {
+    default_commands().into_iter()
+        .filter(|(name, _)| name == "gpu" || (FORWARDED_COMMANDS.contains(&name.as_str()) && !(fan_curve && name == "fan")))
+        .collect()
+}
+
+// Owned by root and not writable by anyone else
+fn root_only(path: &Path) -> Result<(), String> {
+    let metadata = fs::metadata(path).map_err(|e| format!("{} could not be read. Error: {}", path.display(), e))?;
+
+    if metadata.uid() != 0 || metadata.mode() & 0o022 != 0 {
+        return Err(format!("{} has to be owned by root and writable only by root.", path.display()));
+    }
+
+    Ok(())
+}
+
+// <dir>/<name>.tgh, where name cannot step out of dir
+fn profile_path(dir: &str, name: &str) -> Result<PathBuf, String> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("'{}' is not a profile name. Names are letters, digits, - and _.", name));
+    }
+
+    let path = Path::new(dir).join(format!("{}.tgh", name));
+    root_only(Path::new(dir))?;
+    root_only(&path)?;
+    Ok(path)
+}
+
+fn apply_profile(env: &mut Environment, name: &str, gpu: usize, fans: &mut Option<FanController>) -> Result<(), String> {
+    let path = profile_path(env.config.get("daemon.profiles"), name)?;
+    let contents = fs::read_to_string(&path).map_err(|e| format!("Failed to read profile {}. Error: {}", path.display(), e))?;
+
+    let mut current = gpu;
+    let result = script::run_script(&path.to_string_lossy(), &contents, &profile_commands(fans.is_some()), env, &mut current);
+
+    // A reset in the profile hands the fans back to the driver, the curve takes them again
+    if let Some(fans) = fans {
+        fans.resume();
+    }
+    result
+}
+
+fn gpu_param(params: &Value) -> Result<Option<usize>, String> {
+    match params.get("gpu") {
+        None | Some(Value::Null) => Ok(None),
+        Some(v) => match v.as_f64() {
+            Some(n) if n >= 0.0 && n.fract() == 0.0 => Ok(Some(n as usize)),
+            _ => Err(String::from("gpu must be an integer greater than or equal to 0")),
+        },
+    }
+}
+
+/*
+ * Runs one request and returns the reply. Notifications (no id) are run but not answered, as
+ * JSON-RPC asks.
+ */
+fn handle(line: &str, client: &mut Client, commands: &HashMap<String, HelperCommand>, env: &mut Environment, default_gpu: usize, fans: &mut Option<FanController>) -> Option<String> {
+    let request = match json::parse(line) {
+        Ok(r) => r,
+        Err(e) => return Some(error(&Value::Null, PARSE_ERROR, &e)),
+    };
+
+    let id = request.get("id").cloned();
+    let reply_id = id.clone().unwrap_or(Value::Null);
+
+    let method = match request.get("method").and_then(|m| m.as_str()) {
+        Some(m) => m.to_string(),
+        None => return Some(error(&reply_id, INVALID_REQUEST, "The request has no method.")),
+    };
+    let params = request.get("params").cloned().unwrap_or(Value::Object(Vec::new()));
+
+    let gpu = match gpu_param(&params) {
+        Ok(g) => g,
+        Err(e) => return Some(error(&reply_id, INVALID_PARAMS, &e)),
+    };
+
+    let result: Result<String, (i32, String)> = match method.as_str() {
+        "query" => {
+            let fields = params.get("fields").and_then(|f| f.as_str()).unwrap_or(nvidiagpu::QUERY_INFO_FIELDS);
+            query(env, gpu, fields).map(|gpus| json::array(&gpus)).map_err(|e| (SERVER_ERROR, e))
+        },
+        "set" => {
+            let command = params.get("command").and_then(|c| c.as_str()).unwrap_or("");
+            let args: Vec<String> = params.get("args").and_then(|a| a.as_array()).unwrap_or(&[])
+                .iter().map(|a| a.as_str().map_or_else(|| a.encode(), String::from)).collect();
+
+            match cmd_exists(&command.to_string(), commands) {
+                // An argument that is a command name would start a second, unchecked command
+                Some(_) if args.iter().any(|a| cmd_exists(a, commands).is_some()) => {
+                    Err((INVALID_PARAMS, String::from("Arguments cannot be command names.")))
+                },
+                // The daemon runs as root, and the test would be the caller's command
+                Some(cmd) if cmd.name == "undervolt" && args.iter().any(|a| a == "--test") => {
+                    Err((INVALID_PARAMS, String::from("The guided undervolt runs its test as the caller, send only the --offset it found.")))
+                },
+                Some(cmd) if cmd.name == "fan" && fans.is_some() => {
+                    Err((SERVER_ERROR, String::from("The daemon's fan curve drives the fans. Change or remove daemon.fan_curve instead.")))
+                },
+                Some(cmd) if FORWARDED_COMMANDS.contains(&cmd.name.as_str()) => {
+                    let mut tokens = vec![String::from("gpu"), gpu.unwrap_or(default_gpu).to_string(), cmd.name.clone()];
+                    tokens.extend(args);
+
+                    let mut current = default_gpu;
+                    let mut errors = Vec::new();
+                    run_tokens(&tokens, commands, env, &mut current, &mut |e| errors.push(e));
+                    if let Some(fans) = fans.as_mut().filter(|_| cmd.name == "reset") {
+                        fans.resume();
+                    }
+                    if errors.is_empty() { Ok(String::from("null")) } else { Err((SERVER_ERROR, errors.join(" "))) }
+                },
+                _ => Err((INVALID_PARAMS, format!("'{}' is not a command that can be set. Expected one of {}.", command, FORWARDED_COMMANDS.join(", ")))),
+            }
+        },
+        "apply-profile" => match params.get("profile").and_then(|p| p.as_str()) {
+            Some(name) => apply_profile(env, name, gpu.unwrap_or(default_gpu), fans).map(|_| String::from("null")).map_err(|e| (SERVER_ERROR, e)),
+            None => Err((INVALID_PARAMS, format!("apply-profile needs the name of a profile in {}.", env.config.get("daemon.profiles")))),
+        },
+        "subscribe" => {
+            let interval = params.get("interval").and_then(|i| i.as_f64()).unwrap_or(1.0);
+            if interval.is_finite() && interval >= 0.1 {
+                let fields = params.get("fields").and_then(|f| f.as_str()).unwrap_or(nvidiagpu::QUERY_INFO_FIELDS).to_string();
+                client.subscription = Some(Subscription { interval: Duration::from_secs_f64(interval), fields, next: Instant::now() });
+                Ok(String::from("true"))
+            } else {
+                Err((INVALID_PARAMS, String::from("interval must be at least 0.1 seconds.")))
+            }
+        },
+        "unsubscribe" => {
+            client.subscription = None;
+            Ok(String::from("true"))
+        },
+        _ => Err((METHOD_NOT_FOUND, format!("There is no method '{}'.", method))),
+    };
+
+    id.as_ref()?;
+
+    Some(match result {
+        Ok(r) => response(&reply_id, r),
+        Err((code, message)) => error(&reply_id, code, &message),
+    })
+}
+
+fn send_metrics(env: &Environment, client: &mut Client) {
+    let subscription = match client.subscription.as_mut() {
+        Some(s) if s.next <= Instant::now() => s,
+        _ => return,
+    };
+    subscription.next += subscription.interval;
+
+    let params = match query(env, None, &subscription.fields) {
+        Ok(gpus) => json::Object::new().raw("gpus", json::array(&gpus)).build(),
+        Err(e) => json::Object::new().string("error", &e).build(),
+    };
+
+    client.send(&json::Object::new().string("jsonrpc", "2.0").string("method", "metrics").raw("params", params).build());
+}
+
+/*
+ * Accepts clients and serves their requests until killed. The socket is readable and writable by the
+ * daemon's user and group, so access is given by adding users to that group.
+ */
+pub fn serve(env: &mut Environment, default_gpu: usize) -> Result<(), String> {
+    let socket = env.config.get("daemon.socket").to_string();
+
+    // Mistakes in the fan curve or schedule are reported before the daemon takes over
+    let curve = match env.config.get("daemon.fan_curve") {
+        "" => None,
+        text => Some(fancurve::parse_curve(text)?),
+    };
+    let entries = schedule::parse_schedule(env.config.get("daemon.schedule"))?;
+
+    if Path::new(&socket).exists() {
+        if UnixStream::connect(&socket).is_ok() {
+            return Err(format!("A daemon is already listening on {}.", socket));
+        }
+        let _ = fs::remove_file(&socket);
+    }
+
+    let listener = UnixListener::bind(&socket).map_err(|e| format!("Failed to listen on {}. Error: {}", socket, e))?;
+    let setup = || -> io::Result<()> {
+        fs::set_permissions(&socket, fs::Permissions::from_mode(0o660))?;
+        listener.set_nonblocking(true)
+    };
+    setup().map_err(|e| format!("Failed to set up {}. Error: {}", socket, e))?;
+
+    env.in_daemon = true;
+    let commands = default_commands();
+    let mut clients: Vec<Client> = Vec::new();
+
+    println!("Daemon listening on {}", socket);
+
+    let mut fans = match curve {
+        Some(curve) => {
+            headless::ensure(env)?;
+            println!("Driving every fan with the curve {}", env.config.get("daemon.fan_curve"));
+            Some(FanController::new(curve, env.limits.min_fan_speed.map_or(0, |m| m.ceil() as usize)))
+        },
+        None => None,
+    };
+
+    let mut scheduler = if entries.is_empty() { None } else { Some(Scheduler::new(entries)) };
+
+    signals::install();
+
+    while !signals::stop_requested() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if stream.set_nonblocking(true).is_ok() {
+                    clients.push(Client { stream, buffer: Vec::new(), subscription: None, closed: false });
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {},
+            Err(e) => println!("Failed to accept a connection. Error: {}", e),
+        }
+
+        for client in clients.iter_mut() {
+            client.read();
+
+            while let Some(line) = client.next_line() {
+                if line.is_empty() {
+                    continue;
+                }
+                if let Some(reply) = handle(&line, client, &commands, env, default_gpu, &mut fans) {
+                    client.send(&reply);
+                }
+            }
+
+            send_metrics(env, client);
+        }
+
+        clients.retain(|c| !c.closed);
+
+        if let Some(fans) = fans.as_mut() {
+            fans.tick(env);
+        }
+
+        if let Some(profile) = scheduler.as_mut().and_then(|s| s.due(env)) {
+            match apply_profile(env, &profile, default_gpu, &mut fans) {
+                Ok(()) => println!("Applied the scheduled profile {}", profile),
+                Err(e) => println!("The scheduled profile {} failed. {}", profile, e),
+            }
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    // The fans are not left at a speed nothing is adjusting any more
+    if let Some(fans) = fans.as_mut() {
+        fans.stop(env);
+    }
+    let _ = fs::remove_file(&socket);
+    println!("Daemon stopped.");
+
+    Ok(())
+}
+
+/*
+ * For the commands that change settings in a loop (autotune, sweep and the guided undervolt). While
+ * the daemon runs each change is sent to it as the command it would be on the command line, e.g.
+ * "power 250", so they never race it. Otherwise set makes the change here.
+ */
+pub fn set_through(env: &Environment, gpu: usize, command: &str, value: String, set: impl FnOnce() -> Result<(), String>) -> Result<(), String> {
+    if !env.in_daemon && env.backend != Backend::DryRun {
+        if let Some(result) = forward(env, command, &[&value], &gpu) {
+            return result;
+        }
+    }
+
+    set()
+}
+
+/*
+ * Client side, called by run() for the FORWARDED_COMMANDS. Returns None when no daemon is running, in
+ * which case the command runs here as usual.
+ */
+pub fn forward(env: &Environment, command: &str, args: &[&String], gpu: &usize) -> Option<Result<(), String>> {
+    let socket = env.config.get("daemon.socket");
+    let mut stream = UnixStream::connect(socket).ok()?;
+
+    if env.debug {
+        println!("Sending {} to the daemon on {}", command, socket);
+    }
+
+    let args: Vec<String> = args.iter().map(|a| json::string(a)).collect();
+    let params = json::Object::new().raw("gpu", gpu.to_string()).string("command", command).raw("args", json::array(&args)).build();
+    let request = json::Object::new().string("jsonrpc", "2.0").raw("id", String::from("1")).string("method", "set").raw("params", params).build();
+
+    let mut exchange = || -> io::Result<String> {
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        writeln!(stream, "{}", request)?;
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+        Ok(line)
+    };
+
+    let reply = match exchange() {
+        Ok(line) => line,
+        Err(e) => return Some(Err(format!("The daemon on {} did not answer. Error: {}", socket, e))),
+    };
+
+    let reply = match json::parse(&reply) {
+        Ok(r) => r,
+        Err(e) => return Some(Err(format!("The daemon sent a reply that could not be read. {}", e))),
+    };
+
+    match reply.get("error") {
+        Some(e) => Some(Err(e.get("message").and_then(|m| m.as_str()).unwrap_or("The daemon failed to run the command.").to_string())),
+        None => Some(Ok(())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::DirBuilderExt;
+
+    #[test]
+    fn profiles_only_get_the_setters() {
+        let commands = profile_commands(false);
+        assert!(commands.contains_key("gpu") && commands.contains_key("power") && commands.contains_key("fan"));
+        assert!(!commands.contains_key("run-file") && !commands.contains_key("shell") && !commands.contains_key("autotune"));
+
+        assert!(!profile_commands(true).contains_key("fan"));
+    }
+
+    #[test]
+    fn profiles_are_plain_names_in_a_directory_only_root_can_write() {
+        assert!(profile_path("/etc", "../passwd").is_err());
+        assert!(profile_path("/etc", "quiet.tgh").is_err());
+        assert!(profile_path("/etc", "").is_err());
+
+        let dir = std::env::temp_dir().join(format!("tgh-profiles-{}", std::process::id()));
+        fs::DirBuilder::new().mode(0o777).create(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+        fs::write(dir.join("quiet.tgh"), "power 200W\n").unwrap();
+
+        let result = profile_path(&dir.to_string_lossy(), "quiet");
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(result.unwrap_err().contains("writable only by root"));
+    }
+}
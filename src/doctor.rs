@@ -0,0 +1,401 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process::Command;
+
+use crate::coolbits;
+use crate::executor::{execute, execute_as_root, private_temp_dir, shell_quote, Backend, Environment, OutputFormat, Privilege};
+use crate::headless;
+use crate::json;
+
+/*
+ * doctor looks for the reasons a setter would fail before it is run: missing tools, a driver that
+ * is not loaded, sudo that would ask for a password, an X server that cannot be reached, and GPUs
+ * or Xorg configs that do not support a setting. Each check ends up as pass, warn or fail with a
+ * hint on how to fix it.
+ *
+ *   doctor            prints the report, as JSON when output = json
+ *   doctor coolbits   shows the xorg.conf change that enables every Coolbits feature and offers to
+ *                     write it
+ */
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Pass => write!(f, "pass"),
+            Status::Warn => write!(f, "warn"),
+            Status::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+pub struct Check {
+    pub status: Status,
+    pub name: String,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn new(status: Status, name: &str, detail: String, hint: Option<&str>) -> Check {
+        Check { status, name: name.to_string(), detail, hint: hint.map(String::from) }
+    }
+}
+
+// Runs a query, returning its trimmed stdout if it succeeded
+fn query(env: &Environment, cmd: &str) -> Option<String> {
+    match execute(env, &cmd.to_string()) {
+        Ok(o) if o.status.success() => Some(String::from_utf8_lossy(&o.stdout).trim().to_string()),
+        _ => None,
+    }
+}
+
+fn tool_checks(env: &Environment) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    for (tool, package_hint) in [
+        ("nvidia-smi", "Install the NVIDIA driver utilities, e.g. nvidia-utils. clock, memory, power and every readout need it."),
+        ("nvidia-settings", "Install nvidia-settings. fan, clockoffset and memoryoffset need it."),
+    ] {
+        match query(env, &format!("command -v {}", tool)) {
+            Some(path) => checks.push(Check::new(Status::Pass, tool, path, None)),
+            None => checks.push(Check::new(Status::Fail, tool, String::from("Not found in PATH."), Some(package_hint))),
+        }
+    }
+
+    checks
+}
+
+fn driver_check(env: &Environment) -> Check {
+    match execute(env, &String::from("nvidia-smi --query-gpu=driver_version --format=csv,noheader")) {
+        Ok(o) if o.status.success() => {
+            let version = String::from_utf8_lossy(&o.stdout).lines().next().unwrap_or("").trim().to_string();
+            Check::new(Status::Pass, "Driver", version, None)
+        },
+        Ok(o) => {
+            // nvidia-smi explains a missing driver on stdout, the shell a missing nvidia-smi on stderr
+            let text = format!("{}{}", String::from_utf8_lossy(&o.stdout), String::from_utf8_lossy(&o.stderr));
+            let message = text.lines().map(|l| l.trim()).find(|l| !l.is_empty()).unwrap_or("nvidia-smi failed.").to_string();
+            Check::new(Status::Fail, "Driver", message, Some("Check that the nvidia kernel module is loaded (lsmod | grep nvidia) and matches the installed utilities."))
+        },
+        Err(e) => Check::new(Status::Fail, "Driver", format!("nvidia-smi could not be run. Error: {}", e), None),
+    }
+}
+
+// clock, memory and power are escalated, so escalation has to work without a prompt for scripts
+fn privilege_check(env: &Environment) -> Check {
+    let root = query(env, "id -u").as_deref() == Some("0");
+    let name = env.privilege.name();
+
+    match env.privilege {
+        _ if root && env.privilege != Privilege::Pkexec => Check::new(Status::Pass, "Privilege", format!("Running as root, privilege is {}.", name), None),
+        Privilege::Sudo | Privilege::Doas => match query(env, &format!("{} -n true", name)) {
+            Some(_) => Check::new(Status::Pass, "Privilege", format!("{} works without a password.", name), None),
+            None => Check::new(Status::Warn, "Privilege", format!("{} asks for a password, so clock, memory and power will prompt, and fail without a terminal.", name),
+                               Some("Allow nvidia-smi without a password, or run as root with privilege = \"root\".")),
+        },
+        Privilege::Pkexec => Check::new(Status::Warn, "Privilege", String::from("pkexec asks through a polkit agent for every clock, memory and power change."),
+                                        Some("Use sudo or doas with a passwordless rule for scripts, or privilege = \"root\" when already root.")),
+        Privilege::Helper => match UnixStream::connect(env.config.get("helper.socket")) {
+            Ok(_) => Check::new(Status::Pass, "Privilege", format!("The helper is listening on {}.", env.config.get("helper.socket")), None),
+            Err(e) => Check::new(Status::Fail, "Privilege", format!("The helper at {} could not be reached: {}", env.config.get("helper.socket"), e),
+                                 Some("Start 'teamgreenhelper helper' as root, e.g. from a systemd service.")),
+        },
+        Privilege::Root => Check::new(Status::Fail, "Privilege", String::from("privilege is root but this is not running as root, so clock, memory and power will fail."),
+                                      Some("Run as root, or set privilege to sudo, doas or pkexec.")),
+        Privilege::None => Check::new(Status::Warn, "Privilege", String::from("privilege is none and this is not root, so clock, memory and power may fail."),
+                                      Some("Run as root, or set privilege to sudo, doas or pkexec.")),
+    }
+}
+
+fn x_check(env: &Environment) -> Check {
+    if !env.x_found {
+        return match env.config.get("headless") {
+            "off" => Check::new(Status::Fail, "X server", String::from("None was found and headless is off."),
+                                Some("Start X, pass 'display' and 'xauth', or set headless = \"auto\".")),
+            _ => Check::new(Status::Warn, "X server", String::from("None was found, a headless one will be started for nvidia-settings."),
+                            Some("Starting Xorg needs root and xauth.")),
+        };
+    }
+
+    let cmd = format!("DISPLAY={} XAUTHORITY={} nvidia-settings -t -q gpus", env.display, env.xauthority);
+    match query(env, &cmd) {
+        Some(_) => Check::new(Status::Pass, "X server", format!("{} is reachable with {} ({})", env.display, env.xauthority, env.config.source("display")), None),
+        None => Check::new(Status::Fail, "X server", format!("nvidia-settings could not connect to {} with {}.", env.display, env.xauthority),
+                           Some("Pass the right 'display' and 'xauth', and check that the Xauthority file is readable by this user.")),
+    }
+}
+
+/*
+ * Persistence mode and whether each GPU supports each nvidia-smi setter. Locking clocks needs Volta
+ * (compute capability 7.0) and locking memory needs Ampere (8.0), which nvidia-smi cannot be asked
+ * about directly.
+ */
+fn gpu_checks(env: &Environment) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let rows = match query(env, "nvidia-smi --query-gpu=index,persistence_mode,power.min_limit,power.max_limit,fan.speed --format=csv,noheader,nounits") {
+        Some(rows) => rows,
+        None => return checks,
+    };
+    let compute_caps = query(env, "nvidia-smi --query-gpu=compute_cap --format=csv,noheader").unwrap_or_default();
+    let compute_caps: Vec<Option<f64>> = compute_caps.lines().map(|c| c.trim().parse::<f64>().ok()).collect();
+
+    for (i, row) in rows.lines().enumerate() {
+        let fields: Vec<&str> = row.split(',').map(|f| f.trim()).collect();
+        if fields.len() != 5 {
+            continue;
+        }
+
+        let gpu = fields[0];
+        let name = |setting: &str| format!("GPU {} {}", gpu, setting);
+
+        match fields[1] {
+            "Enabled" => checks.push(Check::new(Status::Pass, &name("persistence"), String::from("Enabled."), None)),
+            "Disabled" => checks.push(Check::new(Status::Warn, &name("persistence"), String::from("Disabled, settings can be lost when the driver unloads while idle."),
+                                                 Some("Enable it with 'sudo nvidia-smi -pm 1' or the nvidia-persistenced service."))),
+            _ => {}
+        }
+
+        match (fields[2].parse::<f64>(), fields[3].parse::<f64>()) {
+            (Ok(min), Ok(max)) => checks.push(Check::new(Status::Pass, &name("power"), format!("Limit can be set from {} W to {} W.", min, max), None)),
+            _ => checks.push(Check::new(Status::Fail, &name("power"), String::from("The power limit cannot be changed on this GPU."), None)),
+        }
+
+        if fields[4].parse::<f64>().is_ok() {
+            checks.push(Check::new(Status::Pass, &name("fan"), String::from("Has fans, controlled through nvidia-settings."), None));
+        } else {
+            checks.push(Check::new(Status::Fail, &name("fan"), String::from("No fans are reported, the GPU is likely passively cooled."), None));
+        }
+
+        for (setting, needed, generation) in [("clock", 7.0, "Volta"), ("memory", 8.0, "Ampere")] {
+            match compute_caps.get(i).copied().flatten() {
+                Some(cap) if cap >= needed => checks.push(Check::new(Status::Pass, &name(setting), format!("Can be locked (compute capability {}).", cap), None)),
+                Some(cap) => checks.push(Check::new(Status::Fail, &name(setting), format!("Locking needs {} or newer (compute capability {}).", generation, cap), None)),
+                None => checks.push(Check::new(Status::Warn, &name(setting), String::from("The driver does not report the compute capability, locking may not be supported."), None)),
+            }
+        }
+    }
+
+    checks
+}
+
+const COOLBITS_HINT: &str = "Run './teamgreenhelper doctor coolbits' to enable the missing features, then restart X.";
+
+fn coolbits_checks(env: &Environment, bus_ids: &[String]) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let log = match coolbits::read_log(&env.display) {
+        Some(log) => log,
+        None => {
+            checks.push(Check::new(Status::Warn, "Xorg log", format!("No Xorg log was found for display {}.", env.display),
+                                   Some("Coolbits can only be read while Xorg runs. Without one, the headless X server enables it by itself.")));
+            return checks;
+        }
+    };
+
+    let config = log.config_file.clone().unwrap_or_else(|| String::from("none, built-in defaults"));
+    checks.push(Check::new(Status::Pass, "Xorg config", format!("{} (from {})", config, log.path.display()), None));
+
+    let screens = bus_ids.len().max(log.coolbits.iter().map(|(s, _)| s + 1).max().unwrap_or(0));
+
+    for screen in 0..screens {
+        let name = format!("GPU {} Coolbits", screen);
+
+        let value = match log.coolbits_for(screen) {
+            Some(v) => v,
+            None => {
+                checks.push(Check::new(Status::Fail, &name, String::from("Not set, so fan control, clock offsets and overvoltage are locked."), Some(COOLBITS_HINT)));
+                continue;
+            }
+        };
+
+        let features: Vec<String> = coolbits::FEATURES.iter()
+            .map(|(bit, feature, _)| format!("{} {}", feature, if value & bit != 0 { "on" } else { "off" }))
+            .collect();
+
+        // Overvoltage is the only feature no command here depends on
+        let status = if value & coolbits::RECOMMENDED == coolbits::RECOMMENDED {
+            Status::Pass
+        } else if value & 12 == 12 {
+            Status::Warn
+        } else {
+            Status::Fail
+        };
+
+        checks.push(Check::new(status, &name, format!("{}: {}", value, features.join(", ")), if status == Status::Pass { None } else { Some(COOLBITS_HINT) }));
+    }
+
+    checks
+}
+
+pub fn checks(env: &Environment, bus_ids: &[String]) -> Vec<Check> {
+    let mut checks = tool_checks(env);
+    checks.push(driver_check(env));
+    checks.push(privilege_check(env));
+    checks.push(x_check(env));
+    checks.extend(gpu_checks(env));
+    checks.extend(coolbits_checks(env, bus_ids));
+    checks
+}
+
+fn print_checks(env: &Environment, checks: &[Check]) {
+    if env.output == OutputFormat::Json {
+        let items: Vec<String> = checks.iter()
+            .map(|c| json::Object::new()
+                .string("status", &c.status.to_string())
+                .string("name", &c.name)
+                .string("detail", &c.detail)
+                .raw("hint", c.hint.as_deref().map_or(String::from("null"), json::string))
+                .build())
+            .collect();
+        println!("{}", json::array(&items));
+        return;
+    }
+
+    for check in checks {
+        println!("[{}] {}: {}", check.status, check.name, check.detail);
+        if let Some(hint) = &check.hint {
+            println!("       {}", hint);
+        }
+    }
+
+    let count = |status: Status| checks.iter().filter(|c| c.status == status).count();
+    println!("\n{} passed, {} warning(s), {} failed", count(Status::Pass), count(Status::Warn), count(Status::Fail));
+}
+
+// diff -u of the two versions, or the whole new file if diff is not installed
+fn show_diff(path: &Path, current: &str, proposed: &str) -> Result<(), String> {
+    // doctor coolbits runs as root, so the files go where nobody else can plant a link
+    let dir = private_temp_dir("teamgreenhelper-doctor").map_err(|e| format!("Failed to prepare the diff. Error: {}", e))?;
+    let write = || -> io::Result<()> {
+        fs::write(dir.join("current"), current)?;
+        fs::write(dir.join("proposed"), proposed)
+    };
+    if let Err(e) = write() {
+        let _ = fs::remove_dir_all(&dir);
+        return Err(format!("Failed to prepare the diff. Error: {}", e));
+    }
+
+    let label = path.display().to_string();
+    let diff = Command::new("diff")
+        .args(["-u", "--label", &label, "--label", &label])
+        .arg(dir.join("current")).arg(dir.join("proposed"))
+        .output();
+
+    match diff {
+        Ok(o) => print!("{}", String::from_utf8_lossy(&o.stdout)),
+        Err(_) => print!("{}", proposed),
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+    Ok(())
+}
+
+pub fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    let _ = io::stdout().flush();
+
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/*
+ * Shows the change that enables every Coolbits feature and writes it once confirmed. The old file is
+ * kept next to it with a .bak suffix. Writing goes through the privilege prefix since xorg.conf is
+ * owned by root.
+ */
+fn fix_coolbits(env: &Environment, bus_ids: &[String]) -> Result<(), String> {
+    let log = coolbits::read_log(&env.display);
+
+    // Keep any bits that are set already, like SLI or old overclocking ones
+    let value = log.iter().flat_map(|l| l.coolbits.iter()).fold(coolbits::RECOMMENDED, |acc, (_, v)| acc | v);
+
+    let enabled = log.as_ref().is_some_and(|l| {
+        let screens = bus_ids.len().max(1);
+        (0..screens).all(|s| l.coolbits_for(s).is_some_and(|v| v & coolbits::RECOMMENDED == coolbits::RECOMMENDED))
+    });
+    if enabled {
+        println!("Coolbits already enables fan control, clock offsets and overvoltage on every GPU.");
+        return Ok(());
+    }
+
+    let (path, current, proposed) = coolbits::proposal(log.as_ref(), bus_ids, value);
+    if current == proposed {
+        println!("{} already sets Coolbits to {}. Restart the X server for it to take effect.", path.display(), value);
+        return Ok(());
+    }
+
+    show_diff(&path, &current, &proposed)?;
+
+    if env.backend != Backend::DryRun {
+        if !io::stdin().is_terminal() {
+            println!("Not writing {} without a terminal to confirm on.", path.display());
+            return Ok(());
+        }
+
+        if !confirm(&format!("Write this to {}?", path.display())) {
+            println!("{} was left unchanged.", path.display());
+            return Ok(());
+        }
+    }
+
+    // Staged where only this user can get at it, as root copies it into /etc/X11 unseen
+    let stage_dir = private_temp_dir("teamgreenhelper-coolbits").map_err(|e| format!("Failed to create a directory to stage the change in. Error: {}", e))?;
+    let staged = stage_dir.join("xorg.conf");
+    if let Err(e) = fs::write(&staged, &proposed) {
+        let _ = fs::remove_dir_all(&stage_dir);
+        return Err(format!("Failed to stage {}. Error: {}", staged.display(), e));
+    }
+
+    let dir = path.parent().unwrap_or(Path::new("/"));
+    let quoted = |p: &Path| shell_quote(&p.to_string_lossy());
+    let mut steps = vec![format!("mkdir -p {}", quoted(dir))];
+    if path.exists() {
+        steps.push(format!("cp {} {}", quoted(&path), shell_quote(&format!("{}.bak", path.display()))));
+    }
+    steps.push(format!("cp {} {}", quoted(&staged), quoted(&path)));
+
+    // Runs the steps in order, stopping at the first that fails
+    let failure = steps.iter()
+        .map(|step| execute_as_root(env, step))
+        .find(|result| !result.as_ref().is_ok_and(|o| o.status.success()));
+    let _ = fs::remove_dir_all(&stage_dir);
+
+    match failure {
+        None => {
+            if env.backend != Backend::DryRun {
+                println!("Wrote {}. Restart the X server for the new Coolbits to take effect.", path.display());
+            }
+            Ok(())
+        },
+        Some(Ok(o)) => Err(format!("Failed to write {}. {}", path.display(), String::from_utf8_lossy(&o.stderr).trim())),
+        Some(Err(e)) => Err(format!("Failed to write {}. Error: {}", path.display(), e)),
+    }
+}
+
+pub fn run(env: &Environment, args: &[&String]) -> Result<(), String> {
+    let bus_ids = headless::gpu_bus_ids(env).unwrap_or_default();
+
+    match args.first().map(|a| a.as_str()) {
+        None => {
+            print_checks(env, &checks(env, &bus_ids));
+            Ok(())
+        },
+        Some("coolbits") => fix_coolbits(env, &bus_ids),
+        Some(other) => Err(format!("'{}' is not a doctor action. Try 'doctor' or 'doctor coolbits'.", other)),
+    }
+}
@@ -1,18 +1,148 @@
-use std::io::{Result};
-use std::process::{Command, Output};
+use std::fs::{self, DirBuilder};
+use std::io::{self, IsTerminal, Read, Result};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Output};
+
+use crate::config::{Config, Limits};
+use crate::headless::HeadlessServer;
+use crate::helper;
+
+pub const DEFAULT_XAUTHORITY: &str = "/run/user/1000/gdm/Xauthority";
+pub const DEFAULT_DISPLAY: &str = ":0";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Backend {
+    // Run nvidia-smi and nvidia-settings
+    System,
+    // Print the commands that would change the GPU instead of running them, queries still run
+    DryRun,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+/*
+ * How commands that need root are run. Only nvidia-smi setters and writes to system files are
+ * escalated; nvidia-settings talks to the X server as the calling user.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Privilege {
+    Sudo,
+    Doas,
+    Pkexec,
+    // Sent to the privileged helper over its socket
+    Helper,
+    // Already running as root, nothing is put in front of commands
+    Root,
+    // Never escalate, for setups where the user has been given access some other way
+    None,
+}
+
+impl Privilege {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Privilege::Sudo => "sudo",
+            Privilege::Doas => "doas",
+            Privilege::Pkexec => "pkexec",
+            Privilege::Helper => "helper",
+            Privilege::Root => "root",
+            Privilege::None => "none",
+        }
+    }
+
+    /*
+     * What is put in front of a command that needs root. Without a terminal to ask for a password on,
+     * sudo and doas are run with -n so that they fail instead of waiting for input.
+     */
+    pub fn prefix(&self) -> &'static str {
+        let interactive = io::stdin().is_terminal();
+
+        match self {
+            Privilege::Sudo if interactive => "sudo ",
+            Privilege::Sudo => "sudo -n ",
+            Privilege::Doas if interactive => "doas ",
+            Privilege::Doas => "doas -n ",
+            Privilege::Pkexec => "pkexec ",
+            Privilege::Helper | Privilege::Root | Privilege::None => "",
+        }
+    }
+
+    // Whether a failed command's stderr says the escalation tool wanted a password it could not ask for
+    fn needed_password(&self, stderr: &str) -> bool {
+        match self {
+            Privilege::Sudo => stderr.contains("a password is required") || stderr.contains("a terminal is required"),
+            Privilege::Doas => stderr.contains("doas: Authentication required") || stderr.contains("doas: Authorization required"),
+            Privilege::Pkexec => stderr.contains("No authentication agent found") || stderr.contains("Not authorized"),
+            Privilege::Helper | Privilege::Root | Privilege::None => false,
+        }
+    }
+}
 
 pub struct Environment {
     pub(crate) xauthority: String,
     pub(crate) display: String,
     pub(crate) debug:bool,
+    pub(crate) backend: Backend,
+    pub(crate) output: OutputFormat,
+    pub(crate) privilege: Privilege,
+    pub(crate) limits: Limits,
+    pub(crate) config: Config,
+    // Whether an X server was found or configured for nvidia-settings
+    pub(crate) x_found: bool,
+    // The private X server started when none was found, stopped when this is dropped
+    pub(crate) headless_server: Option<HeadlessServer>,
+    // Set in the daemon itself so that its setters are not forwarded back to it
+    pub(crate) in_daemon: bool,
+    // How many run-file scripts are running inside each other
+    pub(crate) script_depth: usize,
 }
 
 impl Default for Environment {
     fn default() -> Self {
-        Environment {xauthority:String::from("/run/user/1000/gdm/Xauthority"), display:String::from(":0"), debug: false}
+        Environment {xauthority:String::from(DEFAULT_XAUTHORITY), display:String::from(DEFAULT_DISPLAY), debug: false,
+            backend: Backend::System, output: OutputFormat::Text, privilege: Privilege::Sudo, limits: Limits::default(), config: Config::default(),
+            x_found: false, headless_server: None, in_daemon: false, script_depth: 0}
     }
 }
 
+/*
+ * Runs a command that changes the state of the GPU. Unlike execute, this honours the dry-run
+ * backend, so it must not be used for queries.
+ */
+pub fn execute_change(env: &Environment, cmd: &String) -> Result<Output> {
+    if env.backend == Backend::DryRun {
+        println!("{}", cmd);
+        return Ok(Output { status: ExitStatus::from_raw(0), stdout: Vec::new(), stderr: Vec::new() });
+    }
+
+    execute(env, cmd)
+}
+
+/*
+ * Runs a change that needs root through the privilege prefix. A failure because the password could
+ * not be asked for is turned into an error that says so, rather than a bare exit status.
+ */
+pub fn execute_as_root(env: &Environment, cmd: &str) -> Result<Output> {
+    if env.privilege == Privilege::Helper && env.backend != Backend::DryRun {
+        return helper::request(env, cmd);
+    }
+
+    let output = execute_change(env, &format!("{}{}", env.privilege.prefix(), cmd))?;
+
+    if !output.status.success() && env.privilege.needed_password(&String::from_utf8_lossy(&output.stderr)) {
+        return Err(io::Error::other(format!(
+            "{} needs a password but there is no terminal to ask for it on. Run this from a terminal, allow {} without a password, \
+             or run as root with privilege = \"root\".", env.privilege.name(), cmd.split_whitespace().next().unwrap_or(cmd))));
+    }
+
+    Ok(output)
+}
+
 pub fn execute(env: &Environment, cmd: &String) -> Result<Output> {
     let cmd_output_opt = Command::new("sh")
         .arg("-c")
@@ -21,7 +151,7 @@ pub fn execute(env: &Environment, cmd: &String) -> Result<Output> {
 
     match cmd_output_opt {
         Ok(output) => {
-            if env.debug && output.status.code().map_or(false, |code| code != 0) {
+            if env.debug && output.status.code().is_some_and(|code| code != 0) {
                 let code = output.status.code().unwrap();
                 println!("Failed to execute command: {}", cmd);
                 println!("Status Code: {} - {}", code, get_smi_ret_message(code))
@@ -32,7 +162,7 @@ pub fn execute(env: &Environment, cmd: &String) -> Result<Output> {
         Err(e) => {
             if env.debug {
                 println!("Failed to execute command: {}", cmd);
-                println!("    Error: {}", e.to_string());
+                println!("    Error: {}", e);
             }
 
             Err(e)
@@ -40,6 +170,25 @@ pub fn execute(env: &Environment, cmd: &String) -> Result<Output> {
     }
 }
 
+// Quotes word for sh -c, so a path with spaces or quotes stays one argument
+pub fn shell_quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+/*
+ * Creates a new directory under the temp dir that only this user can enter, with a random name.
+ * It fails rather than reuse a directory that already exists, as someone else could have made it.
+ */
+pub fn private_temp_dir(prefix: &str) -> Result<PathBuf> {
+    let mut random = [0u8; 8];
+    fs::File::open("/dev/urandom")?.read_exact(&mut random)?;
+    let name: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+
+    let dir = std::env::temp_dir().join(format!("{}-{}", prefix, name));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
 //Work in progress for nvidia-smi return codes
 pub fn get_smi_ret_message(x: i32) -> &'static str {
     match x {
@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use crate::executor::Environment;
+use crate::nvidiagpu::{self, GpuSnapshot};
+use crate::state;
+
+/*
+ * Serves the readings of every GPU at /metrics in the Prometheus text format. Each scrape runs
+ * nvidia-smi once, so nothing is cached between scrapes.
+ *
+ * Setter failures happen in other processes, so every teamgreenhelper counts its failed setters in
+ * the shared state directory and the exporter reads the counts from there.
+ */
+
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:9835";
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Failure counts, one "<exit code> <count>" line per code so the file never grows past a few lines
+const FAILURES: &str = "failures";
+
+// Every user can write the file, so only the codes record_failure writes are kept
+fn parse_counts(contents: &str) -> BTreeMap<String, u64> {
+    contents.lines()
+        .filter_map(|l| l.split_once(' '))
+        .filter(|(code, _)| code.parse::<i32>().is_ok() || *code == "signal" || *code == "error")
+        .filter_map(|(code, count)| Some((code.to_string(), count.trim().parse::<u64>().ok()?)))
+        .collect()
+}
+
+/*
+ * Called for every failed setter with its exit code, or "error" when it could not be run at all.
+ * Counting is best effort, as a user cannot create the file until a run as root (the exporter, the
+ * daemon or the helper) has.
+ */
+pub fn record_failure(env: &Environment, code: &str) {
+    let _ = state::update(env, FAILURES, 0o666, |contents| {
+        let mut counts = parse_counts(contents);
+        *counts.entry(code.to_string()).or_insert(0) += 1;
+        counts.iter().map(|(code, count)| format!("{} {}\n", code, count)).collect()
+    });
+}
+
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+pub fn metrics(env: &Environment, snapshots: &[GpuSnapshot]) -> String {
+    let mut out = String::new();
+
+    out.push_str("# HELP nvidia_gpu_info GPU identity, the value is always 1.\n# TYPE nvidia_gpu_info gauge\n");
+    for gpu in snapshots {
+        out.push_str(&format!("nvidia_gpu_info{{gpu=\"{}\",uuid=\"{}\",name=\"{}\",driver_version=\"{}\"}} 1\n",
+                              gpu.index, label(&gpu.uuid), label(&gpu.name), label(&gpu.driver_version)));
+    }
+
+    for (i, (name, help, _)) in GpuSnapshot::default().readings().iter().enumerate() {
+        out.push_str(&format!("# HELP nvidia_gpu_{} {}\n# TYPE nvidia_gpu_{} gauge\n", name, help, name));
+
+        for gpu in snapshots {
+            if let Some(value) = gpu.readings()[i].2 {
+                out.push_str(&format!("nvidia_gpu_{}{{gpu=\"{}\",uuid=\"{}\",name=\"{}\",driver_version=\"{}\"}} {}\n",
+                                      name, gpu.index, label(&gpu.uuid), label(&gpu.name), label(&gpu.driver_version), value));
+            }
+        }
+    }
+
+    out.push_str("# HELP teamgreenhelper_setter_failures_total Failed setters by nvidia-smi or nvidia-settings exit code, or error when one could not be run.\n");
+    out.push_str("# TYPE teamgreenhelper_setter_failures_total counter\n");
+    for (code, count) in parse_counts(&state::read(env, FAILURES)) {
+        out.push_str(&format!("teamgreenhelper_setter_failures_total{{code=\"{}\"}} {}\n", label(&code), count));
+    }
+
+    out
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) {
+    let _ = write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                   status, content_type, body.len(), body);
+}
+
+fn handle(env: &Environment, mut stream: TcpStream) {
+    let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+
+    let mut request_line = String::new();
+    let mut reader = BufReader::new(&stream);
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+
+    // The headers are not needed, but are read so the client sees its whole request consumed
+    let mut header = String::new();
+    while reader.read_line(&mut header).is_ok_and(|n| n > 0) && header.trim() != "" {
+        header.clear();
+    }
+
+    let parts: Vec<&str> = request_line.split_whitespace().collect();
+    match parts.as_slice() {
+        ["GET", "/metrics", ..] => match nvidiagpu::query_snapshots(env) {
+            Ok(snapshots) => respond(&mut stream, "200 OK", "text/plain; version=0.0.4", &metrics(env, &snapshots)),
+            Err(e) => respond(&mut stream, "503 Service Unavailable", "text/plain", &format!("nvidia-smi failed: {}\n", e)),
+        },
+        ["GET", "/", ..] => respond(&mut stream, "200 OK", "text/html", "<html><body><a href=\"/metrics\">Metrics</a></body></html>\n"),
+        ["GET", ..] => respond(&mut stream, "404 Not Found", "text/plain", "Not found\n"),
+        _ => respond(&mut stream, "405 Method Not Allowed", "text/plain", "Only GET is supported\n"),
+    }
+}
+
+/*
+ * exporter (--listen address). Scrapes are served one at a time until the process is killed.
+ */
+pub fn run(env: &Environment, args: &[&String]) -> Result<(), String> {
+    let address = match args {
+        [] => env.config.get("exporter.listen").to_string(),
+        [flag, address] if flag.as_str() == "--listen" => address.to_string(),
+        _ => return Err(String::from("Usage: exporter (--listen address:port)")),
+    };
+
+    // Created now, as runs by other users can only add to it once it exists
+    if let Err(e) = state::update(env, FAILURES, 0o666, |contents| contents.to_string()) {
+        println!("Failed setters of other users cannot be counted, {} could not be created. Error: {}",
+                 state::path(env, FAILURES).display(), e);
+    }
+
+    let listener = TcpListener::bind(&address).map_err(|e| format!("Failed to listen on {}. Error: {}", address, e))?;
+    println!("Serving metrics on http://{}/metrics", address);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => handle(env, stream),
+            Err(e) => println!("Failed to accept a connection. Error: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Source;
+    use crate::executor::private_temp_dir;
+
+    #[test]
+    fn failures_are_counted_by_code_in_the_shared_state() {
+        let dir = private_temp_dir("teamgreenhelper-failures").unwrap();
+        let mut env = Environment::default();
+        env.config.set("state_dir", &dir.to_string_lossy(), Source::CommandLine);
+
+        record_failure(&env, "4");
+        record_failure(&env, "error");
+        record_failure(&env, "4");
+
+        assert_eq!(state::read(&env, FAILURES), "4 2\nerror 1\n");
+        let out = metrics(&env, &[]);
+        let _ = std::fs::remove_dir_all(dir);
+
+        assert!(out.contains("teamgreenhelper_setter_failures_total{code=\"4\"} 2\n"));
+        assert!(out.contains("teamgreenhelper_setter_failures_total{code=\"error\"} 1\n"));
+        assert!(out.contains("# HELP nvidia_gpu_power_draw_watts Power draw in watts.\n"));
+
+        assert_eq!(parse_counts("4 2\n9\"} 1\nsignal 3\nerror x\n").into_iter().collect::<Vec<_>>(),
+                   vec![(String::from("4"), 2), (String::from("signal"), 3)]);
+    }
+}
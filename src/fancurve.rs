@@ -0,0 +1,209 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::executor::Environment;
+use crate::logging::format_utc;
+use crate::{debug_message, nvidiagpu};
+
+/*
+ * The daemon's fan curve. Every fan follows the hottest GPU, at a speed interpolated between the
+ * points of daemon.fan_curve, given as °C:% pairs:
+ *
+ *   fan_curve = "40:30, 60:50, 80:100"
+ *
+ * Below the first point the fans run at its speed, above the last point at that one's. The fans
+ * speed up as soon as the curve says so but only slow down by HYSTERESIS or more, so they do not
+ * hunt around a point. limits.min_fan_speed is a floor for the whole curve.
+ *
+ * A watchdog returns the fans to automatic control when the temperatures cannot be read or the fans
+ * cannot be set WATCHDOG_FAILURES times in a row, and stop does the same when the daemon exits, so a
+ * curve that is no longer running never leaves the fans at a low speed.
+ */
+
+const INTERVAL: Duration = Duration::from_secs(2);
+const HYSTERESIS: usize = 3;
+const WATCHDOG_FAILURES: u32 = 3;
+
+#[derive(Debug, PartialEq)]
+pub struct FanCurve {
+    // (°C, %) with the temperatures rising
+    points: Vec<(f64, f64)>,
+}
+
+pub fn parse_curve(text: &str) -> Result<FanCurve, String> {
+    let mut points: Vec<(f64, f64)> = Vec::new();
+
+    for point in text.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        let parsed = point.split_once(':').and_then(|(t, s)| {
+            Some((t.trim().trim_end_matches(['C', 'c']).parse::<f64>().ok()?, s.trim().trim_end_matches('%').parse::<f64>().ok()?))
+        });
+
+        let (temperature, speed) = match parsed {
+            Some((t, s)) if t.is_finite() && (0.0..=100.0).contains(&s) => (t, s),
+            _ => return Err(format!("'{}' is not a fan curve point. Expected °C:%, e.g. 60:50.", point)),
+        };
+
+        if points.last().is_some_and(|(last, _)| temperature <= *last) {
+            return Err(format!("The fan curve point {} has to be hotter than the one before it.", point));
+        }
+        points.push((temperature, speed));
+    }
+
+    if points.is_empty() {
+        return Err(String::from("The fan curve has no points. Expected °C:% pairs, e.g. \"40:30, 60:50, 80:100\"."));
+    }
+
+    Ok(FanCurve { points })
+}
+
+impl FanCurve {
+    pub fn speed(&self, temperature: f64) -> usize {
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+
+        let speed = if temperature <= first.0 {
+            first.1
+        } else if temperature >= last.0 {
+            last.1
+        } else {
+            self.points.windows(2)
+                .find(|w| temperature <= w[1].0)
+                .map_or(last.1, |w| w[0].1 + (w[1].1 - w[0].1) * (temperature - w[0].0) / (w[1].0 - w[0].0))
+        };
+
+        speed.round() as usize
+    }
+}
+
+fn log(message: &str) {
+    println!("{} Fan curve: {}", format_utc(SystemTime::now(), false), message);
+}
+
+pub struct FanController {
+    curve: FanCurve,
+    minimum: usize,
+    next: Instant,
+    // The speed the fans were last set to, None while they are under automatic control
+    current: Option<usize>,
+    failures: u32,
+}
+
+impl FanController {
+    pub fn new(curve: FanCurve, minimum: usize) -> FanController {
+        FanController { curve, minimum, next: Instant::now(), current: None, failures: 0 }
+    }
+
+    // The speed to set for the hottest GPU at temperature, or None to leave the fans as they are
+    fn target(&self, temperature: f64) -> Option<usize> {
+        let speed = self.curve.speed(temperature).max(self.minimum);
+
+        match self.current {
+            Some(current) if speed == current => None,
+            Some(current) if speed < current && current - speed < HYSTERESIS => None,
+            _ => Some(speed),
+        }
+    }
+
+    fn failed(&mut self, env: &Environment, reason: &str) {
+        // Only the first failures are logged, a GPU that has fallen off the bus would fill the log
+        self.failures += 1;
+        if self.failures <= WATCHDOG_FAILURES {
+            log(reason);
+        }
+
+        if self.failures == WATCHDOG_FAILURES {
+            log(&format!("failed {} times in a row, returning the fans to automatic control", WATCHDOG_FAILURES));
+            self.stop(env);
+        }
+    }
+
+    pub fn tick(&mut self, env: &Environment) {
+        if Instant::now() < self.next {
+            return;
+        }
+        self.next = Instant::now() + INTERVAL;
+
+        let hottest = nvidiagpu::query_all_gpus(env, "temperature.gpu").map(|rows| {
+            rows.iter().filter_map(|row| row.get(1)?.parse::<f64>().ok()).fold(None, |max: Option<f64>, t| Some(max.map_or(t, |m| m.max(t))))
+        });
+
+        let temperature = match hottest {
+            Ok(Some(t)) => t,
+            Ok(None) => return self.failed(env, "no GPU reported a temperature"),
+            Err(e) => return self.failed(env, &format!("the temperatures could not be read. {}", e)),
+        };
+
+        let speed = match self.target(temperature) {
+            Some(speed) => speed,
+            None => {
+                self.failures = 0;
+                return;
+            },
+        };
+
+        match debug_message(env, nvidiagpu::set_all_fan_speeds(env, speed), "Fan Speed") {
+            Ok(()) => {
+                if env.debug {
+                    log(&format!("{}°C, fans to {}%", temperature, speed));
+                }
+                self.current = Some(speed);
+                self.failures = 0;
+            },
+            Err(e) => self.failed(env, &e),
+        }
+    }
+
+    // Sets the fans on the next tick, after something else has changed them
+    pub fn resume(&mut self) {
+        self.current = None;
+        self.next = Instant::now();
+    }
+
+    // Returns the fans to automatic control, if the curve had taken them over
+    pub fn stop(&mut self, env: &Environment) {
+        if self.current.take().is_none() {
+            return;
+        }
+
+        if let Err(e) = debug_message(env, nvidiagpu::reset_all_fan_speeds(env), "Fan Speed") {
+            log(&format!("the fans could not be returned to automatic control. {}", e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curve_points_are_read_in_order() {
+        assert_eq!(parse_curve("40:30, 60C:50%,80:100").unwrap(), FanCurve { points: vec![(40.0, 30.0), (60.0, 50.0), (80.0, 100.0)] });
+        assert!(parse_curve("60:50, 40:30").is_err());
+        assert!(parse_curve("60:150").is_err());
+        assert!(parse_curve("60").is_err());
+        assert!(parse_curve(" , ").is_err());
+    }
+
+    #[test]
+    fn speed_is_interpolated_and_held_past_the_ends() {
+        let curve = parse_curve("40:30, 60:50, 80:100").unwrap();
+
+        assert_eq!(curve.speed(20.0), 30);
+        assert_eq!(curve.speed(50.0), 40);
+        assert_eq!(curve.speed(70.0), 75);
+        assert_eq!(curve.speed(80.0), 100);
+        assert_eq!(curve.speed(95.0), 100);
+    }
+
+    #[test]
+    fn fans_slow_down_only_past_the_hysteresis() {
+        let mut controller = FanController::new(parse_curve("40:30, 60:50").unwrap(), 35);
+        assert_eq!(controller.target(20.0), Some(35));
+        assert_eq!(controller.target(50.0), Some(40));
+
+        controller.current = Some(40);
+        assert_eq!(controller.target(50.0), None);
+        assert_eq!(controller.target(49.0), None);
+        assert_eq!(controller.target(47.0), Some(37));
+        assert_eq!(controller.target(51.0), Some(41));
+    }
+}
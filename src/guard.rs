@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::commands::{default_commands, HelperCommand};
+use crate::executor::Environment;
+use crate::logging::format_utc;
+use crate::nvidiagpu::{self, GpuSnapshot};
+use crate::units::{self, Unit};
+use crate::state::{self, Lock};
+use crate::{headless, run_tokens, signals};
+
+/*
+ * guard watches every GPU and steps in when one goes over a limit:
+ *
+ *   guard --max-temp 83 (--max-hotspot 100) (--max-memory-temp 95) (--max-power 300W) (--interval 2s)
+ *
+ * The response is staged, with --escalate-after (default 30s) between stages while the GPU stays
+ * over its limits:
+ *
+ *   1. every fan goes to 100%
+ *   2. the power limit is lowered to 80% of what it was, or to --max-power if that is lower
+ *   3. clock locks, offsets and applications clocks are reset, the fans stay at 100%
+ *
+ * Once every reading has been back under its limit by a margin for --recover-after (default 60s),
+ * the power limit, clock locks, offsets and applications clocks are put back and the fans return to
+ * automatic control. Offsets are read from nvidia-settings before the reset, but the driver does not
+ * report locked clocks, so only the locks recorded for status can be put back.
+ *
+ * Every change goes through the same commands as the command line, so the configured limits apply
+ * and a running daemon makes them.
+ *
+ * nvidia-smi does not say which fans belong to which GPU, so the fans are shared by every GPU and
+ * only go back to automatic control when no GPU needs them. Settings are left as they are when
+ * guard is stopped, as undoing them could be what overheats a GPU.
+ */
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(2);
+const DEFAULT_ESCALATE_AFTER: Duration = Duration::from_secs(30);
+const DEFAULT_RECOVER_AFTER: Duration = Duration::from_secs(60);
+
+// How far under its limit a reading has to be to count as recovered, so guard does not flap
+const TEMPERATURE_MARGIN: f64 = 5.0;
+const POWER_MARGIN: f64 = 0.95;
+
+// Stage 2 lowers the power limit to this share of the limit it found
+const POWER_REDUCTION: f64 = 0.8;
+
+const LAST_STAGE: u8 = 3;
+
+#[derive(Default)]
+struct Limits {
+    temperature: Option<f64>,
+    hotspot: Option<f64>,
+    memory_temperature: Option<f64>,
+    power: Option<f64>,
+}
+
+struct Options {
+    limits: Limits,
+    interval: Duration,
+    escalate_after: Duration,
+    recover_after: Duration,
+}
+
+// 83, 83C or 83°C
+fn parse_celsius(value: &str) -> Result<f64, String> {
+    match value.trim_end_matches(['C', 'c']).trim_end_matches('°').parse::<f64>() {
+        Ok(n) if n > 0.0 => Ok(n),
+        _ => Err(format!("'{}' is not a temperature. Expected degrees Celsius, e.g. 83 or 83C.", value)),
+    }
+}
+
+fn parse_options(args: &[&String]) -> Result<Options, String> {
+    let mut options = Options {
+        limits: Limits::default(),
+        interval: DEFAULT_INTERVAL,
+        escalate_after: DEFAULT_ESCALATE_AFTER,
+        recover_after: DEFAULT_RECOVER_AFTER,
+    };
+
+    for pair in args.chunks(2) {
+        let (flag, value) = match pair {
+            [flag, value] => (flag.as_str(), value.as_str()),
+            _ => return Err(format!("'{}' needs a value.", pair[0])),
+        };
+
+        match flag {
+            "--max-temp" => options.limits.temperature = Some(parse_celsius(value)?),
+            "--max-hotspot" => options.limits.hotspot = Some(parse_celsius(value)?),
+            "--max-memory-temp" => options.limits.memory_temperature = Some(parse_celsius(value)?),
+            "--max-power" => options.limits.power = match units::parse_value(value, Unit::Watts)? {
+                units::Value::Absolute(n) if n > 0.0 => Some(n),
+                _ => return Err(format!("'{}' is not a power limit. Expected watts, e.g. 300W.", value)),
+            },
+            "--interval" => options.interval = units::parse_duration(value)?,
+            "--escalate-after" => options.escalate_after = units::parse_duration(value)?,
+            "--recover-after" => options.recover_after = units::parse_duration(value)?,
+            _ => return Err(format!("'{}' is not a guard option. Expected --max-temp, --max-hotspot, --max-memory-temp, --max-power, \
+                                     --interval, --escalate-after or --recover-after.", flag)),
+        }
+    }
+
+    let limits = &options.limits;
+    if limits.temperature.is_none() && limits.hotspot.is_none() && limits.memory_temperature.is_none() && limits.power.is_none() {
+        return Err(String::from("guard needs at least one limit, e.g. guard --max-temp 83"));
+    }
+
+    Ok(options)
+}
+
+fn log(gpu: usize, message: &str) {
+    println!("{} GPU {}: {}", format_utc(SystemTime::now(), false), gpu, message);
+}
+
+#[derive(Default)]
+struct GpuState {
+    stage: u8,
+    // The power limit before stage 2 lowered it
+    saved_power_limit: Option<f64>,
+    // The commands that put back what stage 3 reset
+    saved_clocks: Vec<String>,
+    last_action: Option<Instant>,
+    cool_since: Option<Instant>,
+    warned_hotspot: bool,
+    warned_memory: bool,
+}
+
+struct Guard<'a> {
+    options: Options,
+    commands: HashMap<String, HelperCommand>,
+    env: &'a mut Environment,
+    states: HashMap<usize, GpuState>,
+    fans_raised: bool,
+}
+
+impl Guard<'_> {
+    // Every reading over its limit, as "name reading (limit)"
+    fn violations(&self, gpu: &GpuSnapshot, hotspot: Option<f64>, margin: bool) -> Vec<String> {
+        let limits = &self.options.limits;
+        let temperature_margin = if margin { TEMPERATURE_MARGIN } else { 0.0 };
+        let power_margin = if margin { POWER_MARGIN } else { 1.0 };
+
+        let checks = [
+            ("temperature", gpu.temperature, limits.temperature.map(|l| l - temperature_margin), "°C"),
+            ("hotspot", hotspot, limits.hotspot.map(|l| l - temperature_margin), "°C"),
+            ("memory temperature", gpu.memory_temperature, limits.memory_temperature.map(|l| l - temperature_margin), "°C"),
+            ("power draw", gpu.power_draw, limits.power.map(|l| l * power_margin), " W"),
+        ];
+
+        checks.iter()
+            .filter_map(|(name, reading, limit, unit)| match (reading, limit) {
+                (Some(r), Some(l)) if r > l => Some(format!("{} {}{} (limit {}{})", name, r, unit, l, unit)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Runs tokens on gpu like the command line would, returning the errors
+    fn apply(&mut self, gpu: usize, tokens: &[String]) -> Vec<String> {
+        let mut all = vec![String::from("gpu"), gpu.to_string()];
+        all.extend_from_slice(tokens);
+
+        let mut current = gpu;
+        let mut errors = Vec::new();
+        run_tokens(&all, &self.commands, self.env, &mut current, &mut |e| errors.push(e));
+        errors
+    }
+
+    fn raise_fans(&mut self, gpu: usize) {
+        let errors = self.apply(gpu, &[String::from("fan"), String::from("all"), String::from("100")]);
+
+        if errors.is_empty() {
+            self.fans_raised = true;
+            log(gpu, "raised every fan to 100%");
+        }
+        errors.iter().for_each(|e| log(gpu, e));
+    }
+
+    fn lower_power_limit(&mut self, gpu: &GpuSnapshot) {
+        let current = match gpu.power_limit {
+            Some(limit) => limit,
+            None => return log(gpu.index, "the power limit could not be read, so it was not lowered"),
+        };
+
+        let minimum = nvidiagpu::query_gpu_number(self.env, &gpu.index, "power.min_limit").unwrap_or(0.0);
+        let mut target = current * POWER_REDUCTION;
+        if let Some(max) = self.options.limits.power {
+            target = target.min(max);
+        }
+        let target = target.max(minimum).round() as usize;
+
+        let errors = self.apply(gpu.index, &[String::from("power"), target.to_string()]);
+
+        if errors.is_empty() {
+            let state = self.states.entry(gpu.index).or_default();
+            state.saved_power_limit.get_or_insert(current);
+            log(gpu.index, &format!("lowered the power limit from {} W to {} W", current, target));
+        }
+        errors.iter().for_each(|e| log(gpu.index, e));
+    }
+
+    // The clock settings that differ from their defaults, as the commands that set them again
+    fn clock_settings(&self, gpu: usize) -> Vec<String> {
+        let mut tokens = Vec::new();
+
+        for (command, lock) in [("clock", Lock::Core), ("memory", Lock::Memory)] {
+            if let Some(mhz) = state::locked_clock(self.env, gpu, lock) {
+                tokens.extend([command.to_string(), mhz.to_string()]);
+            }
+        }
+
+        let target = format!("gpu:{}", gpu);
+        for (command, attribute) in [("clockoffset", "GPUGraphicsClockOffsetAllPerformanceLevels"), ("memoryoffset", "GPUMemoryTransferRateOffsetAllPerformanceLevels")] {
+            if let Some(offset) = nvidiagpu::query_setting(self.env, &target, attribute).first().filter(|o| **o != 0.0) {
+                tokens.extend([command.to_string(), offset.to_string()]);
+            }
+        }
+
+        let fields = "clocks.applications.memory,clocks.applications.graphics,clocks.default_applications.memory,clocks.default_applications.graphics";
+        let clocks: Vec<String> = nvidiagpu::query_gpu_text(self.env, &gpu, fields).unwrap_or_default().split(',').map(|c| c.trim().to_string()).collect();
+        if let [memory, graphics, default_memory, default_graphics] = clocks.as_slice() {
+            if (memory, graphics) != (default_memory, default_graphics) {
+                tokens.extend([String::from("appclocks"), format!("{},{}", memory, graphics)]);
+            }
+        }
+
+        tokens
+    }
+
+    /*
+     * The clock part of the reset command. Compute mode, persistence and the fans are left alone,
+     * changing the first two would disturb the jobs on the GPU.
+     */
+    fn reset(&mut self, gpu: usize) {
+        let saved = self.clock_settings(gpu);
+        self.states.entry(gpu).or_default().saved_clocks = saved;
+
+        let tokens: Vec<String> = ["clock", "-1", "memory", "-1", "clockoffset", "0", "memoryoffset", "0", "appclocks", "-1"]
+            .iter().map(|t| t.to_string()).collect();
+        let errors = self.apply(gpu, &tokens);
+
+        if errors.is_empty() {
+            log(gpu, "reset clock locks, offsets and applications clocks");
+        }
+        errors.iter().for_each(|e| log(gpu, e));
+    }
+
+    fn escalate(&mut self, gpu: &GpuSnapshot, violations: &[String]) {
+        let state = self.states.entry(gpu.index).or_default();
+        state.cool_since = None;
+
+        let due = state.last_action.is_none_or(|at| at.elapsed() >= self.options.escalate_after);
+        if state.stage >= LAST_STAGE || !due {
+            return;
+        }
+
+        state.stage += 1;
+        state.last_action = Some(Instant::now());
+        let stage = state.stage;
+
+        log(gpu.index, &format!("over its limits, {}. Escalating to stage {}.", violations.join(", "), stage));
+
+        match stage {
+            1 => self.raise_fans(gpu.index),
+            2 => self.lower_power_limit(gpu),
+            _ => self.reset(gpu.index),
+        }
+    }
+
+    fn recover(&mut self, gpu: &GpuSnapshot, cooling: bool) {
+        let recover_after = self.options.recover_after;
+        let state = match self.states.get_mut(&gpu.index) {
+            Some(s) if s.stage > 0 => s,
+            _ => return,
+        };
+
+        if !cooling {
+            state.cool_since = None;
+            return;
+        }
+
+        if state.cool_since.get_or_insert_with(Instant::now).elapsed() < recover_after {
+            return;
+        }
+
+        let saved = state.saved_power_limit.take();
+        let saved_clocks = std::mem::take(&mut state.saved_clocks);
+        *state = GpuState { warned_hotspot: state.warned_hotspot, warned_memory: state.warned_memory, ..GpuState::default() };
+
+        log(gpu.index, &format!("back under its limits for {:?}, restoring its settings", recover_after));
+
+        if let Some(limit) = saved {
+            let errors = self.apply(gpu.index, &[String::from("power"), limit.round().to_string()]);
+            if errors.is_empty() {
+                log(gpu.index, &format!("restored the power limit to {} W", limit));
+            }
+            errors.iter().for_each(|e| log(gpu.index, e));
+        }
+
+        if !saved_clocks.is_empty() {
+            let errors = self.apply(gpu.index, &saved_clocks);
+            if errors.is_empty() {
+                log(gpu.index, &format!("restored {}", saved_clocks.join(" ")));
+            }
+            errors.iter().for_each(|e| log(gpu.index, e));
+        }
+
+        if self.fans_raised && self.states.values().all(|s| s.stage == 0) {
+            let errors = self.apply(gpu.index, &[String::from("fan"), String::from("all"), String::from("-1")]);
+            if errors.is_empty() {
+                self.fans_raised = false;
+                log(gpu.index, "returned every fan to automatic control");
+            }
+            errors.iter().for_each(|e| log(gpu.index, e));
+        }
+    }
+
+    fn check(&mut self, gpu: &GpuSnapshot) {
+        let hotspot = match self.options.limits.hotspot {
+            Some(_) => nvidiagpu::query_hotspot(self.env, &gpu.index),
+            None => None,
+        };
+
+        let state = self.states.entry(gpu.index).or_default();
+        if self.options.limits.hotspot.is_some() && hotspot.is_none() && !state.warned_hotspot {
+            state.warned_hotspot = true;
+            log(gpu.index, "does not report a hotspot temperature, --max-hotspot is not enforced on it");
+        }
+        if self.options.limits.memory_temperature.is_some() && gpu.memory_temperature.is_none() && !state.warned_memory {
+            state.warned_memory = true;
+            log(gpu.index, "does not report a memory temperature, --max-memory-temp is not enforced on it");
+        }
+
+        let violations = self.violations(gpu, hotspot, false);
+        if violations.is_empty() {
+            let cooling = self.violations(gpu, hotspot, true).is_empty();
+            self.recover(gpu, cooling);
+        } else {
+            self.escalate(gpu, &violations);
+        }
+    }
+}
+
+pub fn run(env: &mut Environment, args: &[&String]) -> Result<(), String> {
+    let options = parse_options(args)?;
+    let interval = options.interval;
+
+    // The fans need nvidia-settings, but lowering the power limit does not, so guard runs without X
+    if let Err(e) = headless::ensure(env) {
+        println!("{} The fans cannot be raised.", e);
+    }
+
+    let mut guard = Guard { options, commands: default_commands(), env, states: HashMap::new(), fans_raised: false };
+    signals::install();
+
+    println!("Guarding every GPU every {:?}. Stop with Ctrl-C or SIGTERM.", interval);
+
+    let mut next = Instant::now();
+
+    loop {
+        match nvidiagpu::query_snapshots(guard.env) {
+            Ok(snapshots) => snapshots.iter().for_each(|gpu| guard.check(gpu)),
+            Err(e) => println!("{} Failed to read the GPUs. {}", format_utc(SystemTime::now(), false), e),
+        }
+
+        next += interval;
+        if !signals::sleep(next.saturating_duration_since(Instant::now())) {
+            break;
+        }
+    }
+
+    let escalated: Vec<String> = guard.states.iter().filter(|(_, s)| s.stage > 0).map(|(gpu, _)| gpu.to_string()).collect();
+    if !escalated.is_empty() {
+        println!("Stopped guarding. GPU {} had not recovered and keeps the settings guard applied.", escalated.join(", "));
+    } else {
+        println!("Stopped guarding.");
+    }
+
+    Ok(())
+}
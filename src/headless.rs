@@ -0,0 +1,353 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::Source;
+use crate::coolbits::RECOMMENDED;
+use crate::executor::{execute, private_temp_dir, shell_quote, Backend, Environment};
+
+/*
+ * nvidia-settings can only change fans and clock offsets through an X server. On machines without
+ * a desktop, a private Xorg is started on a free display with a generated xorg.conf that has
+ * Coolbits enabled on every GPU, the assignments are made against it, and it is stopped again when
+ * the Environment is dropped at the end of the run.
+ *
+ * Every GPU is given its own screen in PCI order so that nvidia-settings numbers them the same way
+ * nvidia-smi does.
+ */
+
+const START_TIMEOUT: Duration = Duration::from_secs(15);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub trait XLauncher {
+    // Starts an X server on display using config, with a fresh cookie written to xauthority
+    fn start(&mut self, display: u32, config: &Path, xauthority: &Path) -> io::Result<()>;
+    // Whether the server accepts connections yet
+    fn ready(&mut self, display: u32) -> bool;
+    // Whether the server process is still alive
+    fn running(&mut self) -> bool;
+    fn stop(&mut self);
+}
+
+pub struct HeadlessServer {
+    launcher: Box<dyn XLauncher>,
+    dir: PathBuf,
+    pub display: String,
+    pub xauthority: PathBuf,
+}
+
+impl HeadlessServer {
+    // dir must be a fresh private directory, see private_temp_dir. It is removed again on drop.
+    pub fn start(mut launcher: Box<dyn XLauncher>, bus_ids: &[String], dir: PathBuf, display: u32, timeout: Duration) -> Result<HeadlessServer, String> {
+        let config = dir.join("xorg.conf");
+        let xauthority = dir.join("Xauthority");
+
+        // From here on dropping server stops whatever was started and removes dir
+        let mut server = HeadlessServer { launcher: Box::new(NotStarted), dir, display: format!(":{}", display), xauthority: xauthority.clone() };
+
+        if let Err(e) = fs::write(&config, xorg_conf(bus_ids)) {
+            return Err(format!("Failed to write {}. Error: {}", config.display(), e));
+        }
+
+        if let Err(e) = launcher.start(display, &config, &xauthority) {
+            return Err(format!("Failed to start a headless X server on :{}. Error: {}", display, e));
+        }
+
+        server.launcher = launcher;
+
+        let started = Instant::now();
+        while !server.launcher.ready(display) {
+            if !server.launcher.running() {
+                return Err(format!("The headless X server on :{} exited while starting. Check /var/log/Xorg.{}.log.", display, display));
+            }
+
+            if started.elapsed() >= timeout {
+                return Err(format!("The headless X server on :{} did not start within {} seconds.", display, timeout.as_secs()));
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        Ok(server)
+    }
+}
+
+impl Drop for HeadlessServer {
+    fn drop(&mut self) {
+        self.launcher.stop();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// Placeholder until the real launcher has started, so an early error has nothing to stop
+struct NotStarted;
+
+impl XLauncher for NotStarted {
+    fn start(&mut self, _: u32, _: &Path, _: &Path) -> io::Result<()> { Ok(()) }
+    fn ready(&mut self, _: u32) -> bool { false }
+    fn running(&mut self) -> bool { false }
+    fn stop(&mut self) {}
+}
+
+pub fn xorg_conf(bus_ids: &[String]) -> String {
+    let mut conf = String::from("# Generated by teamgreenhelper for a headless X server\n\nSection \"ServerLayout\"\n    Identifier \"teamgreenhelper\"\n");
+
+    for i in 0..bus_ids.len() {
+        if i == 0 {
+            conf.push_str("    Screen 0 \"Screen0\"\n");
+        } else {
+            conf.push_str(&format!("    Screen {} \"Screen{}\" RightOf \"Screen{}\"\n", i, i, i - 1));
+        }
+    }
+
+    conf.push_str("EndSection\n\nSection \"ServerFlags\"\n    Option \"AutoAddGPU\" \"false\"\n    Option \"AutoAddDevices\" \"false\"\nEndSection\n");
+
+    for (i, bus_id) in bus_ids.iter().enumerate() {
+        conf.push_str(&format!("\nSection \"Device\"\n    Identifier \"Device{}\"\n    Driver \"nvidia\"\n    BusID \"{}\"\n    Option \"Coolbits\" \"{}\"\nEndSection\n", i, bus_id, RECOMMENDED));
+        conf.push_str(&format!("\nSection \"Screen\"\n    Identifier \"Screen{}\"\n    Device \"Device{}\"\n    Option \"AllowEmptyInitialConfiguration\" \"true\"\n    Option \"Coolbits\" \"{}\"\nEndSection\n", i, i, RECOMMENDED));
+    }
+
+    conf
+}
+
+// nvidia-smi reports 00000000:0A:00.0 in hex, xorg.conf wants PCI:10:0:0 in decimal
+pub fn xorg_bus_id(smi_bus_id: &str) -> Option<String> {
+    let parts: Vec<&str> = smi_bus_id.trim().rsplitn(3, ':').collect();
+    if parts.len() < 2 {
+        return None;
+    }
+
+    let (device, function) = parts[0].split_once('.')?;
+    let bus = u32::from_str_radix(parts[1], 16).ok()?;
+    let device = u32::from_str_radix(device, 16).ok()?;
+    let function = u32::from_str_radix(function, 16).ok()?;
+
+    Some(format!("PCI:{}:{}:{}", bus, device, function))
+}
+
+// The bus id of every GPU nvidia-smi lists, in xorg.conf form
+pub fn gpu_bus_ids(env: &Environment) -> io::Result<Vec<String>> {
+    let output = execute(env, &String::from("nvidia-smi --query-gpu=pci.bus_id --format=csv,noheader"))?;
+    Ok(String::from_utf8_lossy(&output.stdout).lines().filter_map(xorg_bus_id).collect())
+}
+
+fn free_display() -> u32 {
+    (10..100)
+        .find(|n| !Path::new(&format!("/tmp/.X11-unix/X{}", n)).exists() && !Path::new(&format!("/tmp/.X{}-lock", n)).exists())
+        .unwrap_or(99)
+}
+
+// Starts Xorg through the configured privilege prefix, since -config with an absolute path needs root
+pub struct XorgLauncher {
+    prefix: &'static str,
+    child: Option<Child>,
+}
+
+impl XorgLauncher {
+    pub fn new(env: &Environment) -> XorgLauncher {
+        XorgLauncher { prefix: env.privilege.prefix(), child: None }
+    }
+}
+
+impl XLauncher for XorgLauncher {
+    fn start(&mut self, display: u32, config: &Path, xauthority: &Path) -> io::Result<()> {
+        let mut cookie = [0u8; 16];
+        fs::File::open("/dev/urandom")?.read_exact(&mut cookie)?;
+        let cookie: String = cookie.iter().map(|b| format!("{:02x}", b)).collect();
+
+        let status = Command::new("xauth")
+            .args(["-q", "-f"]).arg(xauthority)
+            .args(["add", &format!(":{}", display), ".", &cookie])
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::other("xauth could not create the cookie file"));
+        }
+
+        let cmd = format!("exec {}Xorg :{} -config {} -auth {} -nolisten tcp -noreset -novtswitch -sharevts",
+                          self.prefix, display, shell_quote(&config.to_string_lossy()), shell_quote(&xauthority.to_string_lossy()));
+        self.child = Some(Command::new("sh").arg("-c").arg(cmd).stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).spawn()?);
+        Ok(())
+    }
+
+    fn ready(&mut self, display: u32) -> bool {
+        Path::new(&format!("/tmp/.X11-unix/X{}", display)).exists()
+    }
+
+    fn running(&mut self) -> bool {
+        match self.child.as_mut() {
+            Some(child) => matches!(child.try_wait(), Ok(None)),
+            None => false,
+        }
+    }
+
+    fn stop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            // sudo passes SIGTERM on to Xorg, a SIGKILL would leave the server running
+            let _ = Command::new("kill").arg("-TERM").arg(child.id().to_string()).status();
+            let _ = child.wait();
+        }
+    }
+}
+
+/*
+ * Makes sure nvidia-settings has an X server to talk to, starting a headless one if discovery came up
+ * empty. Does nothing if a display was found or configured, or if headless is off.
+ */
+pub fn ensure(env: &mut Environment) -> Result<(), String> {
+    if env.x_found || env.headless_server.is_some() || env.config.get("headless") == "off" {
+        return Ok(());
+    }
+
+    if env.backend == Backend::DryRun {
+        println!("# No X server was found, a headless one would be started for nvidia-settings");
+        return Ok(());
+    }
+
+    let bus_ids = gpu_bus_ids(env).map_err(|e| format!("Failed to list GPUs for the headless X server. Error: {}", e))?;
+    if bus_ids.is_empty() {
+        return Err(String::from("No X server was found and no GPUs were listed by nvidia-smi to start a headless one on."));
+    }
+
+    // Root's Xorg reads the xorg.conf in here, so nobody else may be able to replace it
+    let dir = private_temp_dir("teamgreenhelper-x").map_err(|e| format!("Failed to create a directory for the headless X server. Error: {}", e))?;
+    let server = HeadlessServer::start(Box::new(XorgLauncher::new(env)), &bus_ids, dir, free_display(), START_TIMEOUT)?;
+
+    if env.debug {
+        println!("Started a headless X server on {} for {} GPU(s).", server.display, bus_ids.len());
+    }
+
+    env.display = server.display.clone();
+    env.xauthority = server.xauthority.to_string_lossy().into_owned();
+    env.config.set("display", &env.display.clone(), Source::Headless);
+    env.config.set("xauthority", &env.xauthority.clone(), Source::Headless);
+    env.headless_server = Some(server);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Default)]
+    struct Calls {
+        started: Vec<(u32, bool, PathBuf)>,
+        polls: u32,
+        stopped: bool,
+    }
+
+    // Becomes ready after ready_after polls, or exits on poll dies_after
+    struct FakeLauncher {
+        calls: Rc<RefCell<Calls>>,
+        ready_after: Option<u32>,
+        dies_after: Option<u32>,
+    }
+
+    impl XLauncher for FakeLauncher {
+        fn start(&mut self, display: u32, config: &Path, xauthority: &Path) -> io::Result<()> {
+            self.calls.borrow_mut().started.push((display, config.exists(), xauthority.to_path_buf()));
+            Ok(())
+        }
+
+        fn ready(&mut self, _: u32) -> bool {
+            let mut calls = self.calls.borrow_mut();
+            calls.polls += 1;
+            self.ready_after.is_some_and(|n| calls.polls > n)
+        }
+
+        fn running(&mut self) -> bool {
+            self.dies_after.is_none_or(|n| self.calls.borrow().polls <= n)
+        }
+
+        fn stop(&mut self) {
+            self.calls.borrow_mut().stopped = true;
+        }
+    }
+
+    fn launcher(ready_after: Option<u32>, dies_after: Option<u32>) -> (Box<FakeLauncher>, Rc<RefCell<Calls>>) {
+        let calls = Rc::new(RefCell::new(Calls::default()));
+        (Box::new(FakeLauncher { calls: calls.clone(), ready_after, dies_after }), calls)
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        private_temp_dir(&format!("tgh-headless-{}", name)).unwrap()
+    }
+
+    #[test]
+    fn server_is_stopped_and_cleaned_up_when_dropped() {
+        let (fake, calls) = launcher(Some(3), None);
+        let dir = scratch("drop");
+
+        let server = HeadlessServer::start(fake, &[String::from("PCI:1:0:0")], dir.clone(), 42, Duration::from_secs(5)).unwrap();
+
+        assert_eq!(server.display, ":42");
+        assert_eq!(server.xauthority, dir.join("Xauthority"));
+        assert_eq!(calls.borrow().started, vec![(42, true, dir.join("Xauthority"))]);
+        assert_eq!(calls.borrow().polls, 4);
+        assert!(!calls.borrow().stopped);
+
+        drop(server);
+
+        assert!(calls.borrow().stopped);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn server_that_exits_while_starting_is_reported() {
+        let (fake, calls) = launcher(None, Some(2));
+        let dir = scratch("exits");
+
+        let error = HeadlessServer::start(fake, &[String::from("PCI:1:0:0")], dir.clone(), 43, Duration::from_secs(5)).err().unwrap();
+
+        assert!(error.contains("exited while starting"));
+        assert!(calls.borrow().stopped);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn server_that_never_becomes_ready_times_out() {
+        let (fake, calls) = launcher(None, None);
+        let dir = scratch("timeout");
+
+        let error = HeadlessServer::start(fake, &[String::from("PCI:1:0:0")], dir.clone(), 44, Duration::from_millis(250)).err().unwrap();
+
+        assert!(error.contains("did not start"));
+        assert!(calls.borrow().stopped);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn server_directories_are_private_and_never_reused() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let first = scratch("private");
+        let second = scratch("private");
+
+        assert_ne!(first, second);
+        assert_eq!(fs::metadata(&first).unwrap().permissions().mode() & 0o777, 0o700);
+        let _ = fs::remove_dir(&first);
+        let _ = fs::remove_dir(&second);
+    }
+
+    #[test]
+    fn xorg_conf_enables_coolbits_on_every_gpu() {
+        let conf = xorg_conf(&[String::from("PCI:1:0:0"), String::from("PCI:10:0:0")]);
+
+        assert!(conf.contains("Screen 1 \"Screen1\" RightOf \"Screen0\""));
+        assert!(conf.contains("BusID \"PCI:1:0:0\""));
+        assert!(conf.contains("BusID \"PCI:10:0:0\""));
+        assert_eq!(conf.matches("Option \"Coolbits\" \"28\"").count(), 4);
+    }
+
+    #[test]
+    fn bus_ids_are_converted_from_hex() {
+        assert_eq!(xorg_bus_id("00000000:0A:00.0"), Some(String::from("PCI:10:0:0")));
+        assert_eq!(xorg_bus_id("00000000:41:1F.1\n"), Some(String::from("PCI:65:31:1")));
+        assert_eq!(xorg_bus_id("No devices were found"), None);
+    }
+}
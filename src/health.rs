@@ -0,0 +1,289 @@
+use crate::doctor::Status;
+use crate::executor::{execute, Environment, OutputFormat};
+use crate::json;
+use crate::nvidiagpu;
+
+/*
+ * health reports the memory health of every GPU for datacenter cards: ECC mode, error counts,
+ * retired pages (before Ampere) or remapped rows (Ampere and later), and whatever needs a reboot or
+ * GPU reset to take effect. Each GPU gets a status:
+ *
+ *   fail  uncorrected errors since the driver loaded, or a row that could not be remapped
+ *   warn  a reboot is pending (ECC mode change, page retirement or row remapping), or uncorrected
+ *         errors in the lifetime count
+ *   pass  everything else, including GPUs without ECC
+ *
+ * With output = json it prints one object per GPU for monitoring to alert on. In text mode it
+ * exits with 1 when a GPU fails.
+ */
+
+const ECC_FIELDS: &str = "name,ecc.mode.current,ecc.mode.pending,ecc.errors.corrected.volatile.total,ecc.errors.uncorrected.volatile.total,\
+    ecc.errors.corrected.aggregate.total,ecc.errors.uncorrected.aggregate.total,retired_pages.single_bit_ecc.count,\
+    retired_pages.double_bit.count,retired_pages.pending";
+
+#[derive(Default)]
+struct Health {
+    gpu: usize,
+    name: String,
+    // None where the GPU does not support the reading
+    ecc_current: Option<String>,
+    ecc_pending: Option<String>,
+    corrected_volatile: Option<u64>,
+    uncorrected_volatile: Option<u64>,
+    corrected_aggregate: Option<u64>,
+    uncorrected_aggregate: Option<u64>,
+    retired_single_bit: Option<u64>,
+    retired_double_bit: Option<u64>,
+    retired_pending: Option<bool>,
+    remapped_correctable: Option<u64>,
+    remapped_uncorrectable: Option<u64>,
+    remap_pending: Option<bool>,
+    remap_failure: Option<bool>,
+}
+
+fn text(value: &str) -> Option<String> {
+    if value.is_empty() || value.contains("N/A") { None } else { Some(value.to_string()) }
+}
+
+fn count(value: &str) -> Option<u64> {
+    value.parse::<u64>().ok()
+}
+
+fn flag(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "yes" | "1" | "true" => Some(true),
+        "no" | "0" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+impl Health {
+    // Problems with their status, most serious first
+    fn problems(&self) -> Vec<(Status, String)> {
+        let mut problems = Vec::new();
+
+        if let Some(n) = self.uncorrected_volatile.filter(|n| *n > 0) {
+            problems.push((Status::Fail, format!("{} uncorrected ECC error(s) since the driver loaded. Drain the GPU and reset it.", n)));
+        }
+        if self.remap_failure == Some(true) {
+            problems.push((Status::Fail, String::from("A row could not be remapped. The GPU should be replaced.")));
+        }
+        if self.ecc_pending.is_some() && self.ecc_pending != self.ecc_current {
+            problems.push((Status::Warn, format!("ECC changes to {} at the next reboot.", self.ecc_pending.as_deref().unwrap_or_default())));
+        }
+        if self.retired_pending == Some(true) {
+            problems.push((Status::Warn, String::from("Pages are waiting to be retired at the next reboot or GPU reset.")));
+        }
+        if self.remap_pending == Some(true) {
+            problems.push((Status::Warn, String::from("Rows are waiting to be remapped at the next GPU reset.")));
+        }
+        if let Some(n) = self.uncorrected_aggregate.filter(|n| *n > 0) {
+            problems.push((Status::Warn, format!("{} uncorrected ECC error(s) over the GPU's lifetime.", n)));
+        }
+
+        problems
+    }
+
+    fn status(&self) -> Status {
+        let problems = self.problems();
+
+        if problems.iter().any(|(s, _)| *s == Status::Fail) {
+            Status::Fail
+        } else if problems.is_empty() {
+            Status::Pass
+        } else {
+            Status::Warn
+        }
+    }
+
+    fn reboot_pending(&self) -> bool {
+        (self.ecc_pending.is_some() && self.ecc_pending != self.ecc_current) || self.retired_pending == Some(true) || self.remap_pending == Some(true)
+    }
+}
+
+// Row remapping is only reported by Ampere and later, so a failing query leaves those readings out
+fn add_remapped_rows(env: &Environment, gpus: &mut [Health]) {
+    let uuids = nvidiagpu::query_all_gpus(env, "uuid").unwrap_or_default();
+    let output = match execute(env, &String::from("nvidia-smi --query-remapped-rows=gpu_uuid,remapped_rows.correctable,remapped_rows.uncorrectable,\
+        remapped_rows.pending,remapped_rows.failure --format=csv,noheader,nounits")) {
+        Ok(o) if o.status.success() => o,
+        _ => return,
+    };
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let values: Vec<&str> = line.split(',').map(|v| v.trim()).collect();
+        if values.len() != 5 {
+            continue;
+        }
+
+        let index = uuids.iter().find(|row| row.len() == 2 && row[1] == values[0]).and_then(|row| row[0].parse::<usize>().ok());
+        if let Some(health) = gpus.iter_mut().find(|h| Some(h.gpu) == index) {
+            health.remapped_correctable = count(values[1]);
+            health.remapped_uncorrectable = count(values[2]);
+            health.remap_pending = flag(values[3]);
+            health.remap_failure = flag(values[4]);
+        }
+    }
+}
+
+fn query(env: &Environment) -> Result<Vec<Health>, String> {
+    let rows = nvidiagpu::query_all_gpus(env, ECC_FIELDS).map_err(|e| format!("Failed to read the ECC state. {}", e))?;
+
+    let mut gpus: Vec<Health> = rows.iter()
+        .filter(|r| r.len() == 11)
+        .filter_map(|r| Some(Health {
+            gpu: r[0].parse::<usize>().ok()?,
+            name: r[1].clone(),
+            ecc_current: text(&r[2]),
+            ecc_pending: text(&r[3]),
+            corrected_volatile: count(&r[4]),
+            uncorrected_volatile: count(&r[5]),
+            corrected_aggregate: count(&r[6]),
+            uncorrected_aggregate: count(&r[7]),
+            retired_single_bit: count(&r[8]),
+            retired_double_bit: count(&r[9]),
+            retired_pending: flag(&r[10]),
+            ..Health::default()
+        }))
+        .collect();
+
+    add_remapped_rows(env, &mut gpus);
+    Ok(gpus)
+}
+
+fn or_na<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map_or(String::from("N/A"), |v| v.to_string())
+}
+
+fn print_text(health: &Health) {
+    println!("[{}] GPU {}: {}", health.status(), health.gpu, health.name);
+
+    match &health.ecc_current {
+        Some(current) => {
+            println!("  ECC Mode: {} (pending: {})", current, or_na(&health.ecc_pending));
+            println!("  ECC Errors: volatile {} corrected, {} uncorrected | aggregate {} corrected, {} uncorrected",
+                     or_na(&health.corrected_volatile), or_na(&health.uncorrected_volatile), or_na(&health.corrected_aggregate), or_na(&health.uncorrected_aggregate));
+        },
+        None => println!("  ECC Mode: not supported"),
+    }
+
+    if health.retired_single_bit.is_some() || health.retired_double_bit.is_some() {
+        let pending = health.retired_pending.map_or("N/A", |p| if p { "Yes" } else { "No" });
+        println!("  Retired Pages: {} single bit, {} double bit, pending: {}", or_na(&health.retired_single_bit), or_na(&health.retired_double_bit), pending);
+    }
+    if health.remapped_correctable.is_some() || health.remapped_uncorrectable.is_some() {
+        let yes_no = |v: Option<bool>| v.map_or("N/A", |v| if v { "Yes" } else { "No" });
+        println!("  Remapped Rows: {} correctable, {} uncorrectable, pending: {}, failure: {}", or_na(&health.remapped_correctable),
+                 or_na(&health.remapped_uncorrectable), yes_no(health.remap_pending), yes_no(health.remap_failure));
+    }
+
+    for (status, problem) in health.problems() {
+        println!("  [{}] {}", status, problem);
+    }
+    println!();
+}
+
+fn to_json(health: &Health) -> String {
+    let number = |v: Option<u64>| v.map_or(String::from("null"), |n| n.to_string());
+    let boolean = |v: Option<bool>| v.map_or(String::from("null"), |b| b.to_string());
+    let problems: Vec<String> = health.problems().iter()
+        .map(|(status, problem)| json::Object::new().string("status", &status.to_string()).string("problem", problem).build())
+        .collect();
+
+    json::Object::new()
+        .raw("gpu", health.gpu.to_string())
+        .string("name", &health.name)
+        .string("status", &health.status().to_string())
+        .raw("reboot_pending", health.reboot_pending().to_string())
+        .raw("ecc_mode_current", health.ecc_current.as_deref().map_or(String::from("null"), json::string))
+        .raw("ecc_mode_pending", health.ecc_pending.as_deref().map_or(String::from("null"), json::string))
+        .raw("ecc_corrected_volatile", number(health.corrected_volatile))
+        .raw("ecc_uncorrected_volatile", number(health.uncorrected_volatile))
+        .raw("ecc_corrected_aggregate", number(health.corrected_aggregate))
+        .raw("ecc_uncorrected_aggregate", number(health.uncorrected_aggregate))
+        .raw("retired_pages_single_bit", number(health.retired_single_bit))
+        .raw("retired_pages_double_bit", number(health.retired_double_bit))
+        .raw("retired_pages_pending", boolean(health.retired_pending))
+        .raw("remapped_rows_correctable", number(health.remapped_correctable))
+        .raw("remapped_rows_uncorrectable", number(health.remapped_uncorrectable))
+        .raw("remapped_rows_pending", boolean(health.remap_pending))
+        .raw("remapped_rows_failure", boolean(health.remap_failure))
+        .raw("problems", json::array(&problems))
+        .build()
+}
+
+pub fn run(env: &Environment) -> Result<(), String> {
+    let gpus = query(env)?;
+
+    if env.output == OutputFormat::Json {
+        let items: Vec<String> = gpus.iter().map(to_json).collect();
+        println!("{}", json::array(&items));
+        return Ok(());
+    }
+
+    for health in &gpus {
+        print_text(health);
+    }
+
+    let failed = gpus.iter().filter(|h| h.status() == Status::Fail).count();
+    if failed > 0 {
+        return Err(format!("{} GPU(s) failed the health check.", failed));
+    }
+
+    Ok(())
+}
+
+// ecc on|off, which the driver only applies at the next reboot
+pub fn set_ecc(env: &Environment, gpu: &mut usize, arg: &str) -> Result<(), String> {
+    let enabled = match arg.to_lowercase().as_str() {
+        "on" | "1" | "enabled" => true,
+        "off" | "0" | "disabled" => false,
+        _ => return Err(format!("Failed to set ECC mode. {} is not on or off.", arg)),
+    };
+
+    crate::debug_message(env, nvidiagpu::set_ecc_mode(env, gpu, enabled), "ECC Mode")?;
+    println!("ECC on GPU {} will be {} after the next reboot. Until then it stays as it is.", gpu, if enabled { "enabled" } else { "disabled" });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statuses(health: &Health) -> Vec<Status> {
+        health.problems().into_iter().map(|(s, _)| s).collect()
+    }
+
+    #[test]
+    fn a_healthy_gpu_or_one_without_ecc_has_no_problems() {
+        let healthy = Health { ecc_current: Some(String::from("Enabled")), ecc_pending: Some(String::from("Enabled")), uncorrected_volatile: Some(0),
+                               uncorrected_aggregate: Some(0), retired_pending: Some(false), remap_pending: Some(false), remap_failure: Some(false),
+                               ..Health::default() };
+
+        assert!(healthy.problems().is_empty());
+        assert_eq!(healthy.status(), Status::Pass);
+        assert_eq!(Health::default().status(), Status::Pass);
+    }
+
+    #[test]
+    fn uncorrected_errors_and_failed_remaps_fail_before_the_warnings() {
+        let health = Health { uncorrected_volatile: Some(2), uncorrected_aggregate: Some(5), remap_failure: Some(true), remap_pending: Some(true),
+                              ..Health::default() };
+
+        assert_eq!(statuses(&health), vec![Status::Fail, Status::Fail, Status::Warn, Status::Warn]);
+        assert!(health.problems()[0].1.starts_with("2 uncorrected"));
+        assert_eq!(health.status(), Status::Fail);
+    }
+
+    #[test]
+    fn pending_changes_warn_and_need_a_reboot() {
+        let health = Health { ecc_current: Some(String::from("Enabled")), ecc_pending: Some(String::from("Disabled")), retired_pending: Some(true),
+                              ..Health::default() };
+
+        assert_eq!(statuses(&health), vec![Status::Warn, Status::Warn]);
+        assert_eq!(health.problems()[0].1, "ECC changes to Disabled at the next reboot.");
+        assert_eq!(health.status(), Status::Warn);
+        assert!(health.reboot_pending());
+        assert!(!Health::default().reboot_pending());
+    }
+}
@@ -0,0 +1,494 @@
+use std::ffi::c_void;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::{ExitStatus, Output};
+use std::time::{Duration, Instant};
+
+use crate::config;
+use crate::executor::{execute, Environment};
+
+/*
+ * A small service that runs as root and performs the nvidia-smi setters for unprivileged users, so a
+ * shared machine can let people set power limits without giving them sudo. With privilege = "helper"
+ * the CLI sends every command that needs root to it instead of escalating.
+ *
+ * The protocol is one request line per connection, the nvidia-smi command the CLI would have run:
+ *
+ *   nvidia-smi -i 0 -pl 250
+ *
+ * and the reply is a status line, either "status <exit code>" followed by the output of nvidia-smi,
+ * or "denied <reason>". Only the forms in SETTINGS are accepted, and every request is checked against
+ * the policy file, which is read again for each request so edits apply straight away.
+ */
+
+pub const DEFAULT_SOCKET: &str = "/run/teamgreenhelper.sock";
+pub const DEFAULT_POLICY: &str = "/etc/teamgreenhelper-policy.toml";
+
+// How long a client has to send its whole request, as the helper serves one client at a time
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST: usize = 1024;
+
+// What an option takes after it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Takes {
+    Nothing,
+    // A whole number, e.g. -pl 250
+    Number,
+    // Whole numbers separated by commas, e.g. -ac 5001,1800
+    Numbers,
+    // A name made of letters, digits and underscores, e.g. -c EXCLUSIVE_PROCESS
+    Name,
+}
+
+// nvidia-smi option, the setting it belongs to in the policy, and what it takes
+const SETTINGS: [(&str, &str, Takes); 10] = [
+    ("-lgc", "clock", Takes::Number),
+    ("-rgc", "clock", Takes::Nothing),
+    ("-lmc", "memory", Takes::Number),
+    ("-rmc", "memory", Takes::Nothing),
+    ("-pl", "power", Takes::Number),
+    ("-pm", "persistence", Takes::Number),
+    ("-c", "computemode", Takes::Name),
+    ("-ac", "appclocks", Takes::Numbers),
+    ("-rac", "appclocks", Takes::Nothing),
+    ("-e", "ecc", Takes::Number),
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+    pub gpu: usize,
+    pub option: &'static str,
+    pub setting: &'static str,
+    // None for the resets
+    pub value: Option<String>,
+}
+
+impl Request {
+    pub fn command(&self) -> String {
+        match &self.value {
+            Some(v) => format!("nvidia-smi -i {} {} {}", self.gpu, self.option, v),
+            None => format!("nvidia-smi -i {} {}", self.gpu, self.option),
+        }
+    }
+
+    fn takes(&self) -> Takes {
+        SETTINGS.iter().find(|(o, _, _)| *o == self.option).map_or(Takes::Nothing, |(_, _, t)| *t)
+    }
+}
+
+// The whole numbers of a value that takes Number or Numbers
+fn numbers(value: &str) -> Option<Vec<u64>> {
+    value.split(',').map(|n| n.parse::<u64>().ok()).collect()
+}
+
+// Accepts exactly the commands nvidiagpu builds for the root setters and nothing else
+pub fn parse_request(line: &str) -> Result<Request, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+
+    let (gpu, option, value) = match words.as_slice() {
+        ["nvidia-smi", "-i", gpu, option] => (gpu, option, None),
+        ["nvidia-smi", "-i", gpu, option, value] => (gpu, option, Some(value)),
+        _ => return Err(String::from("only nvidia-smi settings of one GPU are handled")),
+    };
+
+    let gpu = gpu.parse::<usize>().map_err(|_| format!("'{}' is not a GPU index", gpu))?;
+
+    let (option, setting, takes) = match SETTINGS.iter().find(|(o, _, _)| o == option) {
+        Some(s) => *s,
+        None => return Err(format!("nvidia-smi {} is not handled", option)),
+    };
+
+    let value = match (value, takes) {
+        (None, Takes::Nothing) => None,
+        (Some(v), Takes::Number) => Some(v.parse::<u64>().map_err(|_| format!("'{}' is not a whole number", v))?.to_string()),
+        (Some(v), Takes::Numbers) => Some(numbers(v).map(|n| n.iter().map(|n| n.to_string()).collect::<Vec<String>>().join(","))
+            .ok_or_else(|| format!("'{}' is not whole numbers separated by commas", v))?),
+        (Some(v), Takes::Name) if !v.is_empty() && v.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => Some(v.to_string()),
+        (Some(v), Takes::Name) => return Err(format!("'{}' is not a name", v)),
+        _ => return Err(format!("nvidia-smi {} was given the wrong number of values", option)),
+    };
+
+    Ok(Request { gpu, option, setting, value })
+}
+
+pub struct Caller {
+    pub uid: u32,
+    pub name: String,
+    pub groups: Vec<String>,
+}
+
+/*
+ * Who may use the helper and what they may set, e.g.
+ *
+ *   users = "alice, bob"
+ *   groups = "gpu"
+ *
+ *   [gpu]          # every GPU
+ *   power = "100-250"
+ *
+ *   [gpu1]         # GPU 1 only, replaces [gpu] for the settings it has
+ *   power = "100-300"
+ *   clock = "any"
+ *
+ * A setting with no range is refused. The settings are clock, memory, power, persistence (0-1),
+ * ecc (0-1), computemode, which lists the modes allowed, and appclocks, which has a range for the memory and
+ * one for the graphics clock, e.g. "5001-5001, 1200-1800". Resetting a setting is allowed wherever
+ * setting it is.
+ */
+pub struct Policy {
+    pairs: Vec<(String, String)>,
+}
+
+impl Policy {
+    pub fn load(path: &str) -> Result<Policy, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("the policy {} could not be read: {}", path, e))?;
+        let pairs = config::parse_toml(&contents).map_err(|e| format!("the policy {} is invalid: {}", path, e))?;
+        Ok(Policy { pairs })
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.pairs.iter().rev().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    fn list(&self, key: &str) -> Vec<&str> {
+        self.get(key).map_or(Vec::new(), |v| v.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()).collect())
+    }
+
+    pub fn check(&self, caller: &Caller, request: &Request) -> Result<(), String> {
+        let allowed = caller.uid == 0
+            || self.list("users").contains(&caller.name.as_str())
+            || caller.groups.iter().any(|g| self.list("groups").contains(&g.as_str()));
+        if !allowed {
+            return Err(format!("{} is not in the users or groups of the policy", caller.name));
+        }
+
+        let range = match self.get(&format!("gpu{}.{}", request.gpu, request.setting)).or_else(|| self.get(&format!("gpu.{}", request.setting))) {
+            Some(r) => r,
+            None => return Err(format!("the policy does not allow setting {} on GPU {}", request.setting, request.gpu)),
+        };
+
+        let value = match &request.value {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        if range.trim() == "any" {
+            return Ok(());
+        }
+
+        // Names are allowed by listing them, e.g. computemode = "DEFAULT, EXCLUSIVE_PROCESS"
+        if request.takes() == Takes::Name {
+            if !range.split(',').any(|name| name.trim().eq_ignore_ascii_case(value)) {
+                return Err(format!("{} {} is not one of {} on GPU {}", request.setting, value, range, request.gpu));
+            }
+            return Ok(());
+        }
+
+        // One min-max per number, e.g. appclocks = "5001-5001, 1200-1800"
+        let ranges: Vec<Option<(u64, u64)>> = range.split(',')
+            .map(|r| match r.split_once('-').map(|(a, b)| (a.trim().parse::<u64>(), b.trim().parse::<u64>())) {
+                Some((Ok(min), Ok(max))) => Some((min, max)),
+                _ => None,
+            })
+            .collect();
+        let values = numbers(value).unwrap_or_default();
+
+        if ranges.len() != values.len() || ranges.iter().any(|r| r.is_none()) {
+            return Err(format!("the policy range '{}' for {} is not {} min-max or any", range, request.setting, values.len()));
+        }
+
+        for (value, (min, max)) in values.iter().zip(ranges.into_iter().flatten()) {
+            if *value < min || *value > max {
+                return Err(format!("{} {} is outside the allowed range {}-{} on GPU {}", request.setting, value, min, max, request.gpu));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[repr(C)]
+struct UCred {
+    pid: i32,
+    uid: u32,
+    gid: u32,
+}
+
+extern "C" {
+    fn getsockopt(socket: i32, level: i32, name: i32, value: *mut c_void, length: *mut u32) -> i32;
+}
+
+const SOL_SOCKET: i32 = 1;
+const SO_PEERCRED: i32 = 17;
+
+// The kernel's record of who is on the other end, which the client cannot forge
+fn peer_credentials(stream: &UnixStream) -> io::Result<(u32, u32)> {
+    let mut credentials = UCred { pid: 0, uid: u32::MAX, gid: u32::MAX };
+    let mut length = std::mem::size_of::<UCred>() as u32;
+
+    // Safe as credentials and length live for the call and length is the size of credentials
+    let result = unsafe {
+        getsockopt(stream.as_raw_fd(), SOL_SOCKET, SO_PEERCRED, &mut credentials as *mut UCred as *mut c_void, &mut length)
+    };
+
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok((credentials.uid, credentials.gid))
+}
+
+// The name of uid in /etc/passwd, or the number if it has none
+pub fn user_name(uid: u32) -> String {
+    let passwd = fs::read_to_string("/etc/passwd").unwrap_or_default();
+    passwd.lines()
+        .map(|l| l.split(':').collect::<Vec<&str>>())
+        .find(|f| f.len() > 2 && f[2].parse::<u32>().ok() == Some(uid))
+        .map_or(uid.to_string(), |f| f[0].to_string())
+}
+
+// Looks the user and their groups up in /etc/passwd and /etc/group
+fn caller(uid: u32, gid: u32) -> Caller {
+    let name = user_name(uid);
+
+    let group = fs::read_to_string("/etc/group").unwrap_or_default();
+    let groups = group.lines()
+        .map(|l| l.split(':').collect::<Vec<&str>>())
+        .filter(|f| f.len() > 3 && (f[2].parse::<u32>().ok() == Some(gid) || f[3].split(',').any(|m| m == name)))
+        .map(|f| f[0].to_string())
+        .collect();
+
+    Caller { uid, name, groups }
+}
+
+/*
+ * Reads the request line against a deadline for the whole of it. A timeout per read would let a
+ * client hold the helper for as long as it keeps trickling bytes in.
+ */
+fn read_request(stream: &mut UnixStream, timeout: Duration) -> io::Result<String> {
+    let deadline = Instant::now() + timeout;
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 256];
+
+    while !request.contains(&b'\n') && request.len() < MAX_REQUEST {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "the request was not sent in time"));
+        }
+        stream.set_read_timeout(Some(remaining))?;
+
+        // A read that times out fails with WouldBlock, the check above then reports it
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => request.extend_from_slice(&buffer[..n]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    let line = request.split(|b| *b == b'\n').next().unwrap_or_default();
+    Ok(String::from_utf8_lossy(&line[..line.len().min(MAX_REQUEST)]).into_owned())
+}
+
+fn handle(env: &Environment, stream: &mut UnixStream) -> io::Result<()> {
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let (uid, gid) = peer_credentials(stream)?;
+    let caller = caller(uid, gid);
+
+    let line = read_request(stream, REQUEST_TIMEOUT)?;
+
+    let checked = parse_request(&line).and_then(|request| {
+        Policy::load(env.config.get("helper.policy"))?.check(&caller, &request)?;
+        Ok(request)
+    });
+
+    match checked {
+        Ok(request) => {
+            let output = execute(env, &request.command())?;
+            let code = output.status.code().unwrap_or(-1);
+            println!("{}: {} -> status {}", caller.name, request.command(), code);
+
+            writeln!(stream, "status {}", code)?;
+            stream.write_all(&output.stdout)?;
+            stream.write_all(&output.stderr)
+        },
+        Err(reason) => {
+            println!("{}: {} -> denied, {}", caller.name, line.trim(), reason);
+            writeln!(stream, "denied {}", reason)
+        }
+    }
+}
+
+/*
+ * Serves requests one at a time until killed. The socket is open to everyone, the policy decides who
+ * gets anything done.
+ */
+pub fn serve(env: &Environment) -> Result<(), String> {
+    let socket = env.config.get("helper.socket");
+
+    if execute(env, &String::from("id -u")).map(|o| String::from_utf8_lossy(&o.stdout).trim() == "0").unwrap_or(false) {
+        // Make sure the policy is usable before taking requests
+        Policy::load(env.config.get("helper.policy"))?;
+    } else {
+        return Err(String::from("The helper has to run as root to change GPU settings."));
+    }
+
+    if Path::new(socket).exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(format!("A helper is already listening on {}.", socket));
+        }
+        let _ = fs::remove_file(socket);
+    }
+
+    let listener = UnixListener::bind(socket).map_err(|e| format!("Failed to listen on {}. Error: {}", socket, e))?;
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o666)).map_err(|e| format!("Failed to open up {}. Error: {}", socket, e))?;
+
+    println!("Helper listening on {} with the policy {}", socket, env.config.get("helper.policy"));
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                if let Err(e) = handle(env, &mut stream) {
+                    println!("A request failed. Error: {}", e);
+                }
+            },
+            Err(e) => println!("Failed to accept a connection. Error: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+/*
+ * Client side, used by execute_as_root for privilege = "helper". A denial becomes an error and a
+ * status is returned as if nvidia-smi had been run here.
+ */
+pub fn request(env: &Environment, cmd: &str) -> io::Result<Output> {
+    let socket = env.config.get("helper.socket");
+    let mut stream = UnixStream::connect(socket)
+        .map_err(|e| io::Error::other(format!("The helper at {} could not be reached ({}). Is 'teamgreenhelper helper' running?", socket, e)))?;
+
+    writeln!(stream, "{}", cmd)?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+
+    let (status, output) = reply.split_once('\n').unwrap_or((reply.as_str(), ""));
+
+    if let Some(reason) = status.strip_prefix("denied ") {
+        return Err(io::Error::other(format!("The helper refused '{}': {}.", cmd, reason)));
+    }
+
+    match status.strip_prefix("status ").and_then(|c| c.parse::<i32>().ok()) {
+        // A wait status keeps the exit code in its second byte
+        Some(code) => Ok(Output { status: ExitStatus::from_raw((code & 0xff) << 8), stdout: output.as_bytes().to_vec(), stderr: Vec::new() }),
+        None => Err(io::Error::other(format!("The helper sent an unexpected reply: {}", status))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn policy(contents: &str) -> Policy {
+        Policy { pairs: config::parse_toml(contents).unwrap() }
+    }
+
+    fn user(name: &str, groups: &[&str]) -> Caller {
+        Caller { uid: 1000, name: name.to_string(), groups: groups.iter().map(|g| g.to_string()).collect() }
+    }
+
+    #[test]
+    fn requests_are_the_setter_commands_and_nothing_else() {
+        assert_eq!(parse_request("nvidia-smi -i 1 -pl 250\n"), Ok(Request { gpu: 1, option: "-pl", setting: "power", value: Some(String::from("250")) }));
+        assert_eq!(parse_request("nvidia-smi -i 0 -rgc").map(|r| r.command()), Ok(String::from("nvidia-smi -i 0 -rgc")));
+
+        assert!(parse_request("nvidia-smi -i 0 -pl 250; rm -rf /").is_err());
+        assert!(parse_request("nvidia-smi -i 0 -pl -250").is_err());
+        assert!(parse_request("nvidia-smi -i 0 -pl").is_err());
+        assert!(parse_request("nvidia-smi -i 0 -rgc 1500").is_err());
+        assert!(parse_request("nvidia-smi -i 0 --gpu-reset").is_err());
+        assert!(parse_request("nvidia-smi -i x -pl 250").is_err());
+        assert!(parse_request("sh -c id").is_err());
+    }
+
+    #[test]
+    fn policy_lets_listed_users_and_groups_set_values_in_range() {
+        let policy = policy("users = \"alice\"\ngroups = \"gpu, render\"\n[gpu]\npower = \"100-250\"\n[gpu1]\npower = \"100-300\"\nclock = \"any\"\n");
+        let power = |gpu: usize, watts: u64| Request { gpu, option: "-pl", setting: "power", value: Some(watts.to_string()) };
+
+        assert!(policy.check(&user("alice", &[]), &power(0, 250)).is_ok());
+        assert!(policy.check(&user("bob", &["render"]), &power(0, 100)).is_ok());
+        assert!(policy.check(&user("bob", &["users"]), &power(0, 100)).is_err());
+        assert!(policy.check(&Caller { uid: 0, name: String::from("root"), groups: Vec::new() }, &power(0, 200)).is_ok());
+
+        assert!(policy.check(&user("alice", &[]), &power(0, 251)).is_err());
+        assert!(policy.check(&user("alice", &[]), &power(0, 99)).is_err());
+        assert!(policy.check(&user("alice", &[]), &power(1, 300)).is_ok());
+    }
+
+    #[test]
+    fn policy_refuses_settings_it_does_not_name() {
+        let policy = policy("users = \"alice\"\n[gpu]\npower = \"100-250\"\n[gpu1]\nclock = \"any\"\nmemory = \"lots\"\n");
+        let alice = user("alice", &[]);
+
+        assert!(policy.check(&alice, &Request { gpu: 0, option: "-lgc", setting: "clock", value: Some(String::from("1500")) }).is_err());
+        assert!(policy.check(&alice, &Request { gpu: 1, option: "-lgc", setting: "clock", value: Some(String::from("1500")) }).is_ok());
+        assert!(policy.check(&alice, &Request { gpu: 1, option: "-rgc", setting: "clock", value: None }).is_ok());
+        assert!(policy.check(&alice, &Request { gpu: 1, option: "-lmc", setting: "memory", value: Some(String::from("5000")) }).is_err());
+    }
+
+    #[test]
+    fn mode_and_clock_pair_requests_are_checked_against_their_own_policy() {
+        assert_eq!(parse_request("nvidia-smi -i 0 -ac 5001,1800").map(|r| r.value), Ok(Some(String::from("5001,1800"))));
+        assert_eq!(parse_request("nvidia-smi -i 0 -c DEFAULT").map(|r| r.setting), Ok("computemode"));
+        assert!(parse_request("nvidia-smi -i 0 -rac").is_ok());
+        assert!(parse_request("nvidia-smi -i 0 -ac 5001,").is_err());
+        assert!(parse_request("nvidia-smi -i 0 -c $(id)").is_err());
+        assert!(parse_request("nvidia-smi -i 0 -pm on").is_err());
+        assert_eq!(parse_request("nvidia-smi -i 0 -e 0").map(|r| r.setting), Ok("ecc"));
+
+        let policy = policy("users = \"alice\"\n[gpu]\npersistence = \"0-1\"\ncomputemode = \"DEFAULT, EXCLUSIVE_PROCESS\"\nappclocks = \"5001-5001, 1200-1800\"\n");
+        let alice = user("alice", &[]);
+        let check = |line: &str| policy.check(&alice, &parse_request(line).unwrap());
+
+        assert!(check("nvidia-smi -i 0 -pm 1").is_ok());
+        assert!(check("nvidia-smi -i 0 -c exclusive_process").is_ok());
+        assert!(check("nvidia-smi -i 0 -c PROHIBITED").is_err());
+        assert!(check("nvidia-smi -i 0 -ac 5001,1800").is_ok());
+        assert!(check("nvidia-smi -i 0 -ac 5001,1900").is_err());
+        assert!(check("nvidia-smi -i 0 -ac 877,1200").is_err());
+        assert!(check("nvidia-smi -i 0 -rac").is_ok());
+    }
+
+    #[test]
+    fn a_request_has_to_arrive_within_the_deadline() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        client.write_all(b"nvidia-smi -i 0 -rgc\nignored").unwrap();
+        assert_eq!(read_request(&mut server, Duration::from_secs(1)).unwrap(), "nvidia-smi -i 0 -rgc");
+
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let trickle = thread::spawn(move || {
+            for byte in b"nvidia-smi -i 0 -rgc\n" {
+                if client.write_all(&[*byte]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let started = Instant::now();
+        let error = read_request(&mut server, Duration::from_millis(300)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        drop(server);
+        let _ = trickle.join();
+    }
+}
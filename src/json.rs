@@ -0,0 +1,272 @@
+/*
+ * Just enough JSON for machine readable output and the daemon's requests, kept in house so the
+ * program stays free of dependencies. Output is built up as already-encoded strings, input is parsed
+ * into a Value.
+ */
+
+pub fn string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+pub fn array(items: &[String]) -> String {
+    format!("[{}]", items.join(","))
+}
+
+#[derive(Default)]
+pub struct Object {
+    fields: Vec<(String, String)>,
+}
+
+impl Object {
+    pub fn new() -> Object {
+        Object::default()
+    }
+
+    pub fn string(self, key: &str, value: &str) -> Object {
+        self.raw(key, string(value))
+    }
+
+    // Adds a value that is already encoded, e.g. a nested object or array
+    pub fn raw(mut self, key: &str, value: String) -> Object {
+        self.fields.push((key.to_string(), value));
+        self
+    }
+
+    pub fn build(self) -> String {
+        let fields: Vec<String> = self.fields.into_iter().map(|(k, v)| format!("{}:{}", string(&k), v)).collect();
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+pub fn number(value: f64) -> String {
+    if value.is_finite() { value.to_string() } else { String::from("null") }
+}
+
+// A parsed JSON document, for reading requests
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        match self {
+            Value::Null => String::from("null"),
+            Value::Bool(b) => b.to_string(),
+            Value::Number(n) => number(*n),
+            Value::String(s) => string(s),
+            Value::Array(items) => array(&items.iter().map(|v| v.encode()).collect::<Vec<String>>()),
+            Value::Object(fields) => fields.iter().fold(Object::new(), |o, (k, v)| o.raw(k, v.encode())).build(),
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Value, String> {
+    let mut parser = Parser { chars: input.chars().collect(), position: 0 };
+    let value = parser.value()?;
+
+    parser.whitespace();
+    if parser.position < parser.chars.len() {
+        return Err(format!("Unexpected '{}' after the value at {}.", parser.chars[parser.position], parser.position));
+    }
+
+    Ok(value)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn whitespace(&mut self) {
+        while self.chars.get(self.position).is_some_and(|c| c.is_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    fn expect(&mut self, word: &str, value: Value) -> Result<Value, String> {
+        for c in word.chars() {
+            if self.chars.get(self.position) != Some(&c) {
+                return Err(format!("Expected '{}' at {}.", word, self.position));
+            }
+            self.position += 1;
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.whitespace();
+
+        match self.chars.get(self.position) {
+            Some('n') => self.expect("null", Value::Null),
+            Some('t') => self.expect("true", Value::Bool(true)),
+            Some('f') => self.expect("false", Value::Bool(false)),
+            Some('"') => self.string().map(Value::String),
+            Some('[') => self.array(),
+            Some('{') => self.object(),
+            Some(c) if *c == '-' || c.is_ascii_digit() => self.number(),
+            Some(c) => Err(format!("Unexpected '{}' at {}.", c, self.position)),
+            None => Err(String::from("Unexpected end of input.")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let start = self.position;
+        while self.chars.get(self.position).is_some_and(|c| c.is_ascii_digit() || "+-.eE".contains(*c)) {
+            self.position += 1;
+        }
+
+        let text: String = self.chars[start..self.position].iter().collect();
+        text.parse::<f64>().map(Value::Number).map_err(|_| format!("'{}' at {} is not a number.", text, start))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let start = self.position;
+        let mut out = String::new();
+        self.position += 1;
+
+        loop {
+            let c = match self.chars.get(self.position) {
+                Some(c) => *c,
+                None => return Err(format!("The string at {} is not closed.", start)),
+            };
+            self.position += 1;
+
+            match c {
+                '"' => return Ok(out),
+                '\\' => {
+                    let escaped = self.chars.get(self.position).copied();
+                    self.position += 1;
+                    match escaped {
+                        Some('n') => out.push('\n'),
+                        Some('r') => out.push('\r'),
+                        Some('t') => out.push('\t'),
+                        Some('b') => out.push('\u{8}'),
+                        Some('f') => out.push('\u{c}'),
+                        Some('u') => {
+                            let hex: String = self.chars.iter().skip(self.position).take(4).collect();
+                            let code = u32::from_str_radix(&hex, 16).map_err(|_| format!("Invalid \\u escape at {}.", self.position))?;
+                            out.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                            self.position += 4;
+                        },
+                        Some(c) => out.push(c),
+                        None => return Err(format!("The string at {} is not closed.", start)),
+                    }
+                },
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        let mut items = Vec::new();
+        self.position += 1;
+        self.whitespace();
+
+        if self.chars.get(self.position) == Some(&']') {
+            self.position += 1;
+            return Ok(Value::Array(items));
+        }
+
+        loop {
+            items.push(self.value()?);
+            self.whitespace();
+
+            match self.chars.get(self.position) {
+                Some(',') => self.position += 1,
+                Some(']') => {
+                    self.position += 1;
+                    return Ok(Value::Array(items));
+                },
+                _ => return Err(format!("Expected ',' or ']' at {}.", self.position)),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        let mut fields = Vec::new();
+        self.position += 1;
+        self.whitespace();
+
+        if self.chars.get(self.position) == Some(&'}') {
+            self.position += 1;
+            return Ok(Value::Object(fields));
+        }
+
+        loop {
+            self.whitespace();
+            if self.chars.get(self.position) != Some(&'"') {
+                return Err(format!("Expected a key at {}.", self.position));
+            }
+            let key = self.string()?;
+
+            self.whitespace();
+            if self.chars.get(self.position) != Some(&':') {
+                return Err(format!("Expected ':' at {}.", self.position));
+            }
+            self.position += 1;
+
+            fields.push((key, self.value()?));
+            self.whitespace();
+
+            match self.chars.get(self.position) {
+                Some(',') => self.position += 1,
+                Some('}') => {
+                    self.position += 1;
+                    return Ok(Value::Object(fields));
+                },
+                _ => return Err(format!("Expected ',' or '}}' at {}.", self.position)),
+            }
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::executor::Environment;
+use crate::nvidiagpu::{self, GpuSnapshot};
+use crate::{signals, units};
+
+/*
+ * log appends a timestamped snapshot of every GPU to a file on each tick, for lining up throttling
+ * with a slow training run afterwards:
+ *
+ *   log --interval 5s --out run.csv (--format csv|influx) (--rotate-size 100M) (--rotate-time 1d)
+ *
+ * The file is flushed after every tick and on SIGTERM or Ctrl-C, so a stopped run loses nothing.
+ * A rotated file is renamed with the time it was rotated, e.g. run-20240101T000000Z.csv, and a new
+ * one is started.
+ */
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+// Every tick runs nvidia-smi, so anything shorter would keep a core busy
+const MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Csv,
+    // InfluxDB line protocol
+    Influx,
+}
+
+struct Options {
+    interval: Duration,
+    out: PathBuf,
+    format: Format,
+    rotate_size: Option<u64>,
+    rotate_time: Option<Duration>,
+}
+
+fn parse_options(args: &[&String]) -> Result<Options, String> {
+    let mut interval = DEFAULT_INTERVAL;
+    let mut out = None;
+    let mut format = None;
+    let mut rotate_size = None;
+    let mut rotate_time = None;
+
+    for pair in args.chunks(2) {
+        let (flag, value) = match pair {
+            [flag, value] => (flag.as_str(), value.as_str()),
+            _ => return Err(format!("'{}' needs a value.", pair[0])),
+        };
+
+        match flag {
+            "--interval" => interval = units::parse_duration(value)?,
+            "--out" => out = Some(PathBuf::from(value)),
+            "--format" => format = Some(match value {
+                "csv" => Format::Csv,
+                "influx" => Format::Influx,
+                _ => return Err(format!("'{}' is not a log format. Expected csv or influx.", value)),
+            }),
+            "--rotate-size" => rotate_size = Some(units::parse_size(value)?),
+            "--rotate-time" => rotate_time = Some(units::parse_duration(value)?),
+            _ => return Err(format!("'{}' is not a log option. Expected --interval, --out, --format, --rotate-size or --rotate-time.", flag)),
+        }
+    }
+
+    if interval < MIN_INTERVAL {
+        return Err(format!("The log interval has to be at least {}ms.", MIN_INTERVAL.as_millis()));
+    }
+
+    let out = out.ok_or_else(|| String::from("log needs a file to write to, e.g. log --out run.csv"))?;
+
+    // Unless it is given, the format follows the extension
+    let format = format.unwrap_or(match out.extension().and_then(|e| e.to_str()) {
+        Some("influx") | Some("lp") => Format::Influx,
+        _ => Format::Csv,
+    });
+
+    Ok(Options { interval, out, format, rotate_size, rotate_time })
+}
+
+// Days since 1970-01-01 to a year, month and day, from Howard Hinnant's civil_from_days
+fn civil_date(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + if month <= 2 { 1 } else { 0 }, month, day)
+}
+
+// RFC 3339 in UTC, or the compact form used in rotated file names
+pub fn format_utc(time: SystemTime, compact: bool) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) as i64;
+    let (year, month, day) = civil_date(seconds.div_euclid(86400));
+    let of_day = seconds.rem_euclid(86400);
+    let (hour, minute, second) = (of_day / 3600, of_day % 3600 / 60, of_day % 60);
+
+    if compact {
+        format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day, hour, minute, second)
+    } else {
+        format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hour, minute, second)
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn csv_header() -> String {
+    let readings: Vec<&str> = GpuSnapshot::default().readings().iter().map(|(name, _, _)| *name).collect();
+    format!("timestamp,gpu,uuid,name,{}\n", readings.join(","))
+}
+
+fn csv_line(time: SystemTime, gpu: &GpuSnapshot) -> String {
+    let readings: Vec<String> = gpu.readings().iter().map(|(_, _, v)| v.map_or(String::new(), |v| v.to_string())).collect();
+    format!("{},{},{},{},{}\n", format_utc(time, false), gpu.index, csv_field(&gpu.uuid), csv_field(&gpu.name), readings.join(","))
+}
+
+fn influx_tag(value: &str) -> String {
+    value.replace('\\', "\\\\").replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+// nvidia_gpu,gpu=0,uuid=...,name=... core_clock_mhz=1635,... <nanoseconds>, or nothing if every reading is N/A
+fn influx_line(time: SystemTime, gpu: &GpuSnapshot) -> String {
+    let fields: Vec<String> = gpu.readings().iter()
+        .filter_map(|(name, _, value)| value.map(|v| format!("{}={}", name, v)))
+        .collect();
+
+    if fields.is_empty() {
+        return String::new();
+    }
+
+    let nanoseconds = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+    format!("nvidia_gpu,gpu={},uuid={},name={},driver_version={} {} {}\n", gpu.index, influx_tag(&gpu.uuid), influx_tag(&gpu.name),
+            influx_tag(&gpu.driver_version), fields.join(","), nanoseconds)
+}
+
+// Rotating twice in one second adds a counter rather than overwriting the first
+fn rotated_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().map_or(String::from("log"), |s| s.to_string_lossy().into_owned());
+    let ext = path.extension().map_or(String::new(), |e| format!(".{}", e.to_string_lossy()));
+    let time = format_utc(SystemTime::now(), true);
+
+    (1..)
+        .map(|n| if n == 1 { format!("{}-{}{}", stem, time, ext) } else { format!("{}-{}-{}{}", stem, time, n, ext) })
+        .map(|name| path.with_file_name(name))
+        .find(|path| !path.exists())
+        .unwrap_or_default()
+}
+
+struct LogFile {
+    path: PathBuf,
+    format: Format,
+    writer: BufWriter<File>,
+    size: u64,
+    opened: Instant,
+}
+
+impl LogFile {
+    /*
+     * Appends to the file if it exists, a CSV file gets its header when it is empty. A CSV file with
+     * other columns, written by another version, is rotated first so no file mixes two sets of columns.
+     */
+    fn open(path: &Path, format: Format) -> io::Result<LogFile> {
+        if format == Format::Csv {
+            let mut first_line = String::new();
+            if File::open(path).and_then(|f| BufReader::new(f).read_line(&mut first_line)).is_ok_and(|n| n > 0) && first_line != csv_header() {
+                let rotated = rotated_path(path);
+                println!("{} has other columns than this version logs, moving it to {}.", path.display(), rotated.display());
+                fs::rename(path, rotated)?;
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        let mut log = LogFile { path: path.to_path_buf(), format, writer: BufWriter::new(file), size, opened: Instant::now() };
+
+        if format == Format::Csv && size == 0 {
+            log.append(&csv_header())?;
+        }
+
+        Ok(log)
+    }
+
+    fn append(&mut self, text: &str) -> io::Result<()> {
+        self.writer.write_all(text.as_bytes())?;
+        self.size += text.len() as u64;
+        Ok(())
+    }
+
+    fn write(&mut self, time: SystemTime, snapshots: &[GpuSnapshot]) -> io::Result<()> {
+        for gpu in snapshots {
+            let line = match self.format {
+                Format::Csv => csv_line(time, gpu),
+                Format::Influx => influx_line(time, gpu),
+            };
+            self.append(&line)?;
+        }
+
+        self.writer.flush()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        fs::rename(&self.path, rotated_path(&self.path))?;
+        *self = LogFile::open(&self.path, self.format)?;
+        Ok(())
+    }
+}
+
+pub fn run(env: &Environment, args: &[&String]) -> Result<(), String> {
+    let options = parse_options(args)?;
+    let failed = |e: io::Error| format!("Failed to write {}. Error: {}", options.out.display(), e);
+
+    let mut log = LogFile::open(&options.out, options.format).map_err(failed)?;
+    signals::install();
+
+    println!("Logging every {:?} to {}. Stop with Ctrl-C or SIGTERM.", options.interval, options.out.display());
+
+    let mut next = Instant::now();
+
+    loop {
+        let due = options.rotate_size.is_some_and(|max| log.size >= max) || options.rotate_time.is_some_and(|every| log.opened.elapsed() >= every);
+        if due {
+            log.rotate().map_err(failed)?;
+        }
+
+        match nvidiagpu::query_snapshots(env) {
+            Ok(snapshots) => log.write(SystemTime::now(), &snapshots).map_err(failed)?,
+            Err(e) => println!("Failed to read the GPUs. {}", e),
+        }
+
+        next += options.interval;
+        if !signals::sleep(next.saturating_duration_since(Instant::now())) {
+            break;
+        }
+    }
+
+    log.writer.flush().map_err(failed)?;
+    println!("Stopped logging to {}.", options.out.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(args: &[&str]) -> Result<Options, String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        parse_options(&args.iter().collect::<Vec<&String>>())
+    }
+
+    #[test]
+    fn intervals_too_short_for_nvidia_smi_are_rejected() {
+        assert!(options(&["--interval", "1ms", "--out", "run.csv"]).is_err());
+        assert!(options(&["--interval", "0.00001s", "--out", "run.csv"]).is_err());
+        assert_eq!(options(&["--interval", "100ms", "--out", "run.csv"]).unwrap().interval, MIN_INTERVAL);
+        assert_eq!(options(&["--out", "run.lp"]).unwrap().format, Format::Influx);
+    }
+
+    #[test]
+    fn csv_files_with_other_columns_are_rotated_before_appending() {
+        let dir = crate::executor::private_temp_dir("teamgreenhelper-log").unwrap();
+        let path = dir.join("run.csv");
+
+        fs::write(&path, "timestamp,gpu,uuid,name,core_clock_mhz\n").unwrap();
+        drop(LogFile::open(&path, Format::Csv).unwrap());
+        drop(LogFile::open(&path, Format::Csv).unwrap());
+
+        let files = fs::read_dir(&dir).unwrap().count();
+        let contents = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(files, 2);
+        assert_eq!(contents, csv_header());
+        assert!(csv_header().ends_with(",pcie_link_width,memory_temperature_celsius\n"));
+    }
+}
@@ -1,113 +1,174 @@
 mod executor;
+mod config;
+mod json;
 mod commands;
 mod nvidiagpu;
+mod units;
+mod script;
+mod shell;
+mod xserver;
+mod headless;
+mod coolbits;
+mod doctor;
+mod helper;
+mod daemon;
+mod fancurve;
+mod schedule;
+mod exporter;
+mod signals;
+mod logging;
+mod guard;
+mod throttle;
+mod procs;
+mod reservation;
+mod autotune;
+mod sweep;
+mod undervolt;
+mod status;
+mod health;
+mod state;
 
 use std::env;
 use std::collections::HashMap;
 use std::process::Output;
 use std::io::{Result};
 
-use crate::executor::{execute, Environment};
-use crate::commands::{HelperCommand, new_command};
+use crate::executor::{get_smi_ret_message, Backend, Environment};
+use crate::commands::{HelperCommand, default_commands};
+use crate::units::{Unit, Value};
+use crate::config::Source;
 
-pub const BUILD_VERSION: &'static str = "2";
+pub const BUILD_VERSION: &str = "2";
+
+pub fn cmd_exists<'a>(arg: &'a String, commands: &'a HashMap<String, HelperCommand>) -> Option<&'a HelperCommand> {
+    match commands.get(arg) {
+        Some(x) => {
+            Some(x)
+        },
+        None => {
+            check_alias(arg, commands)
+        }
+    }
+}
+
+fn check_alias<'a>(arg: &'a String, args: &'a HashMap<String, HelperCommand>) -> Option<&'a HelperCommand> {
+    args.values().find(|x| x.aliases.contains(arg))
+}
 
 /*
- * The first section will generally work on systems like Ubuntu or Arch Linux
- * However, on some systems running CentOS, Fedora, and others it will not give
- * the .Xauthority file location. Therefore, we will have to query for it through
- * the ps command to find the Xorg running process and its arguments.
- *
- * If the .Xauthority file is located under the home directory of the user, then
- * nvidia-settings seems to ignore whatever we send to it through the command line and
- * uses that file instead.
- *
- * The default value of /run/user/1000/gdm/Xauthority will not be removed if neither
- * command can come up with something.
+ * Prints the output of a setter when debugging and turns a failed execution into an error message
+ * naming the operation, so that callers can report which command went wrong.
  */
-fn get_xauthority(env: &mut Environment) {
-    let sysctl_env_out = execute(env, &String::from("systemctl --user show-environment | grep XAUTHORITY=")).unwrap();
-    let sysctl_env_out_str = String::from_utf8(sysctl_env_out.stdout).unwrap();
-
-    if sysctl_env_out_str.contains("XAUTHORITY=") {
-        env.xauthority = sysctl_env_out_str.chars().skip(11).take(sysctl_env_out_str.len() - 12).collect();
-    } else {
-        let sysctl_env_out = execute(env, &String::from("ps a | grep /Xauthority")).unwrap();
-        let sysctl_env_out_str = String::from_utf8(sysctl_env_out.stdout).unwrap();
-
-        match sysctl_env_out_str.find("/gdm/Xauthority") {
-            Some(i) => {
-                let mut index = i;
-
-                while sysctl_env_out_str.chars().nth(index - 1).unwrap() != ' ' {
-                    index -= 1;
-                }
+pub fn debug_message(env: &Environment, out: Result<Output>, operation: &'static str) -> std::result::Result<(), String> {
+    match out {
+        Ok(o) => {
+            if env.debug {
+                println!("{}", String::from_utf8_lossy(&o.stdout));
+                println!("{}", String::from_utf8_lossy(&o.stderr));
+            }
 
-                env.xauthority = sysctl_env_out_str.chars().skip(index).take((i - index) + 15).collect();
-            },
-            None => {
-                //Keep environment xauthority value as its default
+            match o.status.code() {
+                Some(0) => Ok(()),
+                Some(code) => {
+                    exporter::record_failure(env, &code.to_string());
+                    Err(format!("There was a problem setting this GPU's {}. Status Code: {} - {}", operation, code, get_smi_ret_message(code)))
+                },
+                None => {
+                    exporter::record_failure(env, "signal");
+                    Err(format!("There was a problem setting this GPU's {}. The command was terminated by a signal.", operation))
+                },
             }
         }
+        Err(err) => {
+            exporter::record_failure(env, "error");
+
+            // The command succeeded but the value read back differs, which needs a different fix
+            if let Some(not_applied) = err.get_ref().and_then(|e| e.downcast_ref::<nvidiagpu::NotApplied>()) {
+                return Err(format!("This GPU's {} was not applied: {}.", operation, not_applied));
+            }
+
+            Err(format!("There was a problem setting this GPU's {}. Error: {}", operation, err))
+        }
     }
 }
 
-fn get_display(env: &mut Environment) {
-    let sysctl_env_out = execute(env, &String::from("systemctl --user show-environment | grep DISPLAY=")).unwrap();
-    let sysctl_env_out_str = String::from_utf8(sysctl_env_out.stdout).unwrap();
+// -1 has always meant "back to the driver default" for fans and locked clocks
+fn is_reset_value(arg: &str) -> bool {
+    arg == "-1" || arg.eq_ignore_ascii_case("reset") || arg.eq_ignore_ascii_case("auto")
+}
+
+/*
+ * Parses a setter value and, if it is relative (+50, -10%), resolves it against the GPU's current
+ * reading of field.
+ */
+fn resolve_value(env: &Environment, gpu: &usize, arg: &str, unit: Unit, field: &str) -> std::result::Result<f64, String> {
+    let value = units::parse_value(arg, unit)?;
+
+    match value.resolve(|| nvidiagpu::query_gpu_number(env, gpu, field)) {
+        Some(n) => Ok(n),
+        None => Err(format!("Could not read the current {} to apply '{}' to.", field, arg)),
+    }
+}
 
-    if sysctl_env_out_str.contains("DISPLAY=") {
-        let len = sysctl_env_out_str.len();
-        env.display = sysctl_env_out_str.chars().skip(8).take(len - 9).collect();
+// GPUs without applications clocks fail -rac, so reset leaves them out
+fn reset_applications_clocks(env: &Environment, gpu: &mut usize) -> std::result::Result<(), String> {
+    if nvidiagpu::query_supported_clocks(env, gpu).is_ok_and(|clocks| clocks.is_empty()) {
+        return Ok(());
     }
 
-    //if not then we leave it as its default of :0 which is a pretty good guess
+    debug_message(env, nvidiagpu::reset_applications_clocks(env, gpu), "Resetting Applications Clocks")
 }
 
-fn cmd_exists<'a>(arg: &'a String, commands: &'a HashMap<String, HelperCommand>) -> Option<&'a HelperCommand> {
-    return match commands.get(arg) {
-        Some(x) => {
-            Some(x)
-        },
+/*
+ * Puts setting back to what it was before it was first changed this boot. A setting with nothing
+ * recorded was not changed, so it is left as it is. Every user can write the record, so only a
+ * value from allowed is set, as the name given there rather than the recorded text.
+ */
+fn restore_original(env: &Environment, gpu: &mut usize, setting: &str, allowed: &[&'static str],
+                    set: impl FnOnce(&Environment, &mut usize, &'static str) -> Result<Output>, operation: &'static str) -> std::result::Result<(), String> {
+    let original = match state::original(env, *gpu, setting) {
+        Some(original) => original,
+        None => return Ok(()),
+    };
+
+    let value = match allowed.iter().find(|a| a.eq_ignore_ascii_case(&original)) {
+        Some(value) => *value,
         None => {
-            match check_alias(arg, &commands) {
-                Some(x) => {
-                    Some(x)
-                },
-                None => {
-                    None
-                }
-            }
+            state::record_original(env, *gpu, setting, None);
+            return Err(format!("The {} recorded before the first change, '{}', is not one of {}, so it was left as it is.",
+                               operation, original, allowed.join(", ")));
         }
+    };
+
+    debug_message(env, set(env, gpu, value), operation)?;
+    if env.backend != Backend::DryRun {
+        state::record_original(env, *gpu, setting, None);
     }
+    Ok(())
 }
 
-fn check_alias<'a>(arg: &'a String, args: &'a HashMap<String, HelperCommand>) -> Option<&'a HelperCommand> {
-    for x in args.values() {
-        if x.aliases.contains(arg) {
-            return Some(x);
-        }
-    }
+// Commands that go through nvidia-settings and so need an X server
+const X_COMMANDS: [&str; 5] = ["fan", "memoryoffset", "clockoffset", "powermizer", "reset"];
 
-    return None;
-}
+// Commands whose first argument names a setting, so it may be a command name, e.g. sweep power
+const SETTING_COMMANDS: [&str; 1] = ["sweep"];
 
-fn debug_message(env: &Environment, out: Result<Output>, operation: &'static str) {
-    if env.debug {
-        match out {
-            Ok(o) => {
-                println!("{}", String::from_utf8_lossy(&o.stdout));
-                println!("{}", String::from_utf8_lossy(&o.stderr));
-            }
-            Err(err) => {
-                println!("There was a problem setting this GPU's {}. Error: {}", operation, err);
-            }
+fn run(cmd: &HelperCommand, args: &[&String], env: &mut Environment, gpu: &mut usize) -> std::result::Result<(), String> {
+    if !cmd.args.contains(&args.len()) { return Err(format!("'{}' does not accept {} arguments. See 'help' for more information.", cmd.name, args.len())); }
+
+    // While the daemon runs it owns the GPUs, dry runs stay local since they change nothing
+    // appclocks list only reads, and its output belongs here rather than on the daemon's terminal
+    // The guided undervolt runs the caller's test, so only its result is sent, by undervolt itself
+    let query = (cmd.name == "appclocks" && args[0].eq_ignore_ascii_case("list")) || (cmd.name == "undervolt" && args.iter().any(|a| a.as_str() == "--test"));
+    if daemon::FORWARDED_COMMANDS.contains(&cmd.name.as_str()) && !env.in_daemon && env.backend != Backend::DryRun && !query {
+        if let Some(result) = daemon::forward(env, &cmd.name, args, gpu) {
+            return result;
         }
     }
-}
 
-fn run(cmd: &HelperCommand, args: &Vec<&String>, env: &mut Environment, gpu: &mut usize) {
-    if !cmd.args.contains(&args.len()) { println!("'{}' does not accept {} arguments. See 'help' for more information.", cmd.name, args.len()); return; }
+    if X_COMMANDS.contains(&cmd.name.as_str()) {
+        headless::ensure(env)?;
+    }
 
     if cmd.name.eq("help") {
         println!("----- NVIDIA GPU Terminal Helper ----- b{} -----\n", BUILD_VERSION);
@@ -117,24 +178,87 @@ fn run(cmd: &HelperCommand, args: &Vec<&String>, env: &mut Environment, gpu: &mu
         println!("  gpu [gpu_id]");
         println!("        Optional. Sets the GPU to adjust settings for. Defaults to GPU 0.\n");
         println!("  fan [fan_id] [fan_speed]");
-        println!("        Sets the GPU fan at position fan_id to speed fan_speed.\n");
+        println!("        Sets the GPU fan at position fan_id to speed fan_speed, e.g. 75 or 75%. -1 returns the fan to automatic control.");
+        println!("        fan all [fan_speed] sets every fan of every GPU.\n");
         println!("  clock [speed]");
-        println!("        Sets the GPU core clock speed to speed.\n");
+        println!("        Sets the GPU core clock speed to speed, e.g. 1500 or 1500MHz. +50 or -10% are relative to the current clock. -1 removes the lock.\n");
         println!("  memory [speed]");
-        println!("        Sets the GPU memory clock speed to speed. \n");
+        println!("        Sets the GPU memory clock speed to speed. Accepts the same forms as clock. \n");
         println!("  memoryoffset [speed] (power level)");
-        println!("        Sets the GPU memory clock speed offset to speed. Overclocks or underclocks memory.\n");
+        println!("        Sets the GPU memory clock speed offset to speed. Overclocks or underclocks memory.");
+        println!("        Values in MHz (+750MHz) are doubled to the transfer rate nvidia-settings expects; bare numbers are passed as-is.\n");
         println!("  clockoffset [speed] (power level)");
-        println!("        Sets the GPU core clock clock offset speed to speed. Overclocks or underclocks core.\n");
+        println!("        Sets the GPU core clock clock offset speed to speed, e.g. +100MHz. Overclocks or underclocks core.\n");
         println!("  power [watts]");
-        println!("        Limits the GPU to only be able to pull at most the specified watts.\n");
+        println!("        Limits the GPU to only be able to pull at most the specified watts, e.g. 250W. +25W or -10% are relative to the current limit.\n");
+        println!("  ecc [on|off]");
+        println!("        Turns ECC memory on or off. The change takes effect at the next reboot.\n");
+        println!("  powermizer [adaptive|max|auto]");
+        println!("        Sets the PowerMizer mode. max keeps the clocks up, adaptive and auto let them drop when idle. 'info' shows the performance levels.\n");
+        println!("  persistence [on|off]");
+        println!("        Turns persistence mode on or off, which keeps the driver loaded and the settings in place while no program uses the GPU.\n");
+        println!("  computemode [default|exclusive_process|prohibited]");
+        println!("        Sets whether many processes, one process or none may use the GPU at a time.\n");
+        println!("  appclocks [memory,graphics] | appclocks list");
+        println!("        Sets the applications clocks, e.g. 9501,1710, which the GPU runs at under load. 'appclocks list' shows the supported pairs, -1 resets them.\n");
+        println!("  autotune --test [command] --out [file] (--only core|memory) (--core-max 300) (--memory-max 1500) (--margin 10%)");
+        println!("        Steps the offsets of the selected GPU up, running command after each step, and backs off when it fails.");
+        println!("        The stable offsets less the margin are applied and written to file as a run-file profile.\n");
+        println!("  sweep power [from]..[to] (step [watts]) --bench [command] (--score [regex]) (--out [file])");
+        println!("        Runs command at each power limit from..to of the selected GPU and reports its score per watt and the efficiency knee.");
+        println!("        The score is the first group of regex in the output. The original power limit is restored at the end.\n");
+        println!("  undervolt --target-mhz [clock] --offset [offset] | undervolt --target-mhz [clock] --test [command] (--start-offset 200) (--step 15)");
+        println!("        Locks the core clock of the selected GPU to clock and raises its clock offset, which lowers the voltage at that clock.");
+        println!("        With --test the offset is walked down until command passes. 'undervolt restore' applies the recorded pairs again.\n");
         println!("  resetall");
         println!("        Resets all settings to their defaults. \n");
+        println!("  run-file [path]");
+        println!("        Runs a script of commands, one command line per line. Use - to read the script from stdin.\n");
+        println!("  shell");
+        println!("        Opens an interactive prompt that keeps the selected GPU between lines and shows a summary after each change.\n");
+        println!("  log --out [file] (--interval 5s) (--format csv|influx) (--rotate-size 100M) (--rotate-time 1d)");
+        println!("        Appends the readings of every GPU to file on each interval until stopped, as CSV or InfluxDB line protocol.\n");
+        println!("  guard (--max-temp [celsius]) (--max-hotspot [celsius]) (--max-memory-temp [celsius]) (--max-power [watts]) (--interval 2s)");
+        println!("        Watches every GPU against at least one limit until stopped. Over a limit it raises the fans, then lowers the power limit, then resets clocks,");
+        println!("        and puts the power limit and fans back once it has cooled down. See the README for --escalate-after and --recover-after.\n");
+        println!("  info");
+        println!("        Shows the current stats of the selected GPU.\n");
+        println!("  status");
+        println!("        Shows every tunable of each GPU next to its default: locked clocks, offsets per performance level, power limit, fan control,");
+        println!("        persistence mode and applications clocks. Settings that differ from the default are marked with *.\n");
+        println!("  health");
+        println!("        Reports ECC mode, corrected and uncorrected error counts, retired pages or remapped rows, and pending reboots of every GPU.\n");
+        println!("  procs");
+        println!("        Lists the processes using each GPU with their user, command line, used memory and SM utilisation.\n");
+        println!("  kill [pid] | kill (--gpu [gpu_id]) (--user [name]) (--yes)");
+        println!("        Stops the matching GPU processes with SIGTERM after asking. --yes skips the question.\n");
+        println!("  wait-idle (--gpu [gpu_id|any]) (--max-mem 512) (--max-util 10%) (--timeout 1h) (--reserve --pid [process_id])");
+        println!("        Waits until a GPU uses at most max-mem MiB and max-util % and is not reserved, then prints its index and UUID.");
+        println!("        --reserve also reserves it for process_id, e.g. --pid $$ for the calling script.\n");
+        println!("  reserve (--pid [process_id])");
+        println!("        Reserves the selected GPU for the calling script, or process_id, until it is released or the process exits.\n");
+        println!("  release");
+        println!("        Releases the reservation of the selected GPU.\n");
+        println!("  why-slow");
+        println!("        Explains which limit is holding the selected GPU below its maximum clock, and which setting would lift it.\n");
         println!("Advanced Options (Optional):\n");
         println!("  display [display_id]");
         println!("        Sets the Xorg display value to be passed into nvidia-settings. This is automatic if none is specified.\n");
         println!("  xauth [Xauthority path]");
         println!("        Sets the Xauthority file path to be passed into nvidia-settings. This is automatic if none is specified.\n");
+        println!("  config show");
+        println!("        Shows every setting, its effective value and where it came from (default, a config file, a TGH_ variable or the command line).\n");
+        println!("  doctor (coolbits)");
+        println!("        Checks tools, driver, privileges, the X server, what each GPU supports and its Coolbits, with a hint for every problem.");
+        println!("        'doctor coolbits' shows the xorg.conf change that enables the missing Coolbits and offers to write it.\n");
+        println!("  helper");
+        println!("        Runs the privileged helper as root. Users with privilege = \"helper\" then set clocks, power limits, persistence, compute mode,");
+        println!("        applications clocks and ECC through it, within its policy.\n");
+        println!("  daemon");
+        println!("        Runs the daemon that owns the GPUs. While it runs, the setters above are sent to it, and it serves a JSON-RPC API on its socket.");
+        println!("        It also drives the fans with daemon.fan_curve and runs the profiles of daemon.schedule.\n");
+        println!("  exporter (--listen address:port)");
+        println!("        Serves the readings of every GPU and counts of failed setters at /metrics for Prometheus. Listens on 127.0.0.1:9835 by default.\n");
         println!("  debug true");
         println!("        Shows output of all executions from this program. Will be detailed.\n\n");
         println!();
@@ -144,16 +268,20 @@ fn run(cmd: &HelperCommand, args: &Vec<&String>, env: &mut Environment, gpu: &mu
         println!("https://github.com/Timbers007/teamgreenhelper")
     } else if cmd.name.eq("display") {
         env.display = args[0].clone();
+        env.config.set("display", args[0], Source::CommandLine);
+        env.x_found = true;
     } else if cmd.name.eq("xauth") {
         env.xauthority = args[0].clone();
+        env.config.set("xauthority", args[0], Source::CommandLine);
     } else if cmd.name.eq("debug") {
         match args[0].parse::<bool>() {
             Ok(n) => {
                 env.debug = n;
+                env.config.set("debug", args[0], Source::CommandLine);
                 if env.debug { println!("Successfully set debug mode to {}", env.debug) }
             },
             Err(_) => {
-                println!("{} is not true or false. {} must be set equal to true or false.", args[0], cmd.name);
+                return Err(format!("{} is not true or false. {} must be set equal to true or false.", args[0], cmd.name));
             }
         }
     } else if cmd.name.eq("gpu") {
@@ -163,222 +291,333 @@ fn run(cmd: &HelperCommand, args: &Vec<&String>, env: &mut Environment, gpu: &mu
                 if env.debug { println!("Successfully set current GPU to {}.", gpu) }
             },
             Err(_) => {
-                println!("{} is not an integer greater than or equal to 0.", args[0]);
+                return Err(format!("{} is not an integer greater than or equal to 0.", args[0]));
             }
         }
     } else if cmd.name.eq("fan") {
-        let mut fan_index = 0;
-        let mut fan_speed = 0;
+        // fan all [speed] sets every fan of every GPU at once
+        let every_fan = args.len() == 2 && args[0].eq_ignore_ascii_case("all");
 
-        if args.len() == 1 {
-            match args[0].parse::<i32>() {
-                Ok(n) => {
-                    fan_speed = n;
-                },
-                Err(_) => {
-                    println!("Failed to set fan speed. {} is not an integer greater than or equal to 0.", args[0]);
-                    return;
-                }
-            }
+        let (fan_index, fan_arg) = if every_fan {
+            (0, args[1])
         } else if args.len() == 2 {
             match args[0].parse::<usize>() {
-                Ok(n) => {
-                    fan_index = n;
-                },
+                Ok(n) => (n, args[1]),
                 Err(_) => {
-                    println!("Failed to set fan speed. {} is not an integer greater than or equal to 0.", args[0]);
-                    return;
+                    return Err(format!("Failed to set fan speed. {} is not all or an integer greater than or equal to 0.", args[0]));
                 }
             }
+        } else {
+            (0, args[0])
+        };
 
-            match args[1].parse::<i32>() {
-                Ok(n) => {
-                    fan_speed = n;
-                },
-                Err(_) => {
-                    println!("Failed to set fan speed. {} is not an integer greater than or equal to 0.", args[0]);
-                    return;
-                }
+        if is_reset_value(fan_arg) {
+            if every_fan {
+                return debug_message(env, nvidiagpu::reset_all_fan_speeds(env), "Resetting Fan Speed");
             }
+            return debug_message(env, nvidiagpu::reset_fan_speed(env, gpu), "Resetting Fan Speed");
         }
 
-        if (fan_speed < 0 || fan_speed > 100) && (fan_speed != -1) {
-            println!("Failed to set fan speed. {} is not an integer between 0 and 100.", fan_speed);
+        let fan_speed = match units::parse_value(fan_arg, Unit::Percent) {
+            Ok(Value::Absolute(n)) if (0.0..=100.0).contains(&n) => n.round() as usize,
+            Ok(_) => {
+                return Err(format!("Failed to set fan speed. {} is not a percentage between 0 and 100. Use -1 to return the fan to automatic control.", fan_arg));
+            },
+            Err(e) => {
+                return Err(format!("Failed to set fan speed. {}", e));
+            }
+        };
+
+        if let Some(min) = env.limits.min_fan_speed.filter(|min| (fan_speed as f64) < *min) {
+            return Err(format!("Failed to set fan speed. {}% is below the configured limit of {}% (limits.min_fan_speed).", fan_speed, min));
         }
 
-        if fan_speed == -1 {
-            debug_message(&env, nvidiagpu::reset_fan_speed(&env, gpu), "Resetting Fan Speed");
-        } else {
-            debug_message(&env, nvidiagpu::set_fan_speed(&env, gpu, fan_index, fan_speed as usize), "Fan Speed");
+        if every_fan {
+            return debug_message(env, nvidiagpu::set_all_fan_speeds(env, fan_speed), "Fan Speed");
         }
+        return debug_message(env, nvidiagpu::set_fan_speed(env, gpu, fan_index, fan_speed), "Fan Speed");
     } else if cmd.name.eq("memoryoffset") {
-        let memory_offset;
-        match args[0].parse::<i32>() {
-            Ok(n) => {
-                memory_offset = n;
-            },
-            Err(_) => {
-                println!("Failed to set memory offset. {} is not a valid integer.", args[0]);
-                return;
+        let memory_offset = match units::parse_offset(args[0], true) {
+            Ok(n) => n,
+            Err(e) => {
+                return Err(format!("Failed to set memory offset. {}", e));
             }
+        };
+
+        if let Some(max) = env.limits.max_memory_offset.filter(|max| memory_offset as f64 / 2.0 > *max) {
+            return Err(format!("Failed to set memory offset. {} MHz is above the configured limit of {} MHz (limits.max_memory_offset).", memory_offset as f64 / 2.0, max));
         }
 
-        debug_message(&env, nvidiagpu::set_memory_offset(&env, gpu, memory_offset), "Memory Speed Offset");
+        return debug_message(env, nvidiagpu::set_memory_offset(env, gpu, memory_offset), "Memory Speed Offset");
     } else if cmd.name.eq("clockoffset") {
-        let clock_offset;
-        match args[0].parse::<i32>() {
-            Ok(n) => {
-                clock_offset = n;
-            },
-            Err(_) => {
-                println!("Failed to set core clock offset. {} is not a valid integer.", args[0]);
-                return;
+        let clock_offset = match units::parse_offset(args[0], false) {
+            Ok(n) => n,
+            Err(e) => {
+                return Err(format!("Failed to set core clock offset. {}", e));
             }
+        };
+
+        if let Some(max) = env.limits.max_core_offset.filter(|max| clock_offset as f64 > *max) {
+            return Err(format!("Failed to set core clock offset. {} MHz is above the configured limit of {} MHz (limits.max_core_offset).", clock_offset, max));
         }
 
-        debug_message(&env, nvidiagpu::set_core_offset(&env, gpu, clock_offset), "Clock Offset");
+        return debug_message(env, nvidiagpu::set_core_offset(env, gpu, clock_offset), "Clock Offset");
     } else if cmd.name.eq("clock") {
-        let clock_speed;
-        match args[0].parse::<i32>() {
+        if is_reset_value(args[0]) {
+            return debug_message(env, nvidiagpu::reset_core(env, gpu), "Resetting Core Clock");
+        }
+
+        return match resolve_value(env, gpu, args[0], Unit::Megahertz, "clocks.current.graphics") {
+            Ok(n) if env.limits.max_core_clock.is_some_and(|max| n > max) => {
+                Err(format!("Failed to lock core clock. {} MHz is above the configured limit of {} MHz (limits.max_core_clock).", n, env.limits.max_core_clock.unwrap_or_default()))
+            },
+            Ok(n) if n > 0.0 => {
+                debug_message(env, nvidiagpu::lock_core(env, gpu, n.round() as usize), "Locked Core Clock")
+            },
             Ok(n) => {
-                clock_speed = n;
+                Err(format!("Failed to lock core clock. {} MHz is not a clock speed greater than zero. If you wish to remove the locked speed, please specify -1 as your argument.", n))
             },
-            Err(_) => {
-                println!("Failed to lock core clock. {} is not an integer greater than or equal to zero. If you wish to remove the locked speed, please specify -1 as your argument.", args[0]);
-                return;
+            Err(e) => {
+                Err(format!("Failed to lock core clock. {}", e))
             }
+        };
+    } else if cmd.name.eq("memory") {
+        if is_reset_value(args[0]) {
+            return debug_message(env, nvidiagpu::reset_memory(env, gpu), "Resetting Memory Clock");
         }
 
-        if clock_speed > 0 {
-            debug_message(&env, nvidiagpu::lock_core(&env, gpu, clock_speed as usize), "Locked Core Clock");
-        } else {
-            debug_message(&env, nvidiagpu::reset_core(&env, gpu), "Resetting Core Clock");
-        }
-    } else if cmd.name.eq("memory") {
-        let memory_speed;
-        match args[0].parse::<i32>() {
+        return match resolve_value(env, gpu, args[0], Unit::Megahertz, "clocks.current.memory") {
+            Ok(n) if n >= 0.0 => {
+                debug_message(env, nvidiagpu::lock_memory(env, gpu, n.round() as usize), "Locked Memory Speed")
+            },
             Ok(n) => {
-                memory_speed = n;
+                Err(format!("Failed to lock memory clock. {} MHz is not a clock speed greater than or equal to zero. If you wish to remove the locked speed, please specify -1 as your argument.", n))
             },
-            Err(_) => {
-                println!("Failed to lock memory clock. {} is not an integer. If you wish to remove the locked speed, please specify -1 as your argument.", args[0]);
-                return;
+            Err(e) => {
+                Err(format!("Failed to lock memory clock. {}", e))
             }
-        }
-
-        if memory_speed >= 0 {
-            debug_message(&env, nvidiagpu::lock_memory(&env, gpu, memory_speed as usize), "Locked Memory Speed");
-        } else {
-            debug_message(&env, nvidiagpu::reset_memory(&env, gpu), "Resetting Memory Clock")
-        }
+        };
     } else if cmd.name.eq("power") {
-        let power;
-        match args[0].parse::<usize>() {
+        return match resolve_value(env, gpu, args[0], Unit::Watts, "power.limit") {
+            Ok(n) if env.limits.max_power.is_some_and(|max| n > max) => {
+                Err(format!("Failed to set power limit. {} W is above the configured limit of {} W (limits.max_power).", n, env.limits.max_power.unwrap_or_default()))
+            },
+            Ok(n) if n >= 0.0 => {
+                debug_message(env, nvidiagpu::set_power_limit(env, gpu, n.round() as usize), "Power Limit")
+            },
             Ok(n) => {
-                power = n;
+                Err(format!("Failed to set power limit. {} W is not a power limit greater than or equal to 0.", n))
             },
-            Err(_) => {
-                println!("Failed to set power limit. {} is not a valid integer greater than or equal to 0.", args[0]);
-                return;
+            Err(e) => {
+                Err(format!("Failed to set power limit. {}", e))
+            }
+        };
+    } else if cmd.name.eq("powermizer") {
+        return match nvidiagpu::POWERMIZER_MODES.iter().find(|(name, _, _)| args[0].eq_ignore_ascii_case(name)) {
+            Some((_, mode, _)) => debug_message(env, nvidiagpu::set_powermizer_mode(env, gpu, *mode), "PowerMizer Mode"),
+            None => Err(format!("Failed to set PowerMizer mode. {} is not adaptive, max or auto.", args[0])),
+        };
+    } else if cmd.name.eq("persistence") {
+        let enabled = match args[0].to_lowercase().as_str() {
+            "on" | "1" | "enabled" => true,
+            "off" | "0" | "disabled" => false,
+            _ => {
+                return Err(format!("Failed to set persistence mode. {} is not on or off.", args[0]));
+            }
+        };
+
+        return debug_message(env, nvidiagpu::set_persistence_mode(env, gpu, enabled), "Persistence Mode");
+    } else if cmd.name.eq("computemode") {
+        let mode = match args[0].to_lowercase().replace('-', "_").as_str() {
+            "default" | "0" => "DEFAULT",
+            "exclusive_process" | "exclusive" | "3" => "EXCLUSIVE_PROCESS",
+            "prohibited" | "2" => "PROHIBITED",
+            _ => {
+                return Err(format!("Failed to set compute mode. {} is not default, exclusive_process or prohibited.", args[0]));
             }
+        };
+
+        return debug_message(env, nvidiagpu::set_compute_mode(env, gpu, mode), "Compute Mode");
+    } else if cmd.name.eq("appclocks") {
+        if args[0].eq_ignore_ascii_case("list") {
+            return nvidiagpu::print_supported_clocks(env, gpu);
+        }
+        if is_reset_value(args[0]) {
+            return debug_message(env, nvidiagpu::reset_applications_clocks(env, gpu), "Resetting Applications Clocks");
         }
 
-        debug_message(&env, nvidiagpu::set_power_limit(&env, gpu, power), "Power Limit");
+        let clock = |v: &str| v.trim().trim_end_matches("MHz").parse::<usize>().ok();
+        return match args[0].split_once(',').map(|(m, g)| (clock(m), clock(g))) {
+            Some((_, Some(g))) if env.limits.max_core_clock.is_some_and(|max| g as f64 > max) => {
+                Err(format!("Failed to set applications clocks. {} MHz is above the configured limit of {} MHz (limits.max_core_clock).", g, env.limits.max_core_clock.unwrap_or_default()))
+            },
+            Some((Some(m), Some(g))) => {
+                debug_message(env, nvidiagpu::set_applications_clocks(env, gpu, m, g), "Applications Clocks")
+            },
+            _ => {
+                Err(format!("Failed to set applications clocks. {} is not a memory,graphics pair of clocks in MHz, e.g. 9501,1710. See 'appclocks list'.", args[0]))
+            }
+        };
+    } else if cmd.name.eq("ecc") {
+        return health::set_ecc(env, gpu, args[0]);
+    } else if cmd.name.eq("health") {
+        return health::run(env);
     } else if cmd.name.eq("reset") {
-        debug_message(&env, nvidiagpu::reset_core(&env, gpu), "Resetting Core Clock");
-        debug_message(&env, nvidiagpu::reset_memory(&env, gpu), "Resetting Memory Clock");
-        debug_message(&env, nvidiagpu::set_core_offset(&env, gpu, 0), "Clock Offset");
-        debug_message(&env, nvidiagpu::set_memory_offset(&env, gpu, 0), "Memory Offset");
-        debug_message(&env, nvidiagpu::reset_fan_speed(&env, gpu), "Fan Speed");
-    }
-}
+        // Every step is attempted even if an earlier one fails, the first failure is reported
+        let results = [
+            debug_message(env, nvidiagpu::reset_core(env, gpu), "Resetting Core Clock"),
+            debug_message(env, nvidiagpu::reset_memory(env, gpu), "Resetting Memory Clock"),
+            debug_message(env, nvidiagpu::set_core_offset(env, gpu, 0), "Clock Offset"),
+            debug_message(env, nvidiagpu::set_memory_offset(env, gpu, 0), "Memory Offset"),
+            debug_message(env, nvidiagpu::reset_fan_speed(env, gpu), "Fan Speed"),
+            reset_applications_clocks(env, gpu),
+            restore_original(env, gpu, "computemode", &nvidiagpu::COMPUTE_MODES, nvidiagpu::set_compute_mode, "Compute Mode"),
+            restore_original(env, gpu, "persistence", &["Enabled", "Disabled"], |env, gpu, mode| nvidiagpu::set_persistence_mode(env, gpu, mode == "Enabled"),
+                             "Persistence Mode"),
+        ];
+
+        // The undervolt pair is gone with the lock and offset, so restore must not bring it back. The
+        // record is root's, so without the daemon this can fail after every setting was reset
+        let forgotten = if env.backend == Backend::DryRun { Ok(()) } else { undervolt::forget(env, gpu) };
+
+        return results.into_iter().chain([forgotten]).collect();
+    } else if cmd.name.eq("run-file") {
+        return script::run_file(args[0], env, gpu);
+    } else if cmd.name.eq("shell") {
+        return shell::run(env, gpu);
+    } else if cmd.name.eq("status") {
+        return status::run(env);
+    } else if cmd.name.eq("procs") {
+        return procs::run(env);
+    } else if cmd.name.eq("kill") {
+        return procs::kill(env, args);
+    } else if cmd.name.eq("wait-idle") {
+        return reservation::wait_idle(env, args);
+    } else if cmd.name.eq("reserve") {
+        return reservation::reserve(env, gpu, args);
+    } else if cmd.name.eq("release") {
+        return reservation::release(env, gpu);
+    } else if cmd.name.eq("autotune") {
+        return autotune::run(env, gpu, args);
+    } else if cmd.name.eq("sweep") {
+        return sweep::run(env, gpu, args);
+    } else if cmd.name.eq("undervolt") {
+        return undervolt::run(env, gpu, args);
+    } else if cmd.name.eq("why-slow") {
+        return throttle::why_slow(env, gpu);
+    } else if cmd.name.eq("info") {
+        nvidiagpu::print_query_info(env, gpu);
+    } else if cmd.name.eq("config") {
+        if !args.is_empty() && args[0] != "show" {
+            return Err(format!("'{}' is not a config action. Try 'config show'.", args[0]));
+        }
 
-fn main() {
-    let mut env = Environment::default();
+        config::show(env);
+    } else if cmd.name.eq("doctor") {
+        return doctor::run(env, args);
+    } else if cmd.name.eq("helper") {
+        return helper::serve(env);
+    } else if cmd.name.eq("daemon") {
+        return daemon::serve(env, *gpu);
+    } else if cmd.name.eq("exporter") {
+        return exporter::run(env, args);
+    } else if cmd.name.eq("log") {
+        return logging::run(env, args);
+    } else if cmd.name.eq("guard") {
+        return guard::run(env, args);
+    }
 
-    let args: Vec<String> = env::args().collect();
+    Ok(())
+}
 
-    if args.len() == 1 {
-        nvidiagpu::print_query_info(&env, &0);
-    } else if args.len() == 2 {
-        match args[1].parse::<usize>() {
-            Ok(n) => {
-                nvidiagpu::print_query_info(&env, &n);
-                return;
-            },
-            Err(_) => {}
-        }
-    }
+/*
+ * Runs a command line. Each error is handed to report as soon as its command fails, so it shows up
+ * next to that command's output. Returns the number of errors.
+ */
+pub fn run_tokens(tokens: &[String], commands: &HashMap<String, HelperCommand>, env: &mut Environment, gpu: &mut usize, report: &mut dyn FnMut(String)) -> usize {
+    let mut errors = 0;
 
-    let mut commands = HashMap::new();
-    commands.insert(String::from("help"), new_command(String::from("help"), vec![String::from("--help"), String::from("-h")], vec![0]));
-    commands.insert(String::from("version"), new_command(String::from("version"), vec![String::from("--version"), String::from("-v"), String::from("v")], vec![0]));
-    commands.insert(String::from("xauth"), new_command(String::from("xauth"), vec![String::from("xauthority"), String::from("xa"), String::from("--xauth")], vec![1]));
-    commands.insert(String::from("debug"), new_command(String::from("debug"), vec![String::from("debug"), String::from("--debug")], vec![1]));
-    commands.insert(String::from("display"), new_command(String::from("display"), vec![String::from("dp"), String::from("--display")], vec![1]));
-    commands.insert(String::from("gpu"), new_command(String::from("gpu"), vec![], vec![1]));
-    commands.insert(String::from("fan"), new_command(String::from("fan"), vec![], vec![1, 2]));
-    commands.insert(String::from("memoryoffset"), new_command(String::from("memoryoffset"), vec![String::from("moc"), String::from("--memoc"), String::from("--memory-offset")], vec![1]));
-    commands.insert(String::from("clockoffset"), new_command(String::from("clockoffset"), vec![String::from("--clockoc"), String::from("--clock-offset")], vec![1]));
-    commands.insert(String::from("clock"), new_command(String::from("clock"), vec![String::from("lgc"), String::from("--clock")], vec![1]));
-    commands.insert(String::from("memory"), new_command(String::from("memory"), vec![String::from("lmc"), String::from("--memory")], vec![1]));
-    commands.insert(String::from("power"), new_command(String::from("power"), vec![String::from("pl"), String::from("--power")], vec![1]));
-    commands.insert(String::from("reset"), new_command(String::from("reset"), vec![String::from("r"), String::from("--reset")], vec![0]));
-
-    get_xauthority(&mut env);
-    get_display(&mut env);
-
-    let mut gpu_index = 0;
-
-    let mut index: usize = 1;
+    let mut index: usize = 0;
     let mut finding_argument = true;
-    let mut cmd = commands.get("help").unwrap();
+    let mut cmd: Option<&HelperCommand> = None;
     let mut arguments: Vec<&String> = Vec::new();
 
     let mut args_max: &usize = &usize::default();
     let mut args_count: usize = 0;
 
-    while index < args.len() {
+    while index < tokens.len() {
         if finding_argument {
-            match cmd_exists(&args[index], &commands) {
+            match cmd_exists(&tokens[index], commands) {
                 Some(x) => {
-                    cmd = x;
+                    cmd = Some(x);
                     finding_argument = false;
-                    args_max = cmd.args.iter().max().unwrap();
+                    args_max = x.args.iter().max().unwrap();
                 },
                 None => {
-                    println!("'{}' was not recognized as a valid argument. Try using 'help' for more information.", &args[index]);
+                    report(format!("'{}' was not recognized as a valid argument. Try using 'help' for more information.", &tokens[index]));
+                    errors += 1;
                 }
             }
-        } else {
-            if args_count >= *args_max {
-                run(cmd, &arguments, &mut env, &mut gpu_index);
-
-                finding_argument = true;
-                index -= 1;
-                args_count = 0;
-                arguments.clear();
-            } else {
-                match cmd_exists(&args[index], &commands) {
-                    Some(_) => {
-                        run(cmd, &arguments, &mut env, &mut gpu_index);
-
-                        finding_argument = true;
-                        index -= 1;
-                        args_count = 0;
-                        arguments.clear();
-                    },
-                    None => {
-                        arguments.push(&args[index]);
-                    }
-                }
+        } else if let Some(current) = cmd.filter(|c| args_count >= *args_max
+            || (cmd_exists(&tokens[index], commands).is_some() && !(arguments.is_empty() && SETTING_COMMANDS.contains(&c.name.as_str())))) {
+            if let Err(e) = run(current, &arguments, env, gpu) {
+                report(e);
+                errors += 1;
             }
+
+            finding_argument = true;
+            index -= 1;
+            args_count = 0;
+            arguments.clear();
+        } else {
+            arguments.push(&tokens[index]);
         }
 
         index += 1;
     }
 
-    if !finding_argument {
-        run(cmd, &arguments, &mut env, &mut gpu_index);
+    if let (false, Some(current)) = (finding_argument, cmd) {
+        if let Err(e) = run(current, &arguments, env, gpu) {
+            report(e);
+            errors += 1;
+        }
+    }
+
+    errors
+}
+
+fn main() {
+    let mut env = Environment::default();
+
+    for warning in config::load().apply(&mut env) {
+        println!("{}", warning);
+    }
+
+    let default_gpu = env.config.default_gpu().unwrap_or(0);
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() == 1 {
+        nvidiagpu::print_query_info(&env, &default_gpu);
+    } else if args.len() == 2 {
+        if let Ok(n) = args[1].parse::<usize>() {
+            nvidiagpu::print_query_info(&env, &n);
+            return;
+        }
+    }
+
+    let commands = default_commands();
+
+    // Discovery only fills in what no config file, variable or argument has set
+    xserver::discover(&mut env);
+
+    let mut gpu_index = default_gpu;
+
+    let errors = run_tokens(&args[1..], &commands, &mut env, &mut gpu_index, &mut |e| println!("{}", e));
+
+    // exit skips destructors, so stop a headless X server first
+    drop(env);
+
+    if errors > 0 {
+        std::process::exit(1);
     }
 }
@@ -1,42 +1,308 @@
-use std::io;
+use std::{error, fmt, io};
 use std::process::{Output};
 use io::Result;
-use crate::{Environment, execute};
+use crate::executor::{execute, execute_as_root, execute_change, Backend, Environment, OutputFormat};
+use crate::state::{self, Lock};
+use crate::{json, throttle};
+
+/*
+ * A setter whose command succeeded but whose value did not stick. nvidia-settings in particular can
+ * exit 0 without applying anything, so every setter reads the attribute back. The setters return it
+ * inside their io::Error, debug_message reports it apart from a failed command.
+ */
+#[derive(Debug)]
+pub struct NotApplied {
+    pub setting: &'static str,
+    pub requested: String,
+    pub actual: String,
+}
+
+impl fmt::Display for NotApplied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} was requested but it reads back as {}", self.setting, self.requested, self.actual)
+    }
+}
+
+impl error::Error for NotApplied {}
+
+// A locked clock can settle a little above the requested clock, at the nearest step the GPU supports
+const CLOCK_TOLERANCE: f64 = 30.0;
+
+// nvidia-smi reports power limits with decimals and some GPUs round the requested limit
+const POWER_TOLERANCE: f64 = 1.0;
+
+/*
+ * Reads back what a setter changed once its command succeeded and fails with NotApplied if accept
+ * rejects any of the readings. A value that cannot be read back is not an error, and a dry run
+ * changes nothing to read.
+ */
+fn verified(env: &Environment, out: Result<Output>, setting: &'static str, requested: f64, unit: &'static str,
+            read: impl FnOnce() -> Vec<f64>, accept: impl Fn(f64) -> bool) -> Result<Output> {
+    let out = out?;
+
+    if env.backend == Backend::DryRun || !out.status.success() {
+        return Ok(out);
+    }
+
+    match read().into_iter().find(|r| !accept(*r)) {
+        Some(actual) => Err(io::Error::other(NotApplied { setting, requested: format!("{}{}", requested, unit), actual: format!("{}{}", actual, unit) })),
+        None => Ok(out),
+    }
+}
+
+// The same for settings that read back as text, e.g. Enabled, compared without case
+fn verified_text(env: &Environment, out: Result<Output>, setting: &'static str, requested: &str, read: impl FnOnce() -> Option<String>) -> Result<Output> {
+    let out = out?;
+
+    if env.backend == Backend::DryRun || !out.status.success() {
+        return Ok(out);
+    }
+
+    match read() {
+        Some(actual) if !actual.eq_ignore_ascii_case(requested) => {
+            Err(io::Error::other(NotApplied { setting, requested: requested.to_string(), actual }))
+        },
+        _ => Ok(out),
+    }
+}
+
+// A text field without its unit, None if nvidia-smi fails or reports it as N/A
+pub fn query_gpu_text(env: &Environment, gpu: &usize, field: &str) -> Option<String> {
+    let output = execute(env, &format!("nvidia-smi -i {} --query-gpu={} --format=csv,noheader,nounits", gpu, field)).ok()?;
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    if !output.status.success() || value.is_empty() || value.contains("N/A") { None } else { Some(value) }
+}
+
+// The text nvidia-settings prints for an attribute, of target (e.g. gpu:0 or fan:1) or of every target when it is empty
+pub fn query_setting_text(env: &Environment, target: &str, attribute: &str) -> String {
+    let target = if target.is_empty() { String::new() } else { format!("[{}]/", target) };
+
+    match execute(env, &format!("DISPLAY={} XAUTHORITY={} nvidia-settings -t -q {}{}", env.display, env.xauthority, target, attribute)) {
+        Ok(o) if o.status.success() => String::from_utf8_lossy(&o.stdout).into_owned(),
+        _ => String::new(),
+    }
+}
+
+// One value per target, those that do not parse are left out
+pub fn query_setting(env: &Environment, target: &str, attribute: &str) -> Vec<f64> {
+    query_setting_text(env, target, attribute).lines().filter_map(|l| l.trim().parse::<f64>().ok()).collect()
+}
+
+// Keeps the record of locked clocks that status shows, as the driver cannot be asked for them
+fn recorded(env: &Environment, out: Result<Output>, gpu: usize, lock: Lock, mhz: Option<usize>) -> Result<Output> {
+    let out = out?;
+
+    if env.backend != Backend::DryRun && out.status.success() {
+        state::record_lock(env, gpu, lock, mhz);
+    }
+
+    Ok(out)
+}
 
 pub fn set_memory_offset(env: &Environment, gpu: &mut usize, memory_offset: i32) -> Result<Output> {
-    execute(env, &format!("sudo DISPLAY={} XAUTHORITY={} nvidia-settings -a [gpu:{}]/GPUMemoryTransferRateOffsetAllPerformanceLevels={}", env.display, env.xauthority, gpu, memory_offset))
+    let out = execute_change(env, &format!("DISPLAY={} XAUTHORITY={} nvidia-settings -a [gpu:{}]/GPUMemoryTransferRateOffsetAllPerformanceLevels={}", env.display, env.xauthority, gpu, memory_offset));
+    verified(env, out, "a memory offset of", memory_offset as f64, "", || query_setting(env, &format!("gpu:{}", gpu), "GPUMemoryTransferRateOffsetAllPerformanceLevels"),
+             |r| r == memory_offset as f64)
 }
 
 pub fn set_core_offset(env: &Environment, gpu: &mut usize, clock_offset: i32) -> Result<Output> {
-    execute(env, &format!("sudo DISPLAY={} XAUTHORITY={} nvidia-settings -a [gpu:{}]/GPUGraphicsClockOffsetAllPerformanceLevels={}", env.display, env.xauthority, gpu, clock_offset))
+    let out = execute_change(env, &format!("DISPLAY={} XAUTHORITY={} nvidia-settings -a [gpu:{}]/GPUGraphicsClockOffsetAllPerformanceLevels={}", env.display, env.xauthority, gpu, clock_offset));
+    verified(env, out, "a clock offset of", clock_offset as f64, " MHz", || query_setting(env, &format!("gpu:{}", gpu), "GPUGraphicsClockOffsetAllPerformanceLevels"),
+             |r| r == clock_offset as f64)
 }
 
+// There is no query for a locked clock, but the current clock must not be above it
 pub fn lock_core(env: &Environment, gpu: &mut usize, clock_speed: usize) -> Result<Output> {
-    execute(env, &format!("sudo nvidia-smi -i {} -lgc {}", gpu, clock_speed))
+    let out = execute_as_root(env, &format!("nvidia-smi -i {} -lgc {}", gpu, clock_speed));
+    let out = verified(env, out, "a core clock of at most", clock_speed as f64, " MHz", || query_gpu_number(env, gpu, "clocks.current.graphics").into_iter().collect(),
+                       |r| r <= clock_speed as f64 + CLOCK_TOLERANCE);
+    recorded(env, out, *gpu, Lock::Core, Some(clock_speed))
 }
 
 pub fn lock_memory(env: &Environment, gpu: &mut usize, memory_speed: usize) -> Result<Output> {
-    execute(env, &format!("sudo nvidia-smi -i {} -lmc {}", gpu, memory_speed))
+    let out = execute_as_root(env, &format!("nvidia-smi -i {} -lmc {}", gpu, memory_speed));
+    let out = verified(env, out, "a memory clock of at most", memory_speed as f64, " MHz", || query_gpu_number(env, gpu, "clocks.current.memory").into_iter().collect(),
+                       |r| r <= memory_speed as f64 + CLOCK_TOLERANCE);
+    recorded(env, out, *gpu, Lock::Memory, Some(memory_speed))
 }
 
 pub fn set_power_limit(env: &Environment, gpu: &mut usize, power: usize) -> Result<Output> {
-    execute(env, &format!("sudo nvidia-smi -i {} -pl {}", gpu, power))
+    let out = execute_as_root(env, &format!("nvidia-smi -i {} -pl {}", gpu, power));
+    verified(env, out, "a power limit of", power as f64, " W", || query_gpu_number(env, gpu, "power.limit").into_iter().collect(),
+             |r| (r - power as f64).abs() <= POWER_TOLERANCE)
 }
 
 pub fn set_fan_speed(env: &Environment, gpu: &mut usize, fan_index: usize, fan_speed: usize) -> Result<Output> {
-    execute(env, &format!("sudo DISPLAY={} XAUTHORITY={} nvidia-settings -a [gpu:{}]/GPUFanControlState=1 -a [fan:{}]/GPUTargetFanSpeed={}", env.display, env.xauthority, gpu, fan_index, fan_speed))
+    let out = execute_change(env, &format!("DISPLAY={} XAUTHORITY={} nvidia-settings -a [gpu:{}]/GPUFanControlState=1 -a [fan:{}]/GPUTargetFanSpeed={}", env.display, env.xauthority, gpu, fan_index, fan_speed));
+    verified(env, out, "a fan speed of", fan_speed as f64, "%", || query_setting(env, &format!("fan:{}", fan_index), "GPUTargetFanSpeed"),
+             |r| r == fan_speed as f64)
 }
 
+// Fan control state 0 hands the fans back to the driver
 pub fn reset_fan_speed(env: &Environment, gpu: &mut usize) -> Result<Output> {
-    execute(env, &format!("sudo DISPLAY={} XAUTHORITY={} nvidia-settings -a [gpu:{}]/GPUFanControlState=0", env.display, env.xauthority, gpu))
+    let out = execute_change(env, &format!("DISPLAY={} XAUTHORITY={} nvidia-settings -a [gpu:{}]/GPUFanControlState=0", env.display, env.xauthority, gpu));
+    verified(env, out, "fan control state", 0.0, "", || query_setting(env, &format!("gpu:{}", gpu), "GPUFanControlState"), |r| r == 0.0)
+}
+
+// Without a target nvidia-settings applies an attribute to every GPU and fan it knows
+pub fn set_all_fan_speeds(env: &Environment, fan_speed: usize) -> Result<Output> {
+    let out = execute_change(env, &format!("DISPLAY={} XAUTHORITY={} nvidia-settings -a GPUFanControlState=1 -a GPUTargetFanSpeed={}", env.display, env.xauthority, fan_speed));
+    verified(env, out, "a fan speed of", fan_speed as f64, "%", || query_setting(env, "", "GPUTargetFanSpeed"), |r| r == fan_speed as f64)
+}
+
+pub fn reset_all_fan_speeds(env: &Environment) -> Result<Output> {
+    let out = execute_change(env, &format!("DISPLAY={} XAUTHORITY={} nvidia-settings -a GPUFanControlState=0", env.display, env.xauthority));
+    verified(env, out, "fan control state", 0.0, "", || query_setting(env, "", "GPUFanControlState"), |r| r == 0.0)
+}
+
+// Keeps what setting was before the first change that took effect, so reset can put it back
+fn remembered(env: &Environment, out: Result<Output>, gpu: usize, setting: &str, before: Option<String>) -> Result<Output> {
+    let out = out?;
+
+    if env.backend != Backend::DryRun && out.status.success() && before.is_some() {
+        state::record_original(env, gpu, setting, before);
+    }
+
+    Ok(out)
+}
+
+pub fn set_persistence_mode(env: &Environment, gpu: &mut usize, enabled: bool) -> Result<Output> {
+    let before = query_gpu_text(env, gpu, "persistence_mode");
+    let out = execute_as_root(env, &format!("nvidia-smi -i {} -pm {}", gpu, if enabled { 1 } else { 0 }));
+    let out = verified_text(env, out, "persistence mode", if enabled { "Enabled" } else { "Disabled" }, || query_gpu_text(env, gpu, "persistence_mode"));
+    remembered(env, out, *gpu, "persistence", before)
+}
+
+// The compute mode names nvidia-smi -c takes, which read back in mixed case
+pub const COMPUTE_MODES: [&str; 3] = ["DEFAULT", "EXCLUSIVE_PROCESS", "PROHIBITED"];
+
+// mode is one of COMPUTE_MODES, anything else is refused as it would end up in a root shell
+pub fn set_compute_mode(env: &Environment, gpu: &mut usize, mode: &str) -> Result<Output> {
+    if !COMPUTE_MODES.contains(&mode) {
+        return Err(io::Error::other(format!("'{}' is not one of the compute modes {}", mode, COMPUTE_MODES.join(", "))));
+    }
+
+    let before = query_gpu_text(env, gpu, "compute_mode");
+    let out = execute_as_root(env, &format!("nvidia-smi -i {} -c {}", gpu, mode));
+    let out = verified_text(env, out, "compute mode", mode, || query_gpu_text(env, gpu, "compute_mode"));
+    remembered(env, out, *gpu, "computemode", before)
+}
+
+// The driver applies a new ECC mode at the next reboot, so the pending mode is what is read back
+pub fn set_ecc_mode(env: &Environment, gpu: &mut usize, enabled: bool) -> Result<Output> {
+    let out = execute_as_root(env, &format!("nvidia-smi -i {} -e {}", gpu, if enabled { 1 } else { 0 }));
+    verified_text(env, out, "pending ECC mode", if enabled { "Enabled" } else { "Disabled" }, || query_gpu_text(env, gpu, "ecc.mode.pending"))
+}
+
+// nvidia-smi refuses pairs the GPU does not support, see query_supported_clocks
+pub fn set_applications_clocks(env: &Environment, gpu: &mut usize, memory: usize, graphics: usize) -> Result<Output> {
+    let out = execute_as_root(env, &format!("nvidia-smi -i {} -ac {},{}", gpu, memory, graphics));
+    verified_text(env, out, "applications clocks of", &format!("{},{} MHz", memory, graphics),
+                  || query_gpu_text(env, gpu, "clocks.applications.memory,clocks.applications.graphics").map(|v| format!("{} MHz", v.replace(' ', ""))))
+}
+
+pub fn reset_applications_clocks(env: &Environment, gpu: &mut usize) -> Result<Output> {
+    execute_as_root(env, &format!("nvidia-smi -i {} -rac", gpu))
+}
+
+// GPUPowerMizerMode values by the names powermizer takes, with how nvidia-settings describes them
+pub const POWERMIZER_MODES: [(&str, i32, &str); 3] = [
+    ("adaptive", 0, "Adaptive"),
+    ("max", 1, "Prefer Maximum Performance"),
+    ("auto", 2, "Auto"),
+];
+
+pub fn set_powermizer_mode(env: &Environment, gpu: &mut usize, mode: i32) -> Result<Output> {
+    let out = execute_change(env, &format!("DISPLAY={} XAUTHORITY={} nvidia-settings -a [gpu:{}]/GPUPowerMizerMode={}", env.display, env.xauthority, gpu, mode));
+    verified(env, out, "PowerMizer mode", mode as f64, "", || query_setting(env, &format!("gpu:{}", gpu), "GPUPowerMizerMode"), |r| r == mode as f64)
+}
+
+// One row of the clock table nvidia-settings keeps per performance level, in MHz
+#[derive(Debug, PartialEq)]
+pub struct PerfLevel {
+    pub level: usize,
+    pub core_min: usize,
+    pub core_max: usize,
+    pub memory_min: usize,
+    pub memory_max: usize,
+}
+
+/*
+ * Parses GPUPerfModes, one level per ; separated part:
+ *
+ *   perf=0, nvclock=210, nvclockmin=210, nvclockmax=405, nvclockeditable=0, memclock=405, memclockmin=405, memclockmax=405, ... ;
+ *   perf=1, nvclock=210, nvclockmin=210, nvclockmax=1905, ...
+ */
+pub fn parse_perf_modes(text: &str) -> Vec<PerfLevel> {
+    text.split(';')
+        .filter_map(|part| {
+            let value = |key: &str| part.split(',')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(k, _)| k.trim() == key)
+                .and_then(|(_, v)| v.trim().parse::<usize>().ok());
+
+            Some(PerfLevel {
+                level: value("perf")?,
+                core_min: value("nvclockmin")?,
+                core_max: value("nvclockmax")?,
+                memory_min: value("memclockmin")?,
+                memory_max: value("memclockmax")?,
+            })
+        })
+        .collect()
+}
+
+pub struct PowerMizer {
+    // None if the mode is not one of POWERMIZER_MODES
+    pub mode: Option<&'static str>,
+    pub level: Option<usize>,
+    pub levels: Vec<PerfLevel>,
+}
+
+// None without an X server, or on GPUs nvidia-settings does not report PowerMizer for
+pub fn query_powermizer(env: &Environment, gpu: &usize) -> Option<PowerMizer> {
+    let target = format!("gpu:{}", gpu);
+    let mode = *query_setting(env, &target, "GPUPowerMizerMode").first()?;
+
+    Some(PowerMizer {
+        mode: POWERMIZER_MODES.iter().find(|(_, value, _)| *value as f64 == mode).map(|(_, _, label)| *label),
+        level: query_setting(env, &target, "GPUCurrentPerfLevel").first().map(|l| *l as usize),
+        levels: parse_perf_modes(&query_setting_text(env, &target, "GPUPerfModes")),
+    })
+}
+
+// The memory and graphics clock pairs the GPU supports as applications clocks, fastest memory clock first
+pub fn query_supported_clocks(env: &Environment, gpu: &usize) -> std::result::Result<Vec<(usize, Vec<usize>)>, String> {
+    let output = execute(env, &format!("nvidia-smi -i {} --query-supported-clocks=memory,graphics --format=csv,noheader,nounits", gpu))
+        .map_err(|e| format!("nvidia-smi could not be run. Error: {}", e))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stdout).trim().to_string());
+    }
+
+    let mut clocks: Vec<(usize, Vec<usize>)> = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if let Some((memory, graphics)) = line.split_once(',') {
+            if let (Ok(memory), Ok(graphics)) = (memory.trim().parse::<usize>(), graphics.trim().parse::<usize>()) {
+                match clocks.iter_mut().find(|(m, _)| *m == memory) {
+                    Some((_, list)) => list.push(graphics),
+                    None => clocks.push((memory, vec![graphics])),
+                }
+            }
+        }
+    }
+
+    Ok(clocks)
 }
 
+// Any clock is valid once a lock is removed, so the resets have nothing to read back
 pub fn reset_core(env: &Environment, gpu: &mut usize) -> Result<Output> {
-    execute(env, &format!("sudo nvidia-smi -i {} -rgc", gpu))
+    recorded(env, execute_as_root(env, &format!("nvidia-smi -i {} -rgc", gpu)), *gpu, Lock::Core, None)
 }
 
 pub fn reset_memory(env: &Environment, gpu: &mut usize) -> Result<Output> {
-    execute(env, &format!("sudo nvidia-smi -i {} -rmc", gpu))
+    recorded(env, execute_as_root(env, &format!("nvidia-smi -i {} -rmc", gpu)), *gpu, Lock::Memory, None)
 }
 
 pub fn query_gpu_field<'a>(env: &Environment, gpu: &'a usize, field: &'a str) -> String {
@@ -58,10 +324,143 @@ pub fn query_gpu_field<'a>(env: &Environment, gpu: &'a usize, field: &'a str) ->
     }
 }
 
+/*
+ * Queries a single numeric field without its unit, e.g. power.limit or clocks.current.graphics.
+ * Returns None if nvidia-smi fails or reports the field as N/A.
+ */
+pub fn query_gpu_number(env: &Environment, gpu: &usize, field: &str) -> Option<f64> {
+    let output = execute(env, &format!("nvidia-smi -i {} --query-gpu={} --format=csv,noheader,nounits", gpu, field)).ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8_lossy(&output.stdout).trim().parse::<f64>().ok()
+}
+
+/*
+ * The hotspot temperature is not a --query-gpu field, but drivers that know it print it in the
+ * temperature section of nvidia-smi -q, e.g. "GPU Hotspot Temp : 92 C". None if it is not there.
+ */
+pub fn query_hotspot(env: &Environment, gpu: &usize) -> Option<f64> {
+    let output = execute(env, &format!("nvidia-smi -i {} -q -d TEMPERATURE", gpu)).ok()?;
+
+    String::from_utf8_lossy(&output.stdout).lines()
+        .filter_map(|l| l.split_once(':'))
+        .find(|(label, _)| label.to_lowercase().replace(' ', "").contains("hotspot"))
+        .and_then(|(_, value)| value.trim().trim_end_matches('C').trim().parse::<f64>().ok())
+}
+
+/*
+ * Queries fields of every GPU in one call, without units. Each row is the GPU index followed by the
+ * fields, in the order they were asked for.
+ */
+pub fn query_all_gpus(env: &Environment, fields: &str) -> std::result::Result<Vec<Vec<String>>, String> {
+    let output = execute(env, &format!("nvidia-smi --query-gpu=index,{} --format=csv,noheader,nounits", fields))
+        .map_err(|e| format!("nvidia-smi could not be run. Error: {}", e))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stdout).trim().to_string());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).lines()
+        .map(|row| row.split(',').map(|v| v.trim().to_string()).collect())
+        .collect())
+}
+
+const SNAPSHOT_FIELDS: &str = "uuid,name,driver_version,clocks.current.graphics,clocks.current.memory,temperature.gpu,temperature.memory,power.draw,\
+    enforced.power.limit,fan.speed,utilization.gpu,memory.used,memory.total,pcie.link.gen.current,pcie.link.width.current";
+
+// Typed readings of one GPU, taken with a single nvidia-smi call for every GPU. N/A readings are None.
+#[derive(Clone, Debug, Default)]
+pub struct GpuSnapshot {
+    pub index: usize,
+    pub uuid: String,
+    pub name: String,
+    pub driver_version: String,
+    pub core_clock: Option<f64>,
+    pub memory_clock: Option<f64>,
+    pub temperature: Option<f64>,
+    pub memory_temperature: Option<f64>,
+    pub power_draw: Option<f64>,
+    pub power_limit: Option<f64>,
+    pub fan_speed: Option<f64>,
+    pub utilization: Option<f64>,
+    pub memory_used: Option<f64>,
+    pub memory_total: Option<f64>,
+    pub pcie_generation: Option<f64>,
+    pub pcie_width: Option<f64>,
+}
+
+impl GpuSnapshot {
+    // Every reading, named with its unit and described for the exporter's HELP lines. New readings
+    // go at the end, as this is also the column order of log's CSV files.
+    pub fn readings(&self) -> [(&'static str, &'static str, Option<f64>); 12] {
+        [
+            ("core_clock_mhz", "Current graphics clock in MHz.", self.core_clock),
+            ("memory_clock_mhz", "Current memory clock in MHz.", self.memory_clock),
+            ("temperature_celsius", "Core temperature in degrees Celsius.", self.temperature),
+            ("power_draw_watts", "Power draw in watts.", self.power_draw),
+            ("power_limit_watts", "Enforced power limit in watts.", self.power_limit),
+            ("fan_speed_percent", "Fan speed in percent of its maximum.", self.fan_speed),
+            ("utilization_percent", "Share of the last sample period a kernel was running, in percent.", self.utilization),
+            ("memory_used_mib", "Used memory in MiB.", self.memory_used),
+            ("memory_total_mib", "Total memory in MiB.", self.memory_total),
+            ("pcie_link_generation", "Current PCIe link generation.", self.pcie_generation),
+            ("pcie_link_width", "Current PCIe link width.", self.pcie_width),
+            ("memory_temperature_celsius", "Memory temperature in degrees Celsius, only reported by some GPUs.", self.memory_temperature),
+        ]
+    }
+}
+
+pub fn query_snapshots(env: &Environment) -> std::result::Result<Vec<GpuSnapshot>, String> {
+    let rows = query_all_gpus(env, SNAPSHOT_FIELDS)?;
+
+    Ok(rows.iter().filter(|row| row.len() == 16).map(|row| {
+        let number = |i: usize| row[i].parse::<f64>().ok();
+
+        GpuSnapshot {
+            index: row[0].parse::<usize>().unwrap_or_default(),
+            uuid: row[1].clone(),
+            name: row[2].clone(),
+            driver_version: row[3].clone(),
+            core_clock: number(4),
+            memory_clock: number(5),
+            temperature: number(6),
+            memory_temperature: number(7),
+            power_draw: number(8),
+            power_limit: number(9),
+            fan_speed: number(10),
+            utilization: number(11),
+            memory_used: number(12),
+            memory_total: number(13),
+            pcie_generation: number(14),
+            pcie_width: number(15),
+        }
+    }).collect())
+}
+
+pub const QUERY_INFO_FIELDS: &str = "name,clocks.current.graphics,clocks.current.memory,temperature.gpu,power.draw,\
+    fan.speed,memory.used,memory.total,enforced.power.limit,driver_version,pcie.link.gen.current,pcie.link.width.current,vbios_version";
+
+/*
+ * The throttle::REASONS values of gpu, or None if the driver lacks one of their fields. They are a
+ * query of their own, as one unsupported field makes nvidia-smi refuse the whole query.
+ */
+fn query_throttle_reasons(env: &Environment, gpu: &usize) -> Option<Vec<String>> {
+    let values: Vec<String> = query_gpu_field(env, gpu, &throttle::query_fields()).trim().split(", ").map(|v| v.to_string()).collect();
+    if values.len() == throttle::REASONS.len() { Some(values) } else { None }
+}
+
 pub fn print_query_info(env: &Environment, gpu: &usize) {
-    let gpu_information_raw = query_gpu_field(env, gpu, "name,clocks.current.graphics,clocks.current.memory,temperature.gpu,power.draw,\
-    fan.speed,memory.used,memory.total,enforced.power.limit,driver_version,pcie.link.gen.current,pcie.link.width.current,vbios_version");
+    let gpu_information_raw = query_gpu_field(env, gpu, QUERY_INFO_FIELDS);
     let gpu_information:Vec<&str> = gpu_information_raw.split(", ").collect();
+    let throttle_reasons = query_throttle_reasons(env, gpu);
+
+    if env.output == OutputFormat::Json {
+        print_query_json(env, gpu, &gpu_information, throttle_reasons.as_deref());
+        return;
+    }
 
     println!(" _______                    _____                     ");
     println!("|__   __|                  / ____|                    ");
@@ -70,7 +469,7 @@ pub fn print_query_info(env: &Environment, gpu: &usize) {
     println!("   | |  __/ (_| | | | | | | |__| | | |  __/  __/ | | |");
     println!("   |_|\\___|\\__,_|_| |_| |_|\\_____|_|  \\___|\\___|_| |_|");
     println!();
-    if gpu_information.len() != 13 {
+    if gpu_information.len() != QUERY_INFO_FIELDS.split(',').count() {
         println!("{}", gpu_information[0]);
         return;
     }
@@ -85,9 +484,127 @@ pub fn print_query_info(env: &Environment, gpu: &usize) {
     println!("Used Memory: {}", gpu_information[6]);
     println!("Total Memory: {}", gpu_information[7]);
     println!("Max Power: {}", gpu_information[8]);
+    match &throttle_reasons {
+        Some(values) => println!("Throttle Reasons: {}", throttle::describe(&values.iter().map(|v| v.as_str()).collect::<Vec<&str>>())),
+        None => println!("Throttle Reasons: Unknown"),
+    }
+    print_powermizer(env, gpu);
     println!();
     println!("Driver: {}", gpu_information[9]);
     println!("GPU PCIe Generation: {}", gpu_information[10]);
     println!("GPU PCIe Link Width: {}", gpu_information[11]);
     println!("VBios: {}", gpu_information[12]);
 }
+
+// The PowerMizer part of print_query_info, which needs nvidia-settings
+fn print_powermizer(env: &Environment, gpu: &usize) {
+    let powermizer = match query_powermizer(env, gpu) {
+        Some(p) => p,
+        None => {
+            println!("PowerMizer: Unknown");
+            return;
+        }
+    };
+
+    let level = powermizer.level.map_or(String::from("unknown"), |l| l.to_string());
+    println!("PowerMizer: {}, at performance level {}", powermizer.mode.unwrap_or("Unknown"), level);
+
+    let range = |min: usize, max: usize| if min == max { min.to_string() } else { format!("{}-{}", min, max) };
+    for l in &powermizer.levels {
+        let current = if powermizer.level == Some(l.level) { "  <- current" } else { "" };
+        println!("    Level {}: Core {} MHz, Memory {} MHz{}", l.level, range(l.core_min, l.core_max), range(l.memory_min, l.memory_max), current);
+    }
+}
+
+// A one line version of print_query_info, for showing the effect of a change
+pub fn print_query_summary(env: &Environment, gpu: &usize) {
+    let gpu_information_raw = query_gpu_field(env, gpu, "clocks.current.graphics,clocks.current.memory,temperature.gpu,power.draw,enforced.power.limit,fan.speed");
+    let gpu_information: Vec<&str> = gpu_information_raw.trim().split(", ").collect();
+
+    if gpu_information.len() != 6 {
+        println!("GPU {}: {}", gpu, gpu_information[0]);
+        return;
+    }
+
+    println!("GPU {}: Core {} | Memory {} | {} C | {} / {} | Fan {}", gpu, gpu_information[0], gpu_information[1], gpu_information[2],
+             gpu_information[3], gpu_information[4], gpu_information[5]);
+}
+
+// The applications clock pairs for appclocks list, one line per memory clock
+pub fn print_supported_clocks(env: &Environment, gpu: &usize) -> std::result::Result<(), String> {
+    let clocks = query_supported_clocks(env, gpu).map_err(|e| format!("Failed to list the supported clocks of GPU {}. {}", gpu, e))?;
+
+    if env.output == OutputFormat::Json {
+        let items: Vec<String> = clocks.iter()
+            .map(|(memory, graphics)| json::Object::new()
+                .raw("memory_mhz", memory.to_string())
+                .raw("graphics_mhz", json::array(&graphics.iter().map(|g| g.to_string()).collect::<Vec<String>>()))
+                .build())
+            .collect();
+        println!("{}", json::array(&items));
+        return Ok(());
+    }
+
+    if clocks.is_empty() {
+        println!("GPU {} does not support applications clocks.", gpu);
+        return Ok(());
+    }
+
+    for (memory, graphics) in &clocks {
+        let graphics: Vec<String> = graphics.iter().map(|g| g.to_string()).collect();
+        println!("Memory {} MHz: graphics {} MHz", memory, graphics.join(", "));
+    }
+    println!("\nSet a pair with e.g. 'appclocks {},{}'.", clocks[0].0, clocks[0].1[0]);
+
+    Ok(())
+}
+
+// print_query_info for output = json, keyed by the nvidia-smi field names
+fn print_query_json(env: &Environment, gpu: &usize, gpu_information: &[&str], throttle_reasons: Option<&[String]>) {
+    let fields: Vec<&str> = QUERY_INFO_FIELDS.split(',').map(|f| f.trim()).collect();
+    let mut object = json::Object::new().raw("gpu", gpu.to_string());
+
+    if gpu_information.len() != fields.len() {
+        println!("{}", object.string("error", gpu_information[0].trim()).build());
+        return;
+    }
+
+    for (field, value) in fields.iter().zip(gpu_information) {
+        object = object.string(field, value.trim());
+    }
+    for (field, value) in throttle::query_fields().split(',').zip(throttle_reasons.unwrap_or_default()) {
+        object = object.string(field, value.trim());
+    }
+
+    if let Some(powermizer) = query_powermizer(env, gpu) {
+        let levels: Vec<String> = powermizer.levels.iter()
+            .map(|l| json::Object::new()
+                .raw("level", l.level.to_string())
+                .raw("core_min_mhz", l.core_min.to_string())
+                .raw("core_max_mhz", l.core_max.to_string())
+                .raw("memory_min_mhz", l.memory_min.to_string())
+                .raw("memory_max_mhz", l.memory_max.to_string())
+                .build())
+            .collect();
+        object = object
+            .raw("powermizer_mode", powermizer.mode.map_or(String::from("null"), json::string))
+            .raw("performance_level", powermizer.level.map_or(String::from("null"), |l| l.to_string()))
+            .raw("performance_levels", json::array(&levels));
+    }
+
+    println!("{}", object.build());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_known_compute_modes_reach_nvidia_smi() {
+        let env = Environment { backend: Backend::DryRun, ..Environment::default() };
+
+        assert!(set_compute_mode(&env, &mut 0, "DEFAULT; id").is_err());
+        assert!(set_compute_mode(&env, &mut 0, "default").is_err());
+        assert!(set_compute_mode(&env, &mut 0, "EXCLUSIVE_PROCESS").is_ok());
+    }
+}
@@ -0,0 +1,291 @@
+use std::fs;
+use std::io::IsTerminal;
+
+use crate::doctor::confirm;
+use crate::executor::{execute, execute_as_root, execute_change, Environment, OutputFormat, Privilege};
+use crate::helper::user_name;
+use crate::json;
+use crate::nvidiagpu;
+
+/*
+ * procs lists who is using each GPU. Compute processes and their memory come from
+ * --query-compute-apps, graphics processes and SM utilisation from one sample of nvidia-smi pmon.
+ * The user and full command line are read from /proc, so processes in another PID namespace show
+ * as unknown.
+ *
+ * kill stops GPU processes, e.g. orphaned training jobs, after listing them and asking first.
+ */
+
+#[derive(Clone, Debug, Default)]
+pub struct GpuProcess {
+    pub gpu: usize,
+    pub pid: u32,
+    // C for compute, G for graphics, C+G for both
+    pub kind: String,
+    pub uid: Option<u32>,
+    pub user: String,
+    pub command: String,
+    pub memory: Option<f64>,
+    pub sm: Option<f64>,
+}
+
+// The real uid from /proc/<pid>/status, where pid can also be "self"
+pub fn process_uid(pid: &str) -> Option<u32> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    status.lines()
+        .find_map(|l| l.strip_prefix("Uid:"))
+        .and_then(|ids| ids.split_whitespace().next())
+        .and_then(|uid| uid.parse::<u32>().ok())
+}
+
+// The arguments are separated by NULs in /proc/<pid>/cmdline
+fn command_line(pid: u32) -> Option<String> {
+    let raw = fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+    let words: Vec<String> = raw.split(|b| *b == 0).filter(|w| !w.is_empty()).map(|w| String::from_utf8_lossy(w).into_owned()).collect();
+
+    if words.is_empty() { None } else { Some(words.join(" ")) }
+}
+
+/*
+ * Parses one sample of nvidia-smi pmon -s um, with fb as the used memory. The columns differ
+ * between drivers, so they are found by the names in the header:
+ *
+ *   # gpu         pid   type     sm    mem    enc    dec     fb   command
+ *   # Idx           #    C/G      %      %      %      %     MB   name
+ *       0        1234      C     87     45      -      -   3120   python
+ */
+pub fn parse_pmon(output: &str) -> Vec<GpuProcess> {
+    let header: Vec<&str> = match output.lines().find(|l| l.starts_with('#')) {
+        Some(line) => line.trim_start_matches('#').split_whitespace().collect(),
+        None => return Vec::new(),
+    };
+    let column = |name: &str| header.iter().position(|h| *h == name);
+    let (gpu, pid, kind, sm, fb) = (column("gpu"), column("pid"), column("type"), column("sm"), column("fb"));
+
+    output.lines()
+        .filter(|l| !l.starts_with('#'))
+        .filter_map(|l| {
+            let values: Vec<&str> = l.split_whitespace().collect();
+            let get = |i: Option<usize>| i.and_then(|i| values.get(i)).copied();
+
+            Some(GpuProcess {
+                gpu: get(gpu)?.parse::<usize>().ok()?,
+                // Idle GPUs get a line with - for the pid
+                pid: get(pid)?.parse::<u32>().ok()?,
+                kind: get(kind).unwrap_or("?").to_string(),
+                sm: get(sm).and_then(|v| v.parse::<f64>().ok()),
+                memory: get(fb).and_then(|v| v.parse::<f64>().ok()),
+                ..GpuProcess::default()
+            })
+        })
+        .collect()
+}
+
+pub fn list(env: &Environment) -> Result<Vec<GpuProcess>, String> {
+    let uuids = nvidiagpu::query_all_gpus(env, "uuid")?;
+    let index_of = |uuid: &str| uuids.iter().find(|row| row.len() == 2 && row[1] == uuid).and_then(|row| row[0].parse::<usize>().ok());
+
+    let mut processes: Vec<GpuProcess> = Vec::new();
+
+    let output = execute(env, &String::from("nvidia-smi --query-compute-apps=gpu_uuid,pid,used_memory --format=csv,noheader,nounits"))
+        .map_err(|e| format!("nvidia-smi could not be run. Error: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stdout).trim().to_string());
+    }
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+        if let [uuid, pid, memory] = fields.as_slice() {
+            if let (Some(gpu), Ok(pid)) = (index_of(uuid), pid.parse::<u32>()) {
+                processes.push(GpuProcess { gpu, pid, kind: String::from("C"), memory: memory.parse::<f64>().ok(), ..GpuProcess::default() });
+            }
+        }
+    }
+
+    // pmon is not supported on every GPU, the compute processes are still worth showing without it
+    if let Ok(output) = execute(env, &String::from("nvidia-smi pmon -c 1 -s um")) {
+        for sample in parse_pmon(&String::from_utf8_lossy(&output.stdout)) {
+            match processes.iter_mut().find(|p| p.gpu == sample.gpu && p.pid == sample.pid) {
+                Some(p) => {
+                    p.kind = sample.kind;
+                    p.sm = sample.sm;
+                    p.memory = p.memory.or(sample.memory);
+                },
+                None => processes.push(sample),
+            }
+        }
+    }
+
+    for p in processes.iter_mut() {
+        p.uid = process_uid(&p.pid.to_string());
+        p.user = p.uid.map_or(String::from("?"), user_name);
+        p.command = command_line(p.pid).unwrap_or(String::from("?"));
+    }
+
+    processes.sort_by_key(|p| (p.gpu, p.pid));
+    Ok(processes)
+}
+
+fn print_table(processes: &[GpuProcess]) {
+    let user_width = processes.iter().map(|p| p.user.len()).max().unwrap_or(0).max(4);
+    let mut current_gpu = None;
+
+    for p in processes {
+        if current_gpu != Some(p.gpu) {
+            current_gpu = Some(p.gpu);
+            println!("GPU {}", p.gpu);
+            println!("  {:<8} {:<user_width$} {:<4} {:>10} {:>5}  COMMAND", "PID", "USER", "TYPE", "MEMORY", "SM");
+        }
+
+        let memory = p.memory.map_or(String::from("-"), |m| format!("{} MiB", m));
+        let sm = p.sm.map_or(String::from("-"), |s| format!("{}%", s));
+        println!("  {:<8} {:<user_width$} {:<4} {:>10} {:>5}  {}", p.pid, p.user, p.kind, memory, sm, p.command);
+    }
+}
+
+pub fn run(env: &Environment) -> Result<(), String> {
+    let processes = list(env).map_err(|e| format!("Failed to list GPU processes. {}", e))?;
+
+    if env.output == OutputFormat::Json {
+        let items: Vec<String> = processes.iter()
+            .map(|p| json::Object::new()
+                .raw("gpu", p.gpu.to_string())
+                .raw("pid", p.pid.to_string())
+                .string("type", &p.kind)
+                .string("user", &p.user)
+                .string("command", &p.command)
+                .raw("used_memory_mib", p.memory.map_or(String::from("null"), json::number))
+                .raw("sm_percent", p.sm.map_or(String::from("null"), json::number))
+                .build())
+            .collect();
+        println!("{}", json::array(&items));
+        return Ok(());
+    }
+
+    if processes.is_empty() {
+        println!("No processes are using the GPUs.");
+    } else {
+        print_table(&processes);
+    }
+
+    Ok(())
+}
+
+/*
+ * kill [pid] | kill (--gpu N) (--user name), with --yes to skip the question. Only processes that
+ * procs lists can be killed, they get SIGTERM so they can clean up. Processes of other users are
+ * killed through the privilege strategy, except the helper, which only applies GPU settings.
+ */
+pub fn kill(env: &Environment, args: &[&String]) -> Result<(), String> {
+    let usage = || String::from("Usage: kill [pid] (--yes), or kill (--gpu N) (--user name) (--yes)");
+
+    let mut pid = None;
+    let mut gpu = None;
+    let mut user = None;
+    let mut yes = false;
+
+    let mut words = args.iter();
+    while let Some(word) = words.next() {
+        match word.as_str() {
+            "--yes" => yes = true,
+            "--gpu" => gpu = Some(words.next().and_then(|g| g.parse::<usize>().ok()).ok_or_else(usage)?),
+            "--user" => user = Some(words.next().ok_or_else(usage)?.to_string()),
+            other => pid = Some(other.parse::<u32>().map_err(|_| usage())?),
+        }
+    }
+
+    if pid.is_none() && gpu.is_none() && user.is_none() {
+        return Err(usage());
+    }
+
+    let processes = list(env).map_err(|e| format!("Failed to list GPU processes. {}", e))?;
+    let targets: Vec<GpuProcess> = processes.into_iter()
+        .filter(|p| pid.is_none_or(|pid| p.pid == pid))
+        .filter(|p| gpu.is_none_or(|gpu| p.gpu == gpu))
+        .filter(|p| user.as_ref().is_none_or(|user| &p.user == user))
+        .collect();
+
+    if targets.is_empty() {
+        return Err(match pid {
+            Some(pid) => format!("Process {} is not using a GPU.", pid),
+            None => String::from("No GPU processes match."),
+        });
+    }
+
+    print_table(&targets);
+
+    if !yes {
+        if !std::io::stdin().is_terminal() {
+            return Err(String::from("Not killing without a terminal to confirm on. Add --yes to kill without asking."));
+        }
+        if !confirm(&format!("Send SIGTERM to {} process(es)?", targets.len())) {
+            println!("Nothing was killed.");
+            return Ok(());
+        }
+    }
+
+    let own_uid = process_uid("self");
+    let mut failed = Vec::new();
+
+    // A pid listed twice, on two GPUs, is only killed once
+    let mut pids: Vec<(u32, Option<u32>)> = targets.iter().map(|p| (p.pid, p.uid)).collect();
+    pids.sort_by_key(|(pid, _)| *pid);
+    pids.dedup_by_key(|(pid, _)| *pid);
+
+    for (pid, uid) in pids {
+        let own = uid.is_some() && uid == own_uid;
+
+        // The helper's policy only covers GPU settings, it never sends signals
+        if !own && env.privilege == Privilege::Helper {
+            failed.push(format!("{} (it belongs to another user and the helper does not kill processes, run kill with sudo or as root)", pid));
+            continue;
+        }
+
+        let cmd = format!("kill {}", pid);
+        let result = if own { execute_change(env, &cmd) } else { execute_as_root(env, &cmd) };
+
+        match result {
+            Ok(o) if o.status.success() => println!("Sent SIGTERM to {}.", pid),
+            Ok(o) => failed.push(format!("{} ({})", pid, String::from_utf8_lossy(&o.stderr).trim())),
+            Err(e) => failed.push(format!("{} ({})", pid, e)),
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Failed to kill {}.", failed.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pmon_columns_are_found_by_their_header() {
+        let output = "# gpu         pid   type     sm    mem    enc    dec     fb   command\n\
+                      # Idx           #    C/G      %      %      %      %     MB   name\n\
+                      \x20   0        1234      C     87     45      -      -   3120   python\n\
+                      \x20   0        5678    C+G      -      -      -      -     12   Xorg\n\
+                      \x20   1           -      -      -      -      -      -      -   -\n";
+
+        let processes = parse_pmon(output);
+
+        assert_eq!(processes.len(), 2);
+        assert_eq!((processes[0].gpu, processes[0].pid, processes[0].kind.as_str()), (0, 1234, "C"));
+        assert_eq!((processes[0].sm, processes[0].memory), (Some(87.0), Some(3120.0)));
+        assert_eq!((processes[1].pid, processes[1].kind.as_str(), processes[1].sm), (5678, "C+G", None));
+    }
+
+    #[test]
+    fn pmon_without_fb_or_a_header_still_parses() {
+        let output = "# gpu        pid  type    sm   mem   enc   dec   command\n    0       4321     G     3     1     -     -   Xorg\n";
+
+        let processes = parse_pmon(output);
+
+        assert_eq!(processes.len(), 1);
+        assert_eq!((processes[0].pid, processes[0].memory, processes[0].sm), (4321, None, Some(3.0)));
+        assert!(parse_pmon("Failed to initialize NVML\n").is_empty());
+    }
+}
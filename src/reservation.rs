@@ -0,0 +1,356 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::executor::{Environment, OutputFormat};
+use crate::helper::user_name;
+use crate::json;
+use crate::logging::format_utc;
+use crate::nvidiagpu::{self, GpuSnapshot};
+use crate::procs::process_uid;
+use crate::state;
+use crate::{signals, units};
+
+/*
+ * Advisory GPU reservations for job scripts on a shared machine. A reservation is a file named after
+ * the GPU's UUID in reservation_dir, holding the process it was made for. It lasts until release or
+ * until that process exits, so a crashed job never holds a GPU for good.
+ *
+ *   GPU=$(teamgreenhelper wait-idle --reserve --pid $$ | cut -d' ' -f1)
+ *   CUDA_VISIBLE_DEVICES=$GPU python train.py
+ *   teamgreenhelper gpu $GPU release
+ *
+ * wait-idle --reserve needs --pid, as its output is read in a command substitution whose subshell,
+ * the parent of wait-idle, exits as soon as the GPU is printed.
+ *
+ * Nothing stops a process that ignores reservations from using the GPU.
+ */
+
+pub const DEFAULT_DIR: &str = "/run/lock/teamgreenhelper";
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_MEMORY: f64 = 512.0;
+const DEFAULT_MAX_UTILIZATION: f64 = 10.0;
+
+struct Reservation {
+    pid: u32,
+    user: String,
+    since: String,
+}
+
+impl Reservation {
+    fn parse(contents: &str) -> Option<Reservation> {
+        let value = |key: &str| contents.lines().find_map(|l| l.strip_prefix(key)).map(|v| v.trim().to_string());
+
+        Some(Reservation {
+            pid: value("pid=")?.parse::<u32>().ok()?,
+            user: value("user=").unwrap_or(String::from("?")),
+            since: value("since=").unwrap_or(String::from("?")),
+        })
+    }
+}
+
+// Sticky and open to everyone like /tmp, so every user can reserve but only remove their own
+fn dir(env: &Environment) -> Result<PathBuf, String> {
+    let dir = PathBuf::from(env.config.get("reservation_dir"));
+
+    if !dir.is_dir() {
+        fs::create_dir_all(&dir)
+            .and_then(|_| fs::set_permissions(&dir, fs::Permissions::from_mode(0o1777)))
+            .map_err(|e| format!("Failed to create the reservation directory {}. Error: {}", dir.display(), e))?;
+    }
+
+    Ok(dir)
+}
+
+fn path(env: &Environment, uuid: &str) -> Result<PathBuf, String> {
+    Ok(dir(env)?.join(format!("{}.lock", uuid)))
+}
+
+// Whether two handles are the same file, i.e. a reservation was not replaced in between
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+/*
+ * The live reservation of a GPU. One whose process has exited is removed under a lock on it, and
+ * only if it is still the file at path, so two processes that both found it stale cannot remove a
+ * reservation made in between.
+ */
+fn holder(path: &Path) -> Option<Reservation> {
+    let mut file = File::open(path).ok()?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).ok()?;
+
+    match Reservation::parse(&contents) {
+        Some(r) if PathBuf::from(format!("/proc/{}", r.pid)).exists() => return Some(r),
+        _ => {},
+    }
+
+    if state::lock(&file).is_ok() && fs::metadata(path).ok().zip(file.metadata().ok()).is_some_and(|(p, f)| same_file(&p, &f)) {
+        let _ = fs::remove_file(path);
+    }
+
+    None
+}
+
+/*
+ * Reserves the GPU for pid. The reservation is written in full under a private name and then linked
+ * into place, which fails if the GPU is taken, so two jobs cannot both get it and nobody reads a
+ * half-written one. Returns the other reservation if the GPU is taken, a GPU already reserved for
+ * pid is not.
+ */
+fn reserve_uuid(env: &Environment, uuid: &str, pid: u32) -> Result<Option<Reservation>, String> {
+    let path = path(env, uuid)?;
+    let staged = path.with_file_name(format!("{}.{}.tmp", uuid, std::process::id()));
+    let user = process_uid("self").map_or(String::from("?"), user_name);
+
+    let _ = fs::remove_file(&staged);
+    OpenOptions::new().write(true).create_new(true).open(&staged)
+        .and_then(|mut file| writeln!(file, "pid={}\nuser={}\nsince={}", pid, user, format_utc(SystemTime::now(), false)))
+        .map_err(|e| format!("Failed to write {}. Error: {}", staged.display(), e))?;
+
+    let result = link_reservation(&staged, &path, pid);
+    let _ = fs::remove_file(&staged);
+    result
+}
+
+fn link_reservation(staged: &Path, path: &Path, pid: u32) -> Result<Option<Reservation>, String> {
+    // Twice, in case the first attempt found a stale reservation and removed it
+    for _ in 0..2 {
+        match fs::hard_link(staged, path) {
+            Ok(()) => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => match holder(path) {
+                Some(r) if r.pid == pid => return Ok(None),
+                Some(r) => return Ok(Some(r)),
+                None => continue,
+            },
+            Err(e) => return Err(format!("Failed to create {}. Error: {}", path.display(), e)),
+        }
+    }
+
+    Err(format!("Failed to reserve {}, its reservation keeps changing or belongs to another user and could not be removed.", path.display()))
+}
+
+fn uuid_of(env: &Environment, gpu: &usize) -> Result<String, String> {
+    let rows = nvidiagpu::query_all_gpus(env, "uuid")?;
+
+    rows.iter()
+        .find(|row| row.len() == 2 && row[0] == gpu.to_string())
+        .map(|row| row[1].clone())
+        .ok_or_else(|| format!("GPU {} was not found.", gpu))
+}
+
+// The process a reservation is for, the caller (usually the job script's shell) unless --pid is given
+fn parse_pid(args: &[&String]) -> Result<u32, String> {
+    match args {
+        [] => Ok(std::os::unix::process::parent_id()),
+        [flag, pid] if flag.as_str() == "--pid" => pid.parse::<u32>().map_err(|_| format!("'{}' is not a process id.", pid)),
+        _ => Err(String::from("Usage: reserve (--pid process_id)")),
+    }
+}
+
+pub fn reserve(env: &Environment, gpu: &usize, args: &[&String]) -> Result<(), String> {
+    let pid = parse_pid(args)?;
+    let uuid = uuid_of(env, gpu)?;
+
+    match reserve_uuid(env, &uuid, pid)? {
+        None => {
+            println!("Reserved GPU {} ({}) for process {}.", gpu, uuid, pid);
+            Ok(())
+        },
+        Some(r) => Err(format!("GPU {} is reserved by {} for process {} since {}.", gpu, r.user, r.pid, r.since)),
+    }
+}
+
+pub fn release(env: &Environment, gpu: &usize) -> Result<(), String> {
+    let uuid = uuid_of(env, gpu)?;
+    let path = path(env, &uuid)?;
+
+    if holder(&path).is_none() {
+        println!("GPU {} is not reserved.", gpu);
+        return Ok(());
+    }
+
+    // The sticky directory already stops this, the check gives a clearer message
+    let owner = fs::metadata(&path).map(|m| m.uid()).ok();
+    let own_uid = process_uid("self");
+    if owner.is_some() && owner != own_uid && own_uid != Some(0) {
+        return Err(format!("GPU {} is reserved by another user.", gpu));
+    }
+
+    fs::remove_file(&path).map_err(|e| format!("Failed to release GPU {}. Error: {}", gpu, e))?;
+    println!("Released GPU {} ({}).", gpu, uuid);
+    Ok(())
+}
+
+struct WaitOptions {
+    // None for any GPU
+    gpu: Option<usize>,
+    max_memory: f64,
+    max_utilization: f64,
+    timeout: Option<Duration>,
+    interval: Duration,
+    reserve: bool,
+    // The process a reservation is for, None for the caller
+    pid: Option<u32>,
+}
+
+fn parse_wait_options(args: &[&String]) -> Result<WaitOptions, String> {
+    let mut options = WaitOptions {
+        gpu: None,
+        max_memory: DEFAULT_MAX_MEMORY,
+        max_utilization: DEFAULT_MAX_UTILIZATION,
+        timeout: None,
+        interval: DEFAULT_INTERVAL,
+        reserve: false,
+        pid: None,
+    };
+
+    let mut words = args.iter();
+    while let Some(flag) = words.next() {
+        if flag.as_str() == "--reserve" {
+            options.reserve = true;
+            continue;
+        }
+
+        let value = match words.next() {
+            Some(v) => v.as_str(),
+            None => return Err(format!("'{}' needs a value.", flag)),
+        };
+        let number = |what: &str| value.trim_end_matches(['%', 'B']).trim_end_matches("Mi").parse::<f64>()
+            .map_err(|_| format!("'{}' is not {}.", value, what));
+
+        match flag.as_str() {
+            "--gpu" if value == "any" => options.gpu = None,
+            "--gpu" => options.gpu = Some(value.parse::<usize>().map_err(|_| format!("'{}' is not a GPU index or any.", value))?),
+            "--max-mem" => options.max_memory = number("an amount of memory in MiB")?,
+            "--max-util" => options.max_utilization = number("a percentage")?,
+            "--timeout" => options.timeout = Some(units::parse_duration(value)?),
+            "--interval" => options.interval = units::parse_duration(value)?,
+            "--pid" => options.pid = Some(value.parse::<u32>().map_err(|_| format!("'{}' is not a process id.", value))?),
+            _ => return Err(format!("'{}' is not a wait-idle option. Expected --gpu, --max-mem, --max-util, --timeout, --interval, --reserve or --pid.", flag)),
+        }
+    }
+
+    if options.reserve && options.pid.is_none() {
+        return Err(String::from("wait-idle --reserve needs the process to reserve for, e.g. --pid $$ for the job script. \
+                                 Its parent is usually the subshell reading its output, which exits straight away."));
+    }
+
+    Ok(options)
+}
+
+fn is_idle(gpu: &GpuSnapshot, options: &WaitOptions) -> bool {
+    gpu.memory_used.is_some_and(|m| m <= options.max_memory) && gpu.utilization.is_none_or(|u| u <= options.max_utilization)
+}
+
+/*
+ * wait-idle polls until a GPU is idle and not reserved by another process, then prints its index
+ * and UUID. With --reserve the GPU is reserved for the caller before it is printed.
+ */
+pub fn wait_idle(env: &Environment, args: &[&String]) -> Result<(), String> {
+    let options = parse_wait_options(args)?;
+    let pid = options.pid.unwrap_or_else(std::os::unix::process::parent_id);
+    let start = Instant::now();
+    signals::install();
+
+    loop {
+        let snapshots = nvidiagpu::query_snapshots(env)?;
+
+        if let Some(gpu) = options.gpu {
+            if !snapshots.iter().any(|s| s.index == gpu) {
+                return Err(format!("GPU {} was not found.", gpu));
+            }
+        }
+
+        for snapshot in snapshots.iter().filter(|s| options.gpu.is_none_or(|g| s.index == g) && is_idle(s, &options)) {
+            let free = if options.reserve {
+                reserve_uuid(env, &snapshot.uuid, pid)?.is_none()
+            } else {
+                holder(&path(env, &snapshot.uuid)?).is_none_or(|r| r.pid == pid)
+            };
+
+            if free {
+                if env.output == OutputFormat::Json {
+                    println!("{}", json::Object::new().raw("gpu", snapshot.index.to_string()).string("uuid", &snapshot.uuid).build());
+                } else {
+                    println!("{} {}", snapshot.index, snapshot.uuid);
+                }
+                return Ok(());
+            }
+        }
+
+        let mut wait = options.interval;
+        if let Some(timeout) = options.timeout {
+            if start.elapsed() >= timeout {
+                return Err(format!("No GPU became idle within {:?}.", timeout));
+            }
+            wait = wait.min(timeout.saturating_sub(start.elapsed()));
+        }
+
+        if !signals::sleep(wait) {
+            return Err(String::from("Stopped waiting for an idle GPU."));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Source;
+    use crate::executor::private_temp_dir;
+    use std::thread;
+
+    fn environment(dir: &Path) -> Environment {
+        let mut env = Environment::default();
+        env.config.set("reservation_dir", &dir.to_string_lossy(), Source::CommandLine);
+        env
+    }
+
+    #[test]
+    fn only_one_of_two_racing_jobs_gets_the_gpu() {
+        let dir = private_temp_dir("teamgreenhelper-reservations").unwrap();
+        let pids = [std::process::id(), std::os::unix::process::parent_id()];
+
+        let results: Vec<bool> = thread::scope(|scope| {
+            let jobs: Vec<_> = pids.iter().map(|pid| {
+                let dir = &dir;
+                scope.spawn(move || reserve_uuid(&environment(dir), "GPU-test", *pid).unwrap().is_none())
+            }).collect();
+            jobs.into_iter().map(|j| j.join().unwrap()).collect()
+        });
+
+        let remaining = fs::read_dir(&dir).unwrap().count();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(results.iter().filter(|won| **won).count(), 1);
+        assert_eq!(remaining, 1);
+    }
+
+    #[test]
+    fn a_reservation_of_an_exited_process_is_replaced() {
+        let dir = private_temp_dir("teamgreenhelper-reservations").unwrap();
+        let env = environment(&dir);
+
+        // Above the kernel's highest pid, so it never belongs to a running process
+        fs::write(dir.join("GPU-test.lock"), "pid=4194305\nuser=nobody\nsince=?\n").unwrap();
+        let result = reserve_uuid(&env, "GPU-test", std::process::id()).unwrap();
+        let holder = holder(&dir.join("GPU-test.lock")).map(|r| r.pid);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(result.is_none());
+        assert_eq!(holder, Some(std::process::id()));
+    }
+
+    #[test]
+    fn wait_idle_only_reserves_for_a_given_pid() {
+        let args: Vec<String> = ["--reserve", "--timeout", "1h"].iter().map(|a| a.to_string()).collect();
+        assert!(parse_wait_options(&args.iter().collect::<Vec<&String>>()).is_err());
+
+        let args: Vec<String> = ["--reserve", "--pid", "1234"].iter().map(|a| a.to_string()).collect();
+        assert_eq!(parse_wait_options(&args.iter().collect::<Vec<&String>>()).unwrap().pid, Some(1234));
+    }
+}
@@ -0,0 +1,114 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::executor::{execute, Environment};
+
+/*
+ * The daemon's profile schedule, daemon.schedule, lists the profile to run from each time of day:
+ *
+ *   schedule = "08:00 quiet, 18:30 performance"
+ *
+ * Times are local. The profile that applies is the one whose time passed last, wrapping around
+ * midnight, so a daemon started at noon runs quiet straight away rather than waiting until 18:30.
+ * A profile is only run again once another one has been in between.
+ */
+
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    // Minutes since midnight
+    pub minute: u32,
+    pub profile: String,
+}
+
+pub fn parse_schedule(text: &str) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::new();
+
+    for item in text.split(',').map(|i| i.trim()).filter(|i| !i.is_empty()) {
+        let parsed = item.split_once(char::is_whitespace).and_then(|(time, profile)| {
+            let (hour, minute) = time.split_once(':')?;
+            let (hour, minute) = (hour.parse::<u32>().ok()?, minute.parse::<u32>().ok()?);
+            (hour < 24 && minute < 60).then(|| Entry { minute: hour * 60 + minute, profile: profile.trim().to_string() })
+        });
+
+        match parsed {
+            Some(entry) => entries.push(entry),
+            None => return Err(format!("'{}' is not a schedule entry. Expected a time and a profile, e.g. 18:30 performance.", item)),
+        }
+    }
+
+    entries.sort_by_key(|e| e.minute);
+    Ok(entries)
+}
+
+// The entry in effect at minute, the last one at or before it, or yesterday's last one
+pub fn current(entries: &[Entry], minute: u32) -> Option<&Entry> {
+    entries.iter().rev().find(|e| e.minute <= minute).or_else(|| entries.last())
+}
+
+// The local time as minutes since midnight. date knows the time zone, which std does not.
+fn local_minute(env: &Environment) -> Option<u32> {
+    let output = execute(env, &String::from("date +%H:%M")).ok()?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    let (hour, minute) = text.trim().split_once(':')?;
+    Some(hour.parse::<u32>().ok()? * 60 + minute.parse::<u32>().ok()?)
+}
+
+pub struct Scheduler {
+    entries: Vec<Entry>,
+    // The minute last checked, so date runs once a minute rather than every tick
+    checked: Option<u64>,
+    applied: Option<String>,
+}
+
+impl Scheduler {
+    pub fn new(entries: Vec<Entry>) -> Scheduler {
+        Scheduler { entries, checked: None, applied: None }
+    }
+
+    // The profile to run now, if it is not the one run last
+    pub fn due(&mut self, env: &Environment) -> Option<String> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() / 60);
+        if self.checked == Some(now) {
+            return None;
+        }
+        self.checked = Some(now);
+
+        let profile = current(&self.entries, local_minute(env)?)?.profile.clone();
+        if self.applied.as_ref() == Some(&profile) {
+            return None;
+        }
+
+        self.applied = Some(profile.clone());
+        Some(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_read_and_sorted_by_time() {
+        let entries = parse_schedule("18:30 performance, 08:00 quiet").unwrap();
+
+        assert_eq!(entries, vec![
+            Entry { minute: 480, profile: String::from("quiet") },
+            Entry { minute: 1110, profile: String::from("performance") },
+        ]);
+        assert!(parse_schedule("25:00 quiet").is_err());
+        assert!(parse_schedule("08:00").is_err());
+        assert!(parse_schedule("8am quiet").is_err());
+        assert!(parse_schedule("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn the_last_entry_to_pass_applies_and_wraps_past_midnight() {
+        let entries = parse_schedule("08:00 quiet, 18:30 performance").unwrap();
+        let profile = |minute: u32| current(&entries, minute).map(|e| e.profile.as_str());
+
+        assert_eq!(profile(480), Some("quiet"));
+        assert_eq!(profile(720), Some("quiet"));
+        assert_eq!(profile(1110), Some("performance"));
+        assert_eq!(profile(60), Some("performance"));
+        assert_eq!(current(&[], 60), None);
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+
+use crate::commands::{default_commands, HelperCommand};
+use crate::executor::Environment;
+use crate::run_tokens;
+
+const MAX_DEPTH: usize = 8;
+
+/*
+ * Script files are command lines split over several lines, e.g.
+ *
+ *     # Undervolt both cards
+ *     CLOCK=1800
+ *     gpu 0 clock $CLOCK clockoffset +150MHz
+ *     gpu 1 clock ${CLOCK} clockoffset +120MHz
+ *
+ * Each line goes through the same run_tokens grammar as the command line. The GPU selection and
+ * the Environment carry over from one line to the next, exactly as they would on a single command
+ * line. Variables that are not set in the script fall back to the process environment.
+ */
+pub fn run_file(path: &str, env: &mut Environment, gpu: &mut usize) -> Result<(), String> {
+    if env.script_depth >= MAX_DEPTH {
+        return Err(format!("Not running {}. Scripts can only run-file each other {} levels deep, is one running itself?", path, MAX_DEPTH));
+    }
+
+    let mut contents = String::new();
+
+    let read = if path == "-" {
+        io::stdin().read_to_string(&mut contents).map(|_| ())
+    } else {
+        fs::read_to_string(path).map(|c| contents = c)
+    };
+
+    if let Err(e) = read {
+        return Err(format!("Failed to read script {}. Error: {}", path, e));
+    }
+
+    let name = if path == "-" { "stdin" } else { path };
+    run_script(name, &contents, &default_commands(), env, gpu)
+}
+
+/*
+ * Runs every line of a script with the commands given, which lets the daemon run profiles with only
+ * the setters. Errors are printed with the script's name and the line number.
+ */
+pub fn run_script(name: &str, contents: &str, commands: &HashMap<String, HelperCommand>, env: &mut Environment, gpu: &mut usize) -> Result<(), String> {
+    let mut variables: HashMap<String, String> = HashMap::new();
+    let mut failed = 0;
+
+    env.script_depth += 1;
+    for (number, line) in contents.lines().enumerate() {
+        if run_line(line, &mut variables, commands, env, gpu, &mut |e| println!("{}:{}: {}", name, number + 1, e)) > 0 {
+            failed += 1;
+        }
+    }
+    env.script_depth -= 1;
+
+    if failed > 0 {
+        Err(format!("{} line(s) of {} failed.", failed, name))
+    } else {
+        Ok(())
+    }
+}
+
+/*
+ * Runs one line of a script or shell session: blank lines and comments do nothing, NAME=value sets
+ * a variable and anything else is a command line. Errors are reported as in run_tokens and counted.
+ */
+pub fn run_line(line: &str, variables: &mut HashMap<String, String>, commands: &HashMap<String, HelperCommand>, env: &mut Environment, gpu: &mut usize, report: &mut dyn FnMut(String)) -> usize {
+    let words = match split_words(line, variables) {
+        Ok(w) => w,
+        Err(e) => {
+            report(e);
+            return 1;
+        },
+    };
+
+    if let Some((key, value)) = parse_assignment(&words) {
+        variables.insert(key, value);
+        return 0;
+    }
+
+    run_tokens(&words, commands, env, gpu, report)
+}
+
+// NAME=value, only when it is the whole line
+fn parse_assignment(words: &[String]) -> Option<(String, String)> {
+    if words.len() != 1 {
+        return None;
+    }
+
+    let (key, value) = words[0].split_once('=')?;
+
+    if is_variable_name(key) {
+        Some((key.to_string(), value.to_string()))
+    } else {
+        None
+    }
+}
+
+fn is_variable_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn lookup(name: &str, variables: &HashMap<String, String>) -> Result<String, String> {
+    match variables.get(name) {
+        Some(v) => Ok(v.clone()),
+        None => std::env::var(name).map_err(|_| format!("Variable ${} is not set.", name)),
+    }
+}
+
+/*
+ * Splits a line into words the way a shell would for the simple cases: whitespace separates words,
+ * single quotes are literal, double quotes still expand $VARIABLES and a # outside of quotes
+ * starts a comment.
+ */
+pub fn split_words(line: &str, variables: &HashMap<String, String>) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => {
+                quote = None;
+            },
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                in_word = true;
+            },
+            (None, '#') if !in_word => {
+                break;
+            },
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            },
+            (q, '$') if q != Some('\'') => {
+                let braced = chars.peek() == Some(&'{');
+                if braced {
+                    chars.next();
+                }
+
+                let mut name = String::new();
+                while let Some(&n) = chars.peek() {
+                    if n.is_ascii_alphanumeric() || n == '_' {
+                        name.push(n);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+
+                if braced && chars.next() != Some('}') {
+                    return Err(format!("Missing closing }} after ${{{}.", name));
+                }
+
+                if name.is_empty() {
+                    current.push('$');
+                } else {
+                    current.push_str(&lookup(&name, variables)?);
+                }
+                in_word = true;
+            },
+            (_, c) => {
+                current.push(c);
+                in_word = true;
+            }
+        }
+    }
+
+    if let Some(q) = quote {
+        return Err(format!("Missing closing {} quote.", q));
+    }
+
+    if in_word {
+        words.push(current);
+    }
+
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str, variables: &[(&str, &str)]) -> Result<Vec<String>, String> {
+        let variables: HashMap<String, String> = variables.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        split_words(line, &variables)
+    }
+
+    #[test]
+    fn words_are_split_on_whitespace_and_comments_are_dropped() {
+        assert_eq!(words("  gpu 1   clock 1800 # lock it", &[]).unwrap(), vec!["gpu", "1", "clock", "1800"]);
+        assert_eq!(words("fan 0 50#%", &[]).unwrap(), vec!["fan", "0", "50#%"]);
+        assert!(words("# only a comment", &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn quotes_group_words_and_single_quotes_do_not_expand() {
+        let variables = [("CLOCK", "1800")];
+        assert_eq!(words("autotune --test \"./burn $CLOCK\"", &variables).unwrap(), vec!["autotune", "--test", "./burn 1800"]);
+        assert_eq!(words("echo '$CLOCK # not a comment'", &variables).unwrap(), vec!["echo", "$CLOCK # not a comment"]);
+        assert_eq!(words("''", &[]).unwrap(), vec![""]);
+        assert!(words("clock \"1800", &[]).is_err());
+    }
+
+    #[test]
+    fn variables_expand_with_and_without_braces() {
+        let variables = [("CLOCK", "1800"), ("GPU", "1")];
+        assert_eq!(words("gpu $GPU clock ${CLOCK}MHz", &variables).unwrap(), vec!["gpu", "1", "clock", "1800MHz"]);
+        assert_eq!(words("price $ 5", &[]).unwrap(), vec!["price", "$", "5"]);
+        assert!(words("clock ${CLOCK", &variables).is_err());
+        assert!(words("clock $TGH_TEST_SURELY_UNSET_VARIABLE", &[]).is_err());
+    }
+
+    #[test]
+    fn assignments_are_whole_lines_with_a_valid_name() {
+        let assignment = |line: &str| parse_assignment(&words(line, &[]).unwrap());
+        assert_eq!(assignment("CLOCK=1800"), Some((String::from("CLOCK"), String::from("1800"))));
+        assert_eq!(assignment("NAME='two words'"), Some((String::from("NAME"), String::from("two words"))));
+        assert_eq!(assignment("1GPU=0"), None);
+        assert_eq!(assignment("CLOCK=1800 fan 50"), None);
+    }
+
+    #[test]
+    fn a_script_running_itself_stops_at_the_depth_limit() {
+        let path = std::env::temp_dir().join(format!("tgh-script-{}.tgh", std::process::id()));
+        fs::write(&path, format!("run-file {}\n", path.display())).unwrap();
+
+        let mut env = Environment::default();
+        let mut gpu = 0;
+        let result = run_file(&path.to_string_lossy(), &mut env, &mut gpu);
+        let _ = fs::remove_file(&path);
+
+        assert!(result.is_err());
+        assert_eq!(env.script_depth, 0);
+    }
+}
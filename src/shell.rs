@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use crate::commands::{default_commands, HelperCommand};
+use crate::executor::Environment;
+use crate::{cmd_exists, nvidiagpu, script};
+
+const HISTORY_LIMIT: usize = 500;
+
+// Commands that change the GPU, a summary of it is printed after a line that runs one of these
+const CHANGING_COMMANDS: [&str; 8] = ["fan", "clock", "memory", "memoryoffset", "clockoffset", "power", "reset", "run-file"];
+
+/*
+ * Interactive prompt. Every line is a command line run through script::run_line, so variables work
+ * as in scripts, and the Environment found at startup is kept for the whole session instead of
+ * being rediscovered per invocation.
+ */
+pub fn run(env: &mut Environment, gpu: &mut usize) -> Result<(), String> {
+    let commands = default_commands();
+    let mut variables: HashMap<String, String> = HashMap::new();
+    let mut editor = LineEditor::new(&commands);
+
+    println!("Team Green Helper shell. Type 'help' for commands and 'exit' to leave.");
+
+    while let Some(line) = editor.read_line(&format!("tgh[gpu {}]> ", gpu)) {
+        let trimmed = line.trim();
+        if trimmed == "exit" || trimmed == "quit" {
+            break;
+        }
+
+        let errors = script::run_line(&line, &mut variables, &commands, env, gpu, &mut |e| println!("{}", e));
+
+        if errors == 0 && changes_gpu(&line, &commands) {
+            nvidiagpu::print_query_summary(env, gpu);
+        }
+    }
+
+    editor.save_history();
+    Ok(())
+}
+
+fn changes_gpu(line: &str, commands: &HashMap<String, HelperCommand>) -> bool {
+    line.split_whitespace().any(|word| {
+        cmd_exists(&word.to_string(), commands).is_some_and(|cmd| CHANGING_COMMANDS.contains(&cmd.name.as_str()))
+    })
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".teamgreenhelper_history"))
+}
+
+/*
+ * Switches the terminal out of canonical mode so keys can be read one at a time, and puts it back
+ * when dropped. stty is used rather than termios bindings to keep the program free of dependencies.
+ */
+struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    fn enable() -> Option<RawMode> {
+        let saved = Command::new("stty").arg("-g").stdin(Stdio::inherit()).output().ok()?;
+        if !saved.status.success() {
+            return None;
+        }
+
+        let status = Command::new("stty").args(["-icanon", "-echo", "-isig", "min", "1"]).stdin(Stdio::inherit()).status().ok()?;
+        if !status.success() {
+            return None;
+        }
+
+        Some(RawMode { saved: String::from_utf8_lossy(&saved.stdout).trim().to_string() })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = Command::new("stty").arg(&self.saved).stdin(Stdio::inherit()).status();
+    }
+}
+
+struct LineEditor {
+    history: Vec<String>,
+    words: Vec<String>,
+    interactive: bool,
+}
+
+impl LineEditor {
+    fn new(commands: &HashMap<String, HelperCommand>) -> LineEditor {
+        let mut words: Vec<String> = commands.values()
+            .flat_map(|c| std::iter::once(c.name.clone()).chain(c.aliases.iter().cloned()))
+            .collect();
+        words.push(String::from("exit"));
+        words.sort();
+        words.dedup();
+
+        let history = history_path()
+            .and_then(|p| fs::read_to_string(p).ok())
+            .map(|h| h.lines().map(String::from).collect())
+            .unwrap_or_default();
+
+        LineEditor { history, words, interactive: io::stdin().is_terminal() }
+    }
+
+    fn save_history(&self) {
+        if !self.interactive {
+            return;
+        }
+
+        if let Some(path) = history_path() {
+            let start = self.history.len().saturating_sub(HISTORY_LIMIT);
+            let _ = fs::write(path, self.history[start..].join("\n") + "\n");
+        }
+    }
+
+    // Returns None at the end of input (Ctrl-D or a closed pipe)
+    fn read_line(&mut self, prompt: &str) -> Option<String> {
+        let line = if self.interactive {
+            match RawMode::enable() {
+                Some(_raw) => self.edit(prompt),
+                None => read_plain(prompt),
+            }
+        } else {
+            read_plain("")
+        };
+
+        if let Some(l) = &line {
+            if !l.trim().is_empty() && self.history.last() != Some(l) {
+                self.history.push(l.clone());
+            }
+        }
+
+        line
+    }
+
+    fn edit(&mut self, prompt: &str) -> Option<String> {
+        let mut buffer: Vec<char> = Vec::new();
+        let mut cursor = 0;
+        let mut history_index = self.history.len();
+        let mut stdin = io::stdin().lock();
+
+        redraw(prompt, &buffer, cursor);
+
+        loop {
+            let mut byte = [0u8; 1];
+            if stdin.read(&mut byte).ok()? == 0 {
+                return None;
+            }
+
+            match byte[0] {
+                b'\n' | b'\r' => {
+                    println!();
+                    return Some(buffer.into_iter().collect());
+                },
+                // Ctrl-C abandons the line, signals are off while editing
+                3 => {
+                    println!("^C");
+                    buffer.clear();
+                    cursor = 0;
+                    history_index = self.history.len();
+                },
+                // Ctrl-D
+                4 if buffer.is_empty() => {
+                    println!();
+                    return None;
+                },
+                // Backspace
+                8 | 127 if cursor > 0 => {
+                    cursor -= 1;
+                    buffer.remove(cursor);
+                },
+                // Ctrl-A and Ctrl-E
+                1 => cursor = 0,
+                5 => cursor = buffer.len(),
+                // Ctrl-U
+                21 => {
+                    buffer.drain(..cursor);
+                    cursor = 0;
+                },
+                b'\t' => {
+                    self.complete(&mut buffer, &mut cursor, prompt);
+                },
+                // Escape sequences for the arrow keys
+                27 => {
+                    let mut sequence = [0u8; 2];
+                    if stdin.read_exact(&mut sequence).is_err() || sequence[0] != b'[' {
+                        continue;
+                    }
+
+                    match sequence[1] {
+                        b'A' if history_index > 0 => {
+                            history_index -= 1;
+                            buffer = self.history[history_index].chars().collect();
+                            cursor = buffer.len();
+                        },
+                        b'B' if history_index < self.history.len() => {
+                            history_index += 1;
+                            buffer = self.history.get(history_index).map(|h| h.chars().collect()).unwrap_or_default();
+                            cursor = buffer.len();
+                        },
+                        b'C' if cursor < buffer.len() => cursor += 1,
+                        b'D' if cursor > 0 => cursor -= 1,
+                        _ => {}
+                    }
+                },
+                b if b >= 32 => {
+                    let mut bytes = vec![b];
+                    // Pull in the rest of a multi-byte character
+                    let extra = if b >= 0xF0 { 3 } else if b >= 0xE0 { 2 } else if b >= 0xC0 { 1 } else { 0 };
+                    for _ in 0..extra {
+                        let mut next = [0u8; 1];
+                        if stdin.read_exact(&mut next).is_ok() {
+                            bytes.push(next[0]);
+                        }
+                    }
+
+                    for c in String::from_utf8_lossy(&bytes).chars() {
+                        buffer.insert(cursor, c);
+                        cursor += 1;
+                    }
+                },
+                _ => {}
+            }
+
+            redraw(prompt, &buffer, cursor);
+        }
+    }
+
+    /*
+     * Completes the word under the cursor against command names and aliases. A single match is
+     * filled in, several matches are listed and completed as far as they agree.
+     */
+    fn complete(&self, buffer: &mut Vec<char>, cursor: &mut usize, prompt: &str) {
+        let start = buffer[..*cursor].iter().rposition(|c| c.is_whitespace()).map_or(0, |i| i + 1);
+        let partial: String = buffer[start..*cursor].iter().collect();
+
+        let matches: Vec<&String> = self.words.iter().filter(|w| w.starts_with(&partial)).collect();
+
+        let completion = match matches.len() {
+            0 => return,
+            1 => format!("{} ", matches[0]),
+            _ => {
+                println!();
+                println!("{}", matches.iter().map(|m| m.as_str()).collect::<Vec<&str>>().join("  "));
+                redraw(prompt, buffer, *cursor);
+
+                let mut prefix = matches[0].clone();
+                for m in &matches[1..] {
+                    while !m.starts_with(&prefix) {
+                        prefix.pop();
+                    }
+                }
+                prefix
+            }
+        };
+
+        for c in completion.chars().skip(partial.chars().count()) {
+            buffer.insert(*cursor, c);
+            *cursor += 1;
+        }
+    }
+}
+
+fn redraw(prompt: &str, buffer: &[char], cursor: usize) {
+    let line: String = buffer.iter().collect();
+    print!("\r\x1b[K{}{}", prompt, line);
+
+    if cursor < buffer.len() {
+        print!("\x1b[{}D", buffer.len() - cursor);
+    }
+
+    let _ = io::stdout().flush();
+}
+
+fn read_plain(prompt: &str) -> Option<String> {
+    print!("{}", prompt);
+    let _ = io::stdout().flush();
+
+    let mut line = String::new();
+    match io::stdin().lock().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim_end_matches(['\n', '\r']).to_string()),
+    }
+}
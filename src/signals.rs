@@ -0,0 +1,50 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/*
+ * SIGTERM and SIGINT handling for the commands that run until they are stopped. The handler only sets
+ * a flag, which the command checks between ticks so it can finish a write and flush before exiting.
+ */
+
+static STOP: AtomicBool = AtomicBool::new(false);
+
+const SIGINT: i32 = 2;
+const SIGTERM: i32 = 15;
+
+const SLEEP_STEP: Duration = Duration::from_millis(100);
+
+extern "C" {
+    fn signal(signum: i32, handler: usize) -> usize;
+}
+
+extern "C" fn request_stop(_: i32) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+pub fn install() {
+    // Safe as request_stop only stores to an atomic, which is allowed in a signal handler
+    unsafe {
+        signal(SIGTERM, request_stop as extern "C" fn(i32) as usize);
+        signal(SIGINT, request_stop as extern "C" fn(i32) as usize);
+    }
+}
+
+pub fn stop_requested() -> bool {
+    STOP.load(Ordering::SeqCst)
+}
+
+// Sleeps for duration unless a stop is requested first. Returns whether to keep going.
+pub fn sleep(duration: Duration) -> bool {
+    let end = Instant::now() + duration;
+
+    while !stop_requested() {
+        let now = Instant::now();
+        if now >= end {
+            return true;
+        }
+        thread::sleep(SLEEP_STEP.min(end - now));
+    }
+
+    false
+}
@@ -0,0 +1,247 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+use crate::config;
+use crate::executor::Environment;
+
+/*
+ * Machine-wide state, shared by every user's runs, the daemon, the helper and the exporter, kept in
+ * state_dir (/var/lib/teamgreenhelper). Root creates the directory and its files. Files that every
+ * user has to update, such as the failure counts, are created writable by all.
+ *
+ * Updates take an exclusive flock for the whole read and rewrite, so runs that update the same file
+ * at the same time do not lose each other's changes.
+ */
+
+pub const DEFAULT_DIR: &str = "/var/lib/teamgreenhelper";
+
+const LOCK_EX: i32 = 2;
+
+extern "C" {
+    fn flock(fd: i32, operation: i32) -> i32;
+}
+
+pub fn dir(env: &Environment) -> PathBuf {
+    PathBuf::from(env.config.get("state_dir"))
+}
+
+pub fn path(env: &Environment, name: &str) -> PathBuf {
+    dir(env).join(name)
+}
+
+// Blocks until no other process holds file's lock. The lock is released when file is closed.
+pub fn lock(file: &File) -> io::Result<()> {
+    // Safe as the descriptor belongs to file, which outlives the call
+    if unsafe { flock(file.as_raw_fd(), LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+pub fn read(env: &Environment, name: &str) -> String {
+    fs::read_to_string(path(env, name)).unwrap_or_default()
+}
+
+/*
+ * Replaces the contents of name with change(contents) under the lock. A missing file is created with
+ * mode, which only works for whoever may write to the state directory.
+ */
+pub fn update(env: &Environment, name: &str, mode: u32, change: impl FnOnce(&str) -> String) -> io::Result<()> {
+    let dir = dir(env);
+    if !dir.exists() {
+        fs::DirBuilder::new().recursive(true).mode(0o755).create(&dir)?;
+    }
+
+    let path = dir.join(name);
+    let created = !path.exists();
+    let mut file = OpenOptions::new().read(true).write(true).create(true).mode(mode).open(&path)?;
+
+    // The mode given to open is masked by the umask
+    if created {
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+    }
+
+    lock(&file)?;
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let changed = change(&contents);
+
+    file.seek(SeekFrom::Start(0))?;
+    file.set_len(0)?;
+    file.write_all(changed.as_bytes())
+}
+
+#[derive(Clone, Copy)]
+pub enum Lock {
+    Core,
+    Memory,
+}
+
+impl Lock {
+    fn key(&self) -> &'static str {
+        match self {
+            Lock::Core => "core_mhz",
+            Lock::Memory => "memory_mhz",
+        }
+    }
+}
+
+/*
+ * The driver does not report locked clocks, so the lock setters record what they set in locks.toml
+ * and the resets clear it. The settings that reset puts back rather than to a default keep what
+ * they were before they were first changed in originals.toml. Neither survives a reboot, so both
+ * records hold the boot they were made in. Every user and the daemon may write them, like the
+ * exporter's failures, so whatever acts on a value as root checks it first.
+ */
+const LOCKS: &str = "locks.toml";
+const ORIGINALS: &str = "originals.toml";
+
+fn boot_id() -> String {
+    fs::read_to_string("/proc/sys/kernel/random/boot_id").map(|id| id.trim().to_string()).unwrap_or_default()
+}
+
+// The (key, value) pairs of a record of this boot, e.g. ("gpu0.core_mhz", "1800")
+fn parse_boot_record(contents: &str) -> Vec<(String, String)> {
+    let pairs = config::parse_toml(contents).unwrap_or_default();
+
+    if !pairs.iter().any(|(k, v)| k == "boot_id" && *v == boot_id()) {
+        return Vec::new();
+    }
+
+    pairs.into_iter().filter(|(k, _)| k.starts_with("gpu")).collect()
+}
+
+/*
+ * Sets key to value in the record name, or removes it when value is None. With keep an existing
+ * value is not replaced. Failing to write a record only costs what it is read for later, so the
+ * error is not passed on.
+ */
+fn record(env: &Environment, name: &str, title: &str, key: String, value: Option<String>, keep: bool) {
+    let _ = update(env, name, 0o666, |contents| {
+        let mut pairs = parse_boot_record(contents);
+        if keep && pairs.iter().any(|(k, _)| *k == key) {
+            return contents.to_string();
+        }
+
+        pairs.retain(|(k, _)| *k != key);
+        if let Some(value) = value {
+            pairs.push((key, value));
+        }
+        pairs.sort();
+
+        let mut contents = format!("# Written by teamgreenhelper, {}\nboot_id = \"{}\"\n", title, boot_id());
+        let mut section = String::new();
+        for (key, value) in pairs {
+            if let Some((gpu, field)) = key.split_once('.') {
+                if gpu != section {
+                    contents.push_str(&format!("\n[{}]\n", gpu));
+                    section = gpu.to_string();
+                }
+                contents.push_str(&format!("{} = \"{}\"\n", field, value));
+            }
+        }
+        contents
+    });
+}
+
+pub fn recorded_locks(env: &Environment) -> Vec<(String, String)> {
+    parse_boot_record(&read(env, LOCKS))
+}
+
+// Only a clock is a lock, as every user can write the record
+pub fn recorded_lock(locks: &[(String, String)], gpu: usize, lock: Lock) -> Option<String> {
+    let key = format!("gpu{}.{}", gpu, lock.key());
+    locks.iter().find(|(k, _)| *k == key).map(|(_, v)| v.clone()).filter(|v| v.parse::<usize>().is_ok())
+}
+
+// The clock locked on gpu since boot, if any
+pub fn locked_clock(env: &Environment, gpu: usize, lock: Lock) -> Option<usize> {
+    recorded_lock(&recorded_locks(env), gpu, lock).and_then(|v| v.parse::<usize>().ok())
+}
+
+// Called by the lock setters once a lock took effect, and by the resets with None
+pub fn record_lock(env: &Environment, gpu: usize, lock: Lock, mhz: Option<usize>) {
+    record(env, LOCKS, "the clocks locked since boot", format!("gpu{}.{}", gpu, lock.key()), mhz.map(|m| m.to_string()), false);
+}
+
+// What setting (e.g. persistence) of gpu was before it was first changed this boot
+pub fn original(env: &Environment, gpu: usize, setting: &str) -> Option<String> {
+    let key = format!("gpu{}.{}", gpu, setting);
+    parse_boot_record(&read(env, ORIGINALS)).into_iter().find(|(k, _)| *k == key).map(|(_, v)| v)
+}
+
+// Keeps value only if setting has no original yet, and forgets it when value is None
+pub fn record_original(env: &Environment, gpu: usize, setting: &str, value: Option<String>) {
+    let keep = value.is_some();
+    record(env, ORIGINALS, "the settings as they were before it first changed them", format!("gpu{}.{}", gpu, setting), value, keep);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::private_temp_dir;
+    use std::thread;
+
+    fn environment(dir: &std::path::Path) -> Environment {
+        let mut env = Environment::default();
+        env.config.set("state_dir", &dir.to_string_lossy(), crate::config::Source::CommandLine);
+        env
+    }
+
+    #[test]
+    fn concurrent_updates_are_not_lost() {
+        let dir = private_temp_dir("teamgreenhelper-state").unwrap();
+
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let env = environment(&dir);
+                    for _ in 0..20 {
+                        update(&env, "count", 0o644, |c| (c.trim().parse::<u32>().unwrap_or(0) + 1).to_string()).unwrap();
+                    }
+                });
+            }
+        });
+
+        let env = environment(&dir);
+        assert_eq!(read(&env, "count"), "160");
+        assert_eq!(fs::metadata(path(&env, "count")).unwrap().permissions().mode() & 0o777, 0o644);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn boot_records_are_shared_and_forgotten_after_a_reboot() {
+        let dir = private_temp_dir("teamgreenhelper-state").unwrap();
+        let env = environment(&dir);
+
+        record_lock(&env, 0, Lock::Core, Some(1800));
+        record_lock(&env, 1, Lock::Memory, Some(5001));
+        record_lock(&env, 0, Lock::Memory, Some(7000));
+        record_lock(&env, 0, Lock::Memory, None);
+
+        assert_eq!(locked_clock(&env, 0, Lock::Core), Some(1800));
+        assert_eq!(locked_clock(&env, 0, Lock::Memory), None);
+        assert_eq!(locked_clock(&environment(&dir), 1, Lock::Memory), Some(5001));
+        assert_eq!(fs::metadata(path(&env, LOCKS)).unwrap().permissions().mode() & 0o777, 0o666);
+
+        record_original(&env, 0, "persistence", Some(String::from("Enabled")));
+        record_original(&env, 0, "persistence", Some(String::from("Disabled")));
+        assert_eq!(original(&env, 0, "persistence").as_deref(), Some("Enabled"));
+        record_original(&env, 0, "persistence", None);
+        assert_eq!(original(&env, 0, "persistence"), None);
+
+        let planted = read(&env, LOCKS).replace("core_mhz = \"1800\"", "core_mhz = \"1800; id\"");
+        fs::write(path(&env, LOCKS), planted).unwrap();
+        assert_eq!(recorded_lock(&recorded_locks(&env), 0, Lock::Core), None);
+
+        let other_boot = read(&env, LOCKS).replace(&boot_id(), "another-boot");
+        fs::write(path(&env, LOCKS), other_boot).unwrap();
+        assert_eq!(locked_clock(&env, 0, Lock::Core), None);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,159 @@
+use std::io::{self, IsTerminal};
+
+use crate::executor::{Environment, OutputFormat};
+use crate::state::{self, Lock};
+use crate::{json, nvidiagpu};
+
+/*
+ * status answers "what have I changed on this box?": every tunable of every GPU next to its
+ * default, with the ones that differ marked.
+ *
+ * The driver does not report locked clocks, so the lock rows come from the record that lock_core and
+ * lock_memory keep in the machine-wide state_dir (see state). Locks set with nvidia-smi directly are
+ * not known.
+ *
+ * status only looks, so the offset and fan rows need an X server that is already running.
+ */
+
+const SMI_FIELDS: &str = "name,power.limit,power.default_limit,persistence_mode,clocks.applications.graphics,\
+    clocks.default_applications.graphics,clocks.applications.memory,clocks.default_applications.memory,fan.speed,compute_mode";
+
+struct Row {
+    setting: String,
+    default: String,
+    current: String,
+    changed: bool,
+}
+
+impl Row {
+    fn new(setting: &str, default: String, current: String) -> Row {
+        let changed = default != current && current != "?";
+        Row { setting: setting.to_string(), default, current, changed }
+    }
+}
+
+fn performance_levels(env: &Environment, gpu: usize) -> usize {
+    nvidiagpu::parse_perf_modes(&nvidiagpu::query_setting_text(env, &format!("gpu:{}", gpu), "GPUPerfModes")).len()
+}
+
+fn setting(env: &Environment, target: &str, attribute: &str) -> String {
+    nvidiagpu::query_setting(env, target, attribute).first().map_or(String::from("?"), |v| v.to_string())
+}
+
+fn mhz(value: &str) -> String {
+    match value {
+        "" | "[N/A]" | "N/A" => String::from("?"),
+        v => format!("{} MHz", v),
+    }
+}
+
+fn rows(env: &Environment, gpu: usize, values: &[String], locks: &[(String, String)], x_available: bool) -> Vec<Row> {
+    let lock = |lock: Lock| state::recorded_lock(locks, gpu, lock).map_or(String::from("none"), |v| mhz(&v));
+
+    let mut rows = vec![
+        Row::new("Locked Core Clock", String::from("none"), lock(Lock::Core)),
+        Row::new("Locked Memory Clock", String::from("none"), lock(Lock::Memory)),
+    ];
+
+    if x_available {
+        let target = format!("gpu:{}", gpu);
+        for level in 0..performance_levels(env, gpu) {
+            rows.push(Row::new(&format!("Clock Offset P{}", level), String::from("0"), setting(env, &target, &format!("GPUGraphicsClockOffset[{}]", level))));
+            rows.push(Row::new(&format!("Memory Offset P{}", level), String::from("0"), setting(env, &target, &format!("GPUMemoryTransferRateOffset[{}]", level))));
+        }
+
+        let manual = nvidiagpu::query_setting(env, &target, "GPUFanControlState").first().map(|state| *state == 1.0);
+        let control = match manual {
+            Some(true) => String::from("manual"),
+            Some(false) => String::from("auto"),
+            None => String::from("?"),
+        };
+        rows.push(Row::new("Fan Control", String::from("auto"), control));
+    }
+
+    let power = |v: &str| v.parse::<f64>().map_or(String::from("?"), |w| format!("{} W", w));
+    rows.push(Row::new("Power Limit", power(&values[2]), power(&values[1])));
+    rows.push(Row::new("Persistence Mode", String::from("Disabled"), values[3].clone()));
+    rows.push(Row::new("Compute Mode", String::from("Default"), values[9].clone()));
+    rows.push(Row::new("Applications Clock", mhz(&values[5]), mhz(&values[4])));
+    rows.push(Row::new("Applications Memory", mhz(&values[7]), mhz(&values[6])));
+    rows
+}
+
+// Bold when printed to a terminal, so the changes stand out
+fn highlight(text: String) -> String {
+    if io::stdout().is_terminal() { format!("\x1b[1m{}\x1b[0m", text) } else { text }
+}
+
+fn print_gpu(gpu: usize, name: &str, fan_speed: &str, rows: &[Row]) {
+    println!("GPU {}: {}", gpu, name);
+    println!("  {:<22} {:<14} CURRENT", "SETTING", "DEFAULT");
+
+    for row in rows {
+        let line = format!("  {:<22} {:<14} {:<14}", row.setting, row.default, row.current);
+        if row.changed {
+            println!("{}", highlight(format!("{} *", line)));
+        } else {
+            println!("{}", line.trim_end());
+        }
+    }
+
+    println!("  Fan speed is {}%.\n", fan_speed);
+}
+
+pub fn run(env: &mut Environment) -> Result<(), String> {
+    let gpus = nvidiagpu::query_all_gpus(env, SMI_FIELDS).map_err(|e| format!("Failed to read the GPUs. {}", e))?;
+
+    // Without an X server the nvidia-settings rows are left out, starting one would be a change
+    let x_available = env.x_found || env.headless_server.is_some();
+    if !x_available && env.output != OutputFormat::Json {
+        println!("Offsets and fan control are not shown, as no X server is running.\n");
+    }
+
+    let locks = state::recorded_locks(env);
+    let mut items = Vec::new();
+    let mut changes = 0;
+
+    for row in gpus.iter().filter(|r| r.len() == 11) {
+        let gpu = match row[0].parse::<usize>() {
+            Ok(gpu) => gpu,
+            Err(_) => continue,
+        };
+        let rows = rows(env, gpu, &row[1..], &locks, x_available);
+        changes += rows.iter().filter(|r| r.changed).count();
+
+        if env.output == OutputFormat::Json {
+            let settings: Vec<String> = rows.iter()
+                .map(|r| json::Object::new()
+                    .string("setting", &r.setting)
+                    .string("default", &r.default)
+                    .string("current", &r.current)
+                    .raw("changed", r.changed.to_string())
+                    .build())
+                .collect();
+            items.push(json::Object::new().raw("gpu", gpu.to_string()).string("name", &row[1]).raw("settings", json::array(&settings)).build());
+        } else {
+            print_gpu(gpu, &row[1], &row[9], &rows);
+        }
+    }
+
+    if env.output == OutputFormat::Json {
+        println!("{}", json::array(&items));
+        return Ok(());
+    }
+
+    if x_available {
+        let targets = nvidiagpu::query_setting(env, "", "GPUTargetFanSpeed");
+        if !targets.is_empty() {
+            let fans: Vec<String> = targets.iter().enumerate().map(|(i, t)| format!("fan {} {}%", i, t)).collect();
+            println!("Fan targets (used under manual control): {}\n", fans.join(", "));
+        }
+    }
+
+    match changes {
+        0 => println!("Every setting is at its default."),
+        n => println!("* {} setting(s) differ from the default.", n),
+    }
+
+    Ok(())
+}
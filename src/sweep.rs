@@ -0,0 +1,322 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::executor::Environment;
+use crate::{daemon, debug_message, nvidiagpu, signals};
+
+/*
+ * sweep measures performance per watt across power limits of the selected GPU:
+ *
+ *   sweep power 150..350 step 25 --bench "./bench --quick" (--score "Score: ([0-9.]+)") (--out sweep.csv)
+ *
+ * For each limit the benchmark is run while power draw is sampled, and its score is read from its
+ * output with the --score regex, whose first group is the score. The regex is applied with sed -E,
+ * so it is a POSIX extended regex, and need not match the whole line. The original limit is
+ * restored at the end, also when the sweep fails or is stopped.
+ */
+
+const DEFAULT_STEP: f64 = 25.0;
+const DEFAULT_SCORE: &str = "[Ss]core[^0-9]*([0-9]+(\\.[0-9]+)?)";
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+struct Options {
+    from: f64,
+    to: f64,
+    step: f64,
+    bench: String,
+    score: String,
+    out: Option<String>,
+}
+
+struct Row {
+    limit: f64,
+    score: f64,
+    watts: f64,
+}
+
+impl Row {
+    fn efficiency(&self) -> f64 {
+        if self.watts > 0.0 { self.score / self.watts } else { 0.0 }
+    }
+}
+
+fn watts(value: &str) -> Result<f64, String> {
+    value.trim_end_matches(['W', 'w']).parse::<f64>().ok().filter(|w| *w > 0.0)
+        .ok_or_else(|| format!("'{}' is not a number of watts.", value))
+}
+
+fn parse_options(args: &[&String]) -> Result<Options, String> {
+    let usage = || String::from("Usage: sweep power [from]..[to] (step [watts]) --bench [command] (--score [regex]) (--out [file])");
+
+    let (setting, range, rest) = match args {
+        [setting, range, rest @ ..] => (setting.as_str(), range.as_str(), rest),
+        _ => return Err(usage()),
+    };
+
+    if setting != "power" {
+        return Err(format!("'{}' cannot be swept, only power can.", setting));
+    }
+
+    let (from, to) = range.split_once("..").ok_or_else(usage)?;
+    let mut options = Options { from: watts(from)?, to: watts(to)?, step: DEFAULT_STEP, bench: String::new(), score: DEFAULT_SCORE.to_string(), out: None };
+
+    for pair in rest.chunks(2) {
+        let (flag, value) = match pair {
+            [flag, value] => (flag.as_str(), value.as_str()),
+            _ => return Err(format!("'{}' needs a value.", pair[0])),
+        };
+
+        match flag {
+            "step" | "--step" => options.step = watts(value)?,
+            "--bench" => options.bench = value.to_string(),
+            "--score" => options.score = value.to_string(),
+            "--out" => options.out = Some(value.to_string()),
+            _ => return Err(format!("'{}' is not a sweep option. Expected step, --bench, --score or --out.", flag)),
+        }
+    }
+
+    if options.bench.is_empty() {
+        return Err(String::from("sweep needs a benchmark to run, e.g. --bench \"./bench --quick\""));
+    }
+    if options.from > options.to {
+        return Err(format!("The sweep goes up, {} W is above {} W.", options.from, options.to));
+    }
+
+    Ok(options)
+}
+
+/*
+ * The first group of regex in the last line of output that matches it. sed marks the group of the
+ * leftmost match with \x02 on each side, rather than replacing the whole line with it, as a .* in
+ * front of the regex would take all it could from the group, e.g. 3 of 123.
+ */
+fn read_score(output: &str, regex: &str) -> Result<f64, String> {
+    // \x01 as the delimiter, as a regex is unlikely to contain it
+    let mut sed = Command::new("sed").arg("-nE").arg(format!("s\x01{}\x01\x02\\1\x02\x01p", regex))
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()
+        .map_err(|e| format!("sed could not be run: {}", e))?;
+
+    if let Some(mut stdin) = sed.stdin.take() {
+        let _ = stdin.write_all(output.as_bytes());
+    }
+
+    let result = sed.wait_with_output().map_err(|e| format!("sed failed: {}", e))?;
+    if !result.status.success() {
+        return Err(format!("the score regex is invalid: {}", String::from_utf8_lossy(&result.stderr).trim()));
+    }
+
+    let matched = String::from_utf8_lossy(&result.stdout);
+    match matched.lines().last().and_then(|line| line.split('\x02').nth(1)) {
+        Some(score) => score.trim().parse::<f64>().map_err(|_| format!("the score '{}' is not a number", score.trim())),
+        None => Err(format!("no line of the output matched '{}'", regex)),
+    }
+}
+
+// Runs the benchmark, sampling power draw until it exits. Returns the score and average draw.
+fn measure(env: &Environment, gpu: &usize, options: &Options) -> Result<(f64, f64), String> {
+    let mut child = Command::new("sh").arg("-c").arg(&options.bench).env("TGH_GPU", gpu.to_string())
+        .stdout(Stdio::piped()).stderr(Stdio::null()).spawn()
+        .map_err(|e| format!("The benchmark could not be run. Error: {}", e))?;
+
+    // Read on a thread so a chatty benchmark never blocks on a full pipe
+    let mut stdout = child.stdout.take();
+    let reader = thread::spawn(move || {
+        let mut output = String::new();
+        if let Some(out) = stdout.as_mut() {
+            let _ = out.read_to_string(&mut output);
+        }
+        output
+    });
+
+    let mut samples = Vec::new();
+    let status = loop {
+        if let Some(draw) = nvidiagpu::query_gpu_number(env, gpu, "power.draw") {
+            samples.push(draw);
+        }
+
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) => thread::sleep(SAMPLE_INTERVAL),
+            Err(e) => return Err(format!("Lost track of the benchmark. Error: {}", e)),
+        }
+    };
+
+    let output = reader.join().unwrap_or_default();
+    if !status.success() {
+        return Err(format!("The benchmark exited with {}.", status));
+    }
+
+    let score = read_score(&output, &options.score).map_err(|e| format!("No score was found, {}.", e))?;
+    let average = if samples.is_empty() { 0.0 } else { samples.iter().sum::<f64>() / samples.len() as f64 };
+    Ok((score, average))
+}
+
+/*
+ * The efficiency knee, where adding power stops paying off: with score and power scaled to 0..1
+ * between the lowest and highest readings, the row furthest above the straight line between them.
+ */
+fn knee(rows: &[Row]) -> Option<usize> {
+    let span = |f: fn(&Row) -> f64| {
+        let values: Vec<f64> = rows.iter().map(f).collect();
+        let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        (min, (max - min).max(f64::EPSILON))
+    };
+    let (min_score, score_range) = span(|r| r.score);
+    let (min_watts, watts_range) = span(|r| r.watts);
+
+    rows.iter().enumerate()
+        .map(|(i, r)| (i, (r.score - min_score) / score_range - (r.watts - min_watts) / watts_range))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(i, _)| i)
+}
+
+fn csv(rows: &[Row]) -> String {
+    let mut out = String::from("limit_watts,score,power_draw_watts,score_per_watt\n");
+    for r in rows {
+        out.push_str(&format!("{},{},{:.2},{:.4}\n", r.limit, r.score, r.watts, r.efficiency()));
+    }
+    out
+}
+
+fn report(rows: &[Row], out: &Option<String>) -> Result<(), String> {
+    let knee = knee(rows);
+
+    println!("\n{:>8} {:>10} {:>12} {:>10}", "LIMIT", "DRAW", "SCORE", "SCORE/W");
+    for (i, r) in rows.iter().enumerate() {
+        println!("{:>6} W {:>8.1} W {:>12} {:>10.3}{}", r.limit, r.watts, r.score, r.efficiency(), if Some(i) == knee { "  <- knee" } else { "" });
+    }
+
+    let best = rows.iter().map(|r| r.score).fold(0.0, f64::max);
+    if let Some(r) = knee.map(|i| &rows[i]) {
+        println!("\nRecommended: power {}W, {:.0}% of the best score at {:.3} score/W.", r.limit, r.score / best.max(f64::EPSILON) * 100.0, r.efficiency());
+    }
+
+    match out {
+        Some(path) => {
+            fs::write(path, csv(rows)).map_err(|e| format!("Failed to write {}. Error: {}", path, e))?;
+            println!("Wrote {}.", path);
+        },
+        None => print!("\n{}", csv(rows)),
+    }
+
+    Ok(())
+}
+
+pub fn run(env: &Environment, gpu: &usize, args: &[&String]) -> Result<(), String> {
+    let options = parse_options(args)?;
+
+    if let Some(max) = env.limits.max_power {
+        if options.to > max {
+            return Err(format!("The sweep goes up to {} W, above the configured limit of {} W (limits.max_power).", options.to, max));
+        }
+    }
+
+    let original = nvidiagpu::query_gpu_number(env, gpu, "power.limit").ok_or_else(|| format!("Could not read the power limit of GPU {}.", gpu))?;
+    signals::install();
+
+    let mut rows = Vec::new();
+    let mut failure = None;
+    let mut limit = options.from;
+
+    while limit <= options.to + f64::EPSILON && !signals::stop_requested() {
+        print!("{} W: ", limit);
+        let _ = std::io::stdout().flush();
+        let started = Instant::now();
+
+        let mut index = *gpu;
+        let result = daemon::set_through(env, *gpu, "power", format!("{}W", limit.round()),
+                                         || debug_message(env, nvidiagpu::set_power_limit(env, &mut index, limit.round() as usize), "Power Limit"))
+            .and_then(|_| measure(env, gpu, &options));
+
+        match result {
+            Ok((score, watts)) => {
+                println!("score {} at {:.1} W in {:.0?}", score, watts, started.elapsed());
+                rows.push(Row { limit, score, watts });
+            },
+            Err(e) => {
+                println!("failed");
+                failure = Some(e);
+                break;
+            },
+        }
+
+        limit += options.step;
+    }
+
+    let mut index = *gpu;
+    let restored = daemon::set_through(env, *gpu, "power", format!("{}W", original.round()),
+                                       || debug_message(env, nvidiagpu::set_power_limit(env, &mut index, original.round() as usize), "Power Limit"));
+    match &restored {
+        Ok(()) => println!("Restored the power limit to {} W.", original),
+        Err(e) => println!("{}", e),
+    }
+
+    if !rows.is_empty() {
+        report(&rows, &options.out)?;
+    }
+
+    match failure {
+        Some(e) => Err(format!("The sweep stopped early. {}", e)),
+        None => restored,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(args: &[&str]) -> Result<Options, String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        parse_options(&args.iter().collect::<Vec<&String>>())
+    }
+
+    fn row(limit: f64, score: f64, watts: f64) -> Row {
+        Row { limit, score, watts }
+    }
+
+    #[test]
+    fn options_need_a_rising_power_range_and_a_benchmark() {
+        let parsed = options(&["power", "150W..350", "step", "50", "--bench", "./bench", "--out", "sweep.csv"]).unwrap();
+        assert_eq!((parsed.from, parsed.to, parsed.step), (150.0, 350.0, 50.0));
+        assert_eq!((parsed.bench.as_str(), parsed.out.as_deref(), parsed.score.as_str()), ("./bench", Some("sweep.csv"), DEFAULT_SCORE));
+
+        assert!(options(&["clock", "150..350", "--bench", "./bench"]).is_err());
+        assert!(options(&["power", "350..150", "--bench", "./bench"]).is_err());
+        assert!(options(&["power", "150..350"]).is_err());
+        assert!(options(&["power", "150-350", "--bench", "./bench"]).is_err());
+        assert!(options(&["power", "150..350", "--bench"]).is_err());
+    }
+
+    #[test]
+    fn score_is_the_group_of_the_last_matching_line() {
+        let output = "warmup\nScore: 98.5\nfinal: 1234.5 fps (3 runs)\nScore: 101\ndone\n";
+
+        assert_eq!(read_score(output, DEFAULT_SCORE), Ok(101.0));
+        // The whole number, not the digits a greedy .* in front would leave
+        assert_eq!(read_score(output, "([0-9.]+) fps"), Ok(1234.5));
+        assert!(read_score(output, "latency ([0-9]+)").is_err());
+        assert!(read_score(output, "(unclosed").is_err());
+    }
+
+    #[test]
+    fn knee_is_where_more_power_stops_paying_off() {
+        let rows = [row(150.0, 60.0, 150.0), row(200.0, 90.0, 200.0), row(250.0, 97.0, 250.0), row(300.0, 100.0, 300.0)];
+        assert_eq!(knee(&rows), Some(1));
+
+        // Where the score stops rising altogether, the knee is the first row at the top
+        let flat = [row(150.0, 60.0, 150.0), row(200.0, 100.0, 200.0), row(250.0, 100.0, 250.0)];
+        assert_eq!(knee(&flat), Some(1));
+        assert_eq!(knee(&[]), None);
+    }
+
+    #[test]
+    fn csv_has_one_row_per_limit_with_its_efficiency() {
+        let rows = [row(150.0, 60.0, 149.5), row(200.0, 90.0, 0.0)];
+
+        assert_eq!(csv(&rows), "limit_watts,score,power_draw_watts,score_per_watt\n150,60,149.50,0.4013\n200,90,0.00,0.0000\n");
+    }
+}
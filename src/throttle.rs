@@ -0,0 +1,216 @@
+use crate::executor::{Environment, OutputFormat};
+use crate::json;
+use crate::nvidiagpu;
+
+/*
+ * Decodes clocks_throttle_reasons, nvidia-smi's record of why the GPU is running below its target
+ * clock. info shows which reasons are active and why-slow explains them, with the setter that would
+ * lift each one.
+ */
+
+pub struct Reason {
+    // The field under clocks_throttle_reasons
+    pub field: &'static str,
+    pub label: &'static str,
+}
+
+pub const REASONS: [Reason; 7] = [
+    Reason { field: "sw_power_cap", label: "Power Cap" },
+    Reason { field: "sw_thermal_slowdown", label: "Thermal Slowdown" },
+    Reason { field: "hw_thermal_slowdown", label: "HW Thermal Slowdown" },
+    Reason { field: "hw_slowdown", label: "HW Slowdown" },
+    Reason { field: "sync_boost", label: "Sync Boost" },
+    Reason { field: "gpu_idle", label: "Idle" },
+    Reason { field: "applications_clocks_setting", label: "Applications Clocks Setting" },
+];
+
+// The REASONS fields for --query-gpu, in order
+pub fn query_fields() -> String {
+    REASONS.iter().map(|r| format!("clocks_throttle_reasons.{}", r.field)).collect::<Vec<String>>().join(",")
+}
+
+// The reasons whose value nvidia-smi reports as Active, given the values in the order of REASONS
+pub fn active<'a>(values: &[&str]) -> Vec<&'a Reason> {
+    REASONS.iter().zip(values).filter(|(_, v)| v.trim() == "Active").map(|(r, _)| r).collect()
+}
+
+// A comma separated list of the active reasons for info
+pub fn describe(values: &[&str]) -> String {
+    match active(values).iter().map(|r| r.label).collect::<Vec<&str>>() {
+        labels if labels.is_empty() => String::from("None"),
+        labels => labels.join(", "),
+    }
+}
+
+const WHY_FIELDS: &str = "clocks.current.graphics,clocks.max.graphics,clocks.applications.graphics,temperature.gpu,power.draw,\
+    enforced.power.limit,power.max_limit";
+
+struct Readings {
+    clock: String,
+    max_clock: String,
+    applications_clock: String,
+    temperature: String,
+    power_draw: String,
+    power_limit: Option<f64>,
+    max_power_limit: Option<f64>,
+}
+
+// What the reason means for this GPU right now, and how to lift it if anything here can
+fn explain(reason: &Reason, r: &Readings) -> (String, Option<String>) {
+    match reason.field {
+        "sw_power_cap" => {
+            let explanation = format!("The GPU is drawing {} W against its {} W power limit, so the driver lowers clocks to stay under it.",
+                                      r.power_draw, r.power_limit.map_or(String::from("?"), |l| l.to_string()));
+            let remedy = match (r.power_limit, r.max_power_limit) {
+                (Some(limit), Some(max)) if limit < max => format!("Raise the limit with 'power {}W', the most this GPU allows.", max),
+                (Some(_), Some(_)) => String::from("The limit is already the most this GPU allows. A lower clock with 'clock' or \
+                                                    'clockoffset' gets more work out of the same power."),
+                _ => String::from("Raise the limit with 'power', up to the GPU's power.max_limit."),
+            };
+            (explanation, Some(remedy))
+        },
+        "sw_thermal_slowdown" => (
+            format!("At {} C the GPU is over its target temperature, so the driver lowers clocks to cool it down.", r.temperature),
+            Some(String::from("Raise the fans with 'fan 0 100', or lower the heat with 'power'.")),
+        ),
+        "hw_thermal_slowdown" => (
+            format!("At {} C the GPU is hot enough that the hardware is cutting its clocks hard.", r.temperature),
+            Some(String::from("Raise the fans with 'fan 0 100' and check the cooling. 'power' lowers the heat while that is sorted.")),
+        ),
+        "hw_slowdown" => (
+            String::from("The hardware is cutting clocks, because it is too hot or the power supply signalled a power brake."),
+            Some(String::from("No setting lifts this. Check the cooling and the power cables to the GPU.")),
+        ),
+        "sync_boost" => (
+            String::from("The GPU is held to the clock of the slowest GPU in its sync boost group."),
+            None,
+        ),
+        "gpu_idle" => (
+            String::from("Nothing is running on the GPU, so it has clocked down. It clocks up again as soon as work arrives."),
+            None,
+        ),
+        _ => (
+            format!("Applications clocks hold the core at {} MHz.", r.applications_clock),
+            Some(String::from("Reset them with 'appclocks -1', or list the supported pairs with 'appclocks list'.")),
+        ),
+    }
+}
+
+// The readings and active reasons from the WHY_FIELDS values followed by the REASONS values
+fn parse_why(values: &[String]) -> Option<(Readings, Vec<&'static Reason>)> {
+    let count = WHY_FIELDS.split(',').count();
+    if values.len() != count + REASONS.len() {
+        return None;
+    }
+
+    let readings = Readings {
+        clock: values[0].to_string(),
+        max_clock: values[1].to_string(),
+        applications_clock: values[2].to_string(),
+        temperature: values[3].to_string(),
+        power_draw: values[4].to_string(),
+        power_limit: values[5].parse::<f64>().ok(),
+        max_power_limit: values[6].parse::<f64>().ok(),
+    };
+    let reasons: Vec<&str> = values[count..].iter().map(|v| v.as_str()).collect();
+
+    Some((readings, active(&reasons)))
+}
+
+/*
+ * why-slow explains every active throttle reason of the selected GPU in plain language, and which
+ * setter would lift it.
+ */
+pub fn why_slow(env: &Environment, gpu: &usize) -> Result<(), String> {
+    let rows = nvidiagpu::query_all_gpus(env, &format!("{},{}", WHY_FIELDS, query_fields()))
+        .map_err(|e| format!("Failed to read the throttle reasons of GPU {}. {}", gpu, e))?;
+
+    let (readings, reasons) = match rows.iter().find(|row| row[0] == gpu.to_string()).and_then(|row| parse_why(&row[1..])) {
+        Some(parsed) => parsed,
+        None => return Err(format!("Failed to read the throttle reasons of GPU {}. nvidia-smi did not list it.", gpu)),
+    };
+
+    if env.output == OutputFormat::Json {
+        let items: Vec<String> = reasons.iter().map(|reason| {
+            let (explanation, remedy) = explain(reason, &readings);
+            json::Object::new()
+                .string("reason", reason.field)
+                .string("explanation", &explanation)
+                .raw("remedy", remedy.as_deref().map_or(String::from("null"), json::string))
+                .build()
+        }).collect();
+        println!("{}", json::Object::new().raw("gpu", gpu.to_string()).string("clock", &readings.clock)
+            .string("max_clock", &readings.max_clock).raw("reasons", json::array(&items)).build());
+        return Ok(());
+    }
+
+    println!("GPU {} is running at {} MHz of a possible {} MHz.", gpu, readings.clock, readings.max_clock);
+
+    if reasons.is_empty() {
+        println!("No throttle reason is active, so the clock is what the current load asks for. A locked clock ('clock -1' removes it) or a \
+                  negative 'clockoffset' would also keep it lower.");
+        return Ok(());
+    }
+
+    for reason in reasons {
+        let (explanation, remedy) = explain(reason, &readings);
+        println!("\n{}: {}", reason.label, explanation);
+        if let Some(remedy) = remedy {
+            println!("  {}", remedy);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One row of --query-gpu=WHY_FIELDS,query_fields() after the index
+    fn row(line: &str) -> Vec<String> {
+        line.split(',').map(|v| v.trim().to_string()).collect()
+    }
+
+    #[test]
+    fn active_reasons_follow_the_order_of_the_fields() {
+        let values = ["Active", "Not Active", "Not Active", "Not Active", "Not Active", "Not Active", " Active"];
+
+        assert_eq!(active(&values).iter().map(|r| r.field).collect::<Vec<&str>>(), vec!["sw_power_cap", "applications_clocks_setting"]);
+        assert_eq!(describe(&values), "Power Cap, Applications Clocks Setting");
+        assert_eq!(describe(&["Not Active"; 7]), "None");
+        assert_eq!(describe(&["[N/A]"; 7]), "None");
+        assert!(query_fields().starts_with("clocks_throttle_reasons.sw_power_cap,"));
+        assert_eq!(query_fields().split(',').count(), REASONS.len());
+    }
+
+    #[test]
+    fn a_power_capped_row_explains_how_far_the_limit_can_go() {
+        let (readings, reasons) = parse_why(&row("1410, 2100, 1410, 71, 249.12, 250.00, 300.00, \
+            Active, Not Active, Not Active, Not Active, Not Active, Not Active, Not Active")).unwrap();
+
+        assert_eq!((readings.clock.as_str(), readings.max_clock.as_str()), ("1410", "2100"));
+        assert_eq!(reasons.len(), 1);
+
+        let (explanation, remedy) = explain(reasons[0], &readings);
+        assert!(explanation.contains("249.12 W against its 250 W"));
+        assert_eq!(remedy.as_deref(), Some("Raise the limit with 'power 300W', the most this GPU allows."));
+
+        let at_max = parse_why(&row("1410, 2100, 1410, 71, 299.8, 300.00, 300.00, Active, Not Active, Not Active, Not Active, Not Active, \
+            Not Active, Not Active")).unwrap();
+        assert!(explain(at_max.1[0], &at_max.0).1.unwrap().starts_with("The limit is already"));
+    }
+
+    #[test]
+    fn thermal_and_idle_rows_and_short_rows() {
+        let (readings, reasons) = parse_why(&row("600, 2100, [N/A], 88, 90.0, [N/A], [N/A], \
+            Not Active, Active, Not Active, Not Active, Not Active, Active, Not Active")).unwrap();
+
+        assert_eq!(reasons.iter().map(|r| r.field).collect::<Vec<&str>>(), vec!["sw_thermal_slowdown", "gpu_idle"]);
+        assert!(explain(reasons[0], &readings).0.starts_with("At 88 C"));
+        assert_eq!(explain(reasons[1], &readings).1, None);
+        assert_eq!(readings.power_limit, None);
+
+        assert!(parse_why(&row("600, 2100, 88")).is_none());
+    }
+}
@@ -0,0 +1,263 @@
+use crate::autotune::{GpuTuner, Offset, Tuner};
+use crate::config;
+use crate::executor::{Backend, Environment};
+use crate::{daemon, debug_message, headless, nvidiagpu, state};
+
+/*
+ * On Linux the voltage cannot be set directly, so an undervolt is a locked core clock plus a
+ * positive clock offset: the offset moves the whole voltage/frequency curve up, and the lock stops
+ * the GPU from climbing to the higher voltages. Either half alone does the wrong thing, so they are
+ * applied, checked and recorded as a pair:
+ *
+ *   undervolt --target-mhz 1800 --offset 150
+ *   undervolt --target-mhz 1800 --test "./gpu_burn 60" (--start-offset 200) (--step 15)
+ *   undervolt restore
+ *
+ * The guided form walks the offset down from --start-offset until the test passes. The pairs are
+ * recorded in undervolt.toml of the machine-wide state_dir, restore applies them again (e.g. after
+ * a reboot) and reset forgets the pair of the GPU it resets. Only root can write the record, so while
+ * the daemon runs the pair is sent to it to apply and record. The guided search itself stays with
+ * the caller, as the daemon must not run the caller's test as root.
+ */
+
+const DEFAULT_START_OFFSET: i32 = 200;
+const DEFAULT_STEP: i32 = 15;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Pair {
+    gpu: usize,
+    target: usize,
+    offset: i32,
+}
+
+const RECORD: &str = "undervolt.toml";
+
+fn parse(contents: &str) -> Vec<Pair> {
+    let pairs = config::parse_toml(contents).unwrap_or_default();
+    let value = |key: String| pairs.iter().find(|(k, _)| *k == key).map(|(_, v)| v.clone());
+
+    let mut gpus: Vec<usize> = pairs.iter()
+        .filter_map(|(k, _)| k.strip_prefix("gpu").and_then(|rest| rest.split_once('.')).and_then(|(n, _)| n.parse::<usize>().ok()))
+        .collect();
+    gpus.dedup();
+
+    gpus.into_iter()
+        .filter_map(|gpu| Some(Pair {
+            gpu,
+            target: value(format!("gpu{}.target_mhz", gpu))?.parse().ok()?,
+            offset: value(format!("gpu{}.offset", gpu))?.parse().ok()?,
+        }))
+        .collect()
+}
+
+fn format(pairs: &[Pair]) -> String {
+    let mut contents = String::from("# Written by teamgreenhelper undervolt, applied again by 'undervolt restore'\n");
+    for pair in pairs {
+        contents.push_str(&format!("\n[gpu{}]\ntarget_mhz = {}\noffset = {}\n", pair.gpu, pair.target, pair.offset));
+    }
+    contents
+}
+
+// Replaces the pair of gpu with pair, or removes it when pair is None
+fn update(env: &Environment, gpu: usize, pair: Option<Pair>) -> Result<(), String> {
+    state::update(env, RECORD, 0o644, |contents| {
+        let mut pairs: Vec<Pair> = parse(contents).into_iter().filter(|p| p.gpu != gpu).collect();
+        pairs.extend(pair);
+        pairs.sort_by_key(|p| p.gpu);
+        format(&pairs)
+    })
+    .map_err(|e| format!("Failed to record the undervolt in {}, only root can. Error: {}", state::path(env, RECORD).display(), e))
+}
+
+// Called by reset, which clears both halves of the pair
+pub fn forget(env: &Environment, gpu: &usize) -> Result<(), String> {
+    if !parse(&state::read(env, RECORD)).iter().any(|p| p.gpu == *gpu) {
+        return Ok(());
+    }
+
+    update(env, *gpu, None).map_err(|e| format!("GPU {} was reset, but 'undervolt restore' would still apply its pair. Reset it as root or while the \
+                                                  daemon runs to remove the pair. {}", gpu, e))
+}
+
+/*
+ * Locks the clock first, so the offset never raises an unlocked clock, then sets the offset. Both
+ * setters read their value back. If the offset fails the lock is removed again, so the GPU is never
+ * left with only half the pair.
+ */
+fn apply(env: &Environment, pair: &Pair) -> Result<(), String> {
+    lock(env, pair.gpu, pair.target)?;
+
+    if let Err(e) = set_offset(env, pair.gpu, pair.offset) {
+        let _ = unlock(env, pair.gpu);
+        return Err(format!("{} The clock lock was removed again.", e));
+    }
+
+    Ok(())
+}
+
+// The setters go through the daemon while it runs, see daemon::set_through
+fn lock(env: &Environment, gpu: usize, target: usize) -> Result<(), String> {
+    let mut index = gpu;
+    daemon::set_through(env, gpu, "clock", target.to_string(), || debug_message(env, nvidiagpu::lock_core(env, &mut index, target), "Locked Core Clock"))
+}
+
+fn unlock(env: &Environment, gpu: usize) -> Result<(), String> {
+    let mut index = gpu;
+    daemon::set_through(env, gpu, "clock", String::from("-1"), || debug_message(env, nvidiagpu::reset_core(env, &mut index), "Resetting Core Clock"))
+}
+
+fn set_offset(env: &Environment, gpu: usize, offset: i32) -> Result<(), String> {
+    let mut index = gpu;
+    daemon::set_through(env, gpu, "clockoffset", offset.to_string(), || debug_message(env, nvidiagpu::set_core_offset(env, &mut index, offset), "Clock Offset"))
+}
+
+fn undo(env: &Environment, gpu: usize) {
+    let _ = set_offset(env, gpu, 0);
+    let _ = unlock(env, gpu);
+}
+
+// Walks the offset down from start until the test passes, returning one step below that as a margin
+fn guided(env: &Environment, gpu: usize, target: usize, test: &str, start: i32, step: i32) -> Result<i32, String> {
+    lock(env, gpu, target)?;
+
+    let mut tuner = GpuTuner::new(env, gpu, test, Vec::new());
+    let mut offset = start;
+
+    loop {
+        tuner.apply(Offset::Core, offset)?;
+
+        match tuner.test() {
+            Ok(()) => {
+                println!("{} MHz +{} MHz: stable", target, offset);
+                return Ok((offset - step).max(0));
+            },
+            Err(reason) => println!("{} MHz +{} MHz: failed, {}", target, offset, reason),
+        }
+
+        if offset == 0 {
+            return Err(format!("GPU {} is not stable at {} MHz even without an offset. Try a lower --target-mhz.", gpu, target));
+        }
+        offset = (offset - step).max(0);
+    }
+}
+
+fn restore(env: &Environment) -> Result<(), String> {
+    let pairs = parse(&state::read(env, RECORD));
+    if pairs.is_empty() {
+        println!("No undervolt is recorded.");
+        return Ok(());
+    }
+
+    let mut failed = Vec::new();
+    for pair in pairs {
+        match apply(env, &pair) {
+            Ok(()) => println!("GPU {}: locked to {} MHz with +{} MHz.", pair.gpu, pair.target, pair.offset),
+            Err(e) => failed.push(e),
+        }
+    }
+
+    if failed.is_empty() { Ok(()) } else { Err(failed.join("\n")) }
+}
+
+pub fn run(env: &mut Environment, gpu: &usize, args: &[&String]) -> Result<(), String> {
+    if let [action] = args {
+        if action.as_str() != "restore" {
+            return Err(format!("'{}' is not an undervolt action. Try 'undervolt restore'.", action));
+        }
+        headless::ensure(env)?;
+        return restore(env);
+    }
+
+    let mut target = None;
+    let mut offset = None;
+    let mut test = None;
+    let mut start = DEFAULT_START_OFFSET;
+    let mut step = DEFAULT_STEP;
+
+    for pair in args.chunks(2) {
+        let (flag, value) = match pair {
+            [flag, value] => (flag.as_str(), value.as_str()),
+            _ => return Err(format!("'{}' needs a value.", pair[0])),
+        };
+        let mhz = || value.trim_start_matches('+').trim_end_matches("MHz").parse::<i32>().ok().filter(|n| *n >= 0)
+            .ok_or_else(|| format!("'{}' is not a number of MHz.", value));
+
+        match flag {
+            "--target-mhz" => target = Some(mhz()? as usize),
+            "--offset" => offset = Some(mhz()?),
+            "--test" => test = Some(value.to_string()),
+            "--start-offset" => start = mhz()?,
+            "--step" => step = mhz()?.max(1),
+            _ => return Err(format!("'{}' is not an undervolt option. Expected --target-mhz, --offset, --test, --start-offset or --step.", flag)),
+        }
+    }
+
+    let target = target.ok_or_else(|| String::from("undervolt needs a clock to lock to, e.g. undervolt --target-mhz 1800 --offset 150"))?;
+    if env.limits.max_core_clock.is_some_and(|max| target as f64 > max) {
+        return Err(format!("{} MHz is above the configured limit of {} MHz (limits.max_core_clock).", target, env.limits.max_core_clock.unwrap_or_default()));
+    }
+
+    headless::ensure(env)?;
+
+    let guided_search = test.is_some();
+    let offset = match (offset, test) {
+        (Some(offset), None) => offset,
+        (None, Some(test)) => {
+            if env.backend == Backend::DryRun {
+                return Err(String::from("The guided undervolt has to run its test against real settings, so it does not work with backend = dry-run."));
+            }
+            guided(env, *gpu, target, &test, start, step).inspect_err(|_| undo(env, *gpu))?
+        },
+        _ => return Err(String::from("undervolt needs either --offset, or --test to find one.")),
+    };
+
+    // The search has found its pair, which the daemon applies and records while it runs
+    if guided_search && !env.in_daemon && env.backend != Backend::DryRun {
+        let args = [String::from("--target-mhz"), target.to_string(), String::from("--offset"), offset.to_string()];
+        if let Some(result) = daemon::forward(env, "undervolt", &args.iter().collect::<Vec<&String>>(), gpu) {
+            return result.map(|_| println!("GPU {} is locked to {} MHz with a +{} MHz clock offset.", gpu, target, offset));
+        }
+    }
+
+    let pair = Pair { gpu: *gpu, target, offset };
+    if let Err(e) = apply(env, &pair) {
+        undo(env, *gpu);
+        return Err(format!("{} The undervolt was undone.", e));
+    }
+
+    println!("GPU {} is locked to {} MHz with a +{} MHz clock offset.", gpu, target, offset);
+
+    if env.backend != Backend::DryRun {
+        // Without the daemon only root can record it, the undervolt itself is already applied
+        update(env, pair.gpu, Some(pair)).map_err(|e| format!("The undervolt is applied but restore will not know it. {}", e))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::private_temp_dir;
+
+    #[test]
+    fn pairs_are_recorded_per_gpu_in_the_state_dir_and_forgotten_by_reset() {
+        let dir = private_temp_dir("teamgreenhelper-undervolt").unwrap();
+        let mut env = Environment::default();
+        env.config.set("state_dir", &dir.to_string_lossy(), config::Source::CommandLine);
+
+        update(&env, 1, Some(Pair { gpu: 1, target: 1800, offset: 150 })).unwrap();
+        update(&env, 0, Some(Pair { gpu: 0, target: 1700, offset: 120 })).unwrap();
+        update(&env, 1, Some(Pair { gpu: 1, target: 1800, offset: 135 })).unwrap();
+
+        let pairs = parse(&state::read(&env, RECORD));
+        assert_eq!(pairs.iter().map(|p| (p.gpu, p.target, p.offset)).collect::<Vec<_>>(), vec![(0, 1700, 120), (1, 1800, 135)]);
+
+        forget(&env, &0).unwrap();
+        forget(&env, &3).unwrap();
+        let pairs = parse(&state::read(&env, RECORD));
+        assert_eq!(pairs.iter().map(|p| p.gpu).collect::<Vec<_>>(), vec![1]);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,250 @@
+use std::time::Duration;
+
+/*
+ * Parsing for setter values that may carry a unit or be relative to the GPU's current state, and
+ * for the durations and sizes that long running commands take.
+ *
+ * A leading + or - makes a value relative to whatever the GPU is currently at, and a trailing %
+ * scales the current value instead of replacing it. Offsets are the exception: they are already
+ * deltas, so their sign is simply part of the offset.
+ */
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Unit {
+    Watts,
+    Megahertz,
+    Percent,
+}
+
+impl Unit {
+    fn suffix(&self) -> &'static str {
+        match self {
+            Unit::Watts => "w",
+            Unit::Megahertz => "mhz",
+            Unit::Percent => "%",
+        }
+    }
+
+    fn example(&self) -> &'static str {
+        match self {
+            Unit::Watts => "watts, e.g. 250W, +25W or -10%",
+            Unit::Megahertz => "MHz, e.g. 1500MHz, +100MHz or -50",
+            Unit::Percent => "a percentage between 0 and 100, e.g. 75%",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Value {
+    // 250W, 1500
+    Absolute(f64),
+    // +25W, -50
+    Relative(f64),
+    // 80%, -10% (stored as the factor to multiply the current value by)
+    Scaled(f64),
+}
+
+impl Value {
+    /*
+     * Turns the value into an absolute number. current is only called for relative values,
+     * so absolute values never cost an nvidia-smi query.
+     */
+    pub fn resolve<F: FnOnce() -> Option<f64>>(self, current: F) -> Option<f64> {
+        match self {
+            Value::Absolute(n) => Some(n),
+            Value::Relative(n) => current().map(|c| c + n),
+            Value::Scaled(f) => current().map(|c| c * f),
+        }
+    }
+}
+
+struct Token {
+    signed: bool,
+    number: f64,
+    suffix: String,
+}
+
+fn tokenize(input: &str, unit: Unit) -> Result<Token, String> {
+    let trimmed = input.trim();
+    let signed = trimmed.starts_with('+') || trimmed.starts_with('-');
+    let split = trimmed
+        .char_indices()
+        .skip(if signed { 1 } else { 0 })
+        .find(|(_, c)| !c.is_ascii_digit() && *c != '.')
+        .map_or(trimmed.len(), |(i, _)| i);
+
+    let (number, suffix) = trimmed.split_at(split);
+
+    match number.trim_start_matches('+').parse::<f64>() {
+        Ok(n) if n.is_finite() => Ok(Token { signed, number: n, suffix: suffix.to_string() }),
+        _ => Err(format!("'{}' is not a valid value. Expected {}.", input, unit.example())),
+    }
+}
+
+pub fn parse_value(input: &str, unit: Unit) -> Result<Value, String> {
+    let token = tokenize(input, unit)?;
+
+    // A percentage is already the whole value, +10% would read as either 10% or ten points up
+    if unit == Unit::Percent && token.signed {
+        return Err(format!("'{}' is signed, but fan speeds are not relative. Expected {}.", input, unit.example()));
+    }
+
+    if token.suffix.is_empty() || token.suffix.eq_ignore_ascii_case(unit.suffix()) {
+        if !token.signed {
+            Ok(Value::Absolute(token.number))
+        } else {
+            Ok(Value::Relative(token.number))
+        }
+    } else if token.suffix == "%" {
+        if token.signed {
+            Ok(Value::Scaled(1.0 + token.number / 100.0))
+        } else {
+            Ok(Value::Scaled(token.number / 100.0))
+        }
+    } else {
+        Err(format!("'{}' has an unknown unit '{}'. Expected {}.", input, token.suffix, unit.example()))
+    }
+}
+
+/*
+ * Offsets are signed deltas, so +100MHz and 100 mean the same thing. nvidia-settings takes the
+ * memory offset as a transfer rate, which is double the memory clock, so a value given in MHz is
+ * doubled for it. Bare numbers are passed through untouched for compatibility with older scripts.
+ */
+pub fn parse_offset(input: &str, transfer_rate: bool) -> Result<i32, String> {
+    let token = tokenize(input, Unit::Megahertz)?;
+
+    if token.number.fract() != 0.0 {
+        return Err(format!("'{}' is not a whole number. Expected {}.", input, Unit::Megahertz.example()));
+    }
+
+    let out_of_range = || format!("'{}' is not a valid offset. It is far outside anything a GPU accepts.", input);
+    if token.number.abs() > i32::MAX as f64 {
+        return Err(out_of_range());
+    }
+    let offset = token.number as i32;
+
+    if token.suffix.is_empty() {
+        Ok(offset)
+    } else if token.suffix.eq_ignore_ascii_case(Unit::Megahertz.suffix()) {
+        if transfer_rate { offset.checked_mul(2).ok_or_else(out_of_range) } else { Ok(offset) }
+    } else {
+        Err(format!("'{}' has an unknown unit '{}'. Offsets are given in MHz, e.g. +100MHz or -50.", input, token.suffix))
+    }
+}
+
+// Splits 5s or 100MB into the number and its lower case suffix
+fn split_suffix(input: &str) -> Option<(f64, String)> {
+    let trimmed = input.trim();
+    let split = trimmed.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(trimmed.len());
+    let number = trimmed[..split].parse::<f64>().ok().filter(|n| n.is_finite())?;
+    Some((number, trimmed[split..].trim().to_lowercase()))
+}
+
+// 500ms, 5s, 10m, 1h or 1d. A bare number is in seconds.
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+    let seconds = match split_suffix(input) {
+        Some((n, suffix)) => match suffix.as_str() {
+            "ms" => n / 1000.0,
+            "" | "s" => n,
+            "m" | "min" => n * 60.0,
+            "h" => n * 3600.0,
+            "d" => n * 86400.0,
+            _ => -1.0,
+        },
+        None => -1.0,
+    };
+
+    if seconds <= 0.0 {
+        return Err(format!("'{}' is not a valid duration. Expected e.g. 500ms, 5s, 10m, 1h or 1d.", input));
+    }
+
+    Duration::try_from_secs_f64(seconds).map_err(|_| format!("'{}' is too long a duration.", input))
+}
+
+// 512K, 100M or 1G, with an optional B. A bare number is in bytes.
+pub fn parse_size(input: &str) -> Result<u64, String> {
+    let bytes = match split_suffix(input) {
+        Some((n, suffix)) => match suffix.trim_end_matches('b') {
+            "" => n,
+            "k" => n * 1024.0,
+            "m" => n * 1024.0 * 1024.0,
+            "g" => n * 1024.0 * 1024.0 * 1024.0,
+            _ => -1.0,
+        },
+        None => -1.0,
+    };
+
+    if bytes < 1.0 {
+        return Err(format!("'{}' is not a valid size. Expected e.g. 512K, 100M or 1G.", input));
+    }
+
+    Ok(bytes as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_with_and_without_units_are_absolute() {
+        assert_eq!(parse_value("250", Unit::Watts), Ok(Value::Absolute(250.0)));
+        assert_eq!(parse_value("250W", Unit::Watts), Ok(Value::Absolute(250.0)));
+        assert_eq!(parse_value("1500mhz", Unit::Megahertz), Ok(Value::Absolute(1500.0)));
+        assert_eq!(parse_value("75%", Unit::Percent), Ok(Value::Absolute(75.0)));
+    }
+
+    #[test]
+    fn signed_percentages_are_refused() {
+        assert!(parse_value("+10%", Unit::Percent).is_err());
+        assert!(parse_value("-10", Unit::Percent).is_err());
+        assert_eq!(parse_value("10", Unit::Percent), Ok(Value::Absolute(10.0)));
+    }
+
+    #[test]
+    fn signed_values_are_relative_and_percentages_scale() {
+        assert_eq!(parse_value("+25W", Unit::Watts), Ok(Value::Relative(25.0)));
+        assert_eq!(parse_value("-50", Unit::Megahertz), Ok(Value::Relative(-50.0)));
+        assert_eq!(parse_value("-10%", Unit::Watts), Ok(Value::Scaled(0.9)));
+        assert_eq!(parse_value("80%", Unit::Megahertz), Ok(Value::Scaled(0.8)));
+        assert_eq!(Value::Scaled(0.5).resolve(|| Some(300.0)), Some(150.0));
+        assert_eq!(Value::Relative(25.0).resolve(|| None), None);
+    }
+
+    #[test]
+    fn unknown_units_and_garbage_are_rejected() {
+        assert!(parse_value("250MHz", Unit::Watts).is_err());
+        assert!(parse_value("fast", Unit::Megahertz).is_err());
+        assert!(parse_value("", Unit::Watts).is_err());
+    }
+
+    #[test]
+    fn memory_offsets_in_mhz_are_doubled() {
+        assert_eq!(parse_offset("+750MHz", true), Ok(1500));
+        assert_eq!(parse_offset("750", true), Ok(750));
+        assert_eq!(parse_offset("-100MHz", false), Ok(-100));
+        assert!(parse_offset("10.5", false).is_err());
+        assert!(parse_offset("100W", false).is_err());
+    }
+
+    #[test]
+    fn offsets_out_of_range_are_rejected() {
+        assert!(parse_offset("2000000000MHz", true).is_err());
+        assert!(parse_offset("99999999999", false).is_err());
+        assert_eq!(parse_offset("2000000000", false), Ok(2000000000));
+    }
+
+    #[test]
+    fn durations_and_sizes_take_their_suffixes() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_duration("2"), Ok(Duration::from_secs(2)));
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("5y").is_err());
+        assert!(parse_duration("1e300d").is_err());
+        assert!(parse_duration("inf").is_err());
+        assert_eq!(parse_size("512K"), Ok(512 * 1024));
+        assert_eq!(parse_size("1GB"), Ok(1024 * 1024 * 1024));
+        assert!(parse_size("0").is_err());
+    }
+}
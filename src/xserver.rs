@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::config::Source;
+use crate::executor::Environment;
+
+/*
+ * Finds running X servers by reading /proc rather than parsing ps output, so that it works the same
+ * under gdm, sddm, lightdm and startx:
+ *
+ *   gdm      /usr/lib/xorg/Xorg vt2 -displayfd 3 -auth /run/user/1000/gdm/Xauthority ...
+ *   sddm     /usr/bin/X -auth /var/run/sddm/{uuid} -displayfd 17 -seat seat0 vt1
+ *   lightdm  /usr/lib/xorg/Xorg :0 -seat seat0 -auth /var/run/lightdm/root/:0 ...
+ *   startx   /usr/lib/Xorg :1 vt1 -keeptty -auth /tmp/serverauth.XXXXXXXXXX
+ *
+ * The display comes from the command line when it is there. Servers started with -displayfd only
+ * tell their parent, so for those the /tmp/.X11-unix/X<n> socket the server holds open is looked
+ * up in /proc/net/unix, and failing that a client's DISPLAY is used. A server without -auth falls
+ * back to the XAUTHORITY of its own environment or of one of its clients.
+ */
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct XServer {
+    pub pid: u32,
+    pub executable: String,
+    pub display: Option<String>,
+    pub xauthority: Option<String>,
+    pub seat: Option<String>,
+}
+
+impl XServer {
+    // nvidia-settings needs NV-CONTROL, which Xwayland does not provide
+    pub fn is_xwayland(&self) -> bool {
+        self.executable == "Xwayland"
+    }
+}
+
+const SERVER_NAMES: [&str; 4] = ["Xorg", "X", "Xorg.bin", "Xwayland"];
+
+struct Process {
+    pid: u32,
+    args: Vec<String>,
+    environ: HashMap<String, String>,
+}
+
+fn read_nul_separated(path: &Path) -> Vec<String> {
+    match fs::read(path) {
+        Ok(bytes) => bytes
+            .split(|b| *b == 0)
+            .filter(|part| !part.is_empty())
+            .map(|part| String::from_utf8_lossy(part).into_owned())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn read_processes(proc_root: &Path) -> Vec<Process> {
+    let entries = match fs::read_dir(proc_root) {
+        Ok(e) => e,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut processes: Vec<Process> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let pid = entry.file_name().to_str()?.parse::<u32>().ok()?;
+            let args = read_nul_separated(&entry.path().join("cmdline"));
+
+            // Kernel threads have an empty command line
+            if args.is_empty() {
+                return None;
+            }
+
+            // environ is only readable for our own processes unless we are root
+            let environ = read_nul_separated(&entry.path().join("environ"))
+                .into_iter()
+                .filter_map(|pair| pair.split_once('=').map(|(k, v)| (k.to_string(), v.to_string())))
+                .collect();
+
+            Some(Process { pid, args, environ })
+        })
+        .collect();
+
+    processes.sort_by_key(|p| p.pid);
+    processes
+}
+
+fn executable_name(arg0: &str) -> &str {
+    arg0.rsplit('/').next().unwrap_or(arg0)
+}
+
+fn is_display(arg: &str) -> bool {
+    arg.len() > 1 && arg.starts_with(':') && arg[1..].chars().all(|c| c.is_ascii_digit() || c == '.')
+}
+
+fn argument_after(args: &[String], flag: &str) -> Option<String> {
+    args.iter().position(|a| a == flag).and_then(|i| args.get(i + 1)).cloned()
+}
+
+// Maps socket inodes to X display numbers from the /tmp/.X11-unix/X<n> entries of /proc/net/unix
+fn read_x11_sockets(proc_root: &Path) -> HashMap<String, String> {
+    let mut sockets = HashMap::new();
+
+    let contents = match fs::read_to_string(proc_root.join("net").join("unix")) {
+        Ok(c) => c,
+        Err(_) => return sockets,
+    };
+
+    for line in contents.lines().skip(1) {
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        if columns.len() < 8 {
+            continue;
+        }
+
+        let path = columns[7].trim_start_matches('@');
+        if let Some(number) = path.strip_prefix("/tmp/.X11-unix/X") {
+            if number.chars().all(|c| c.is_ascii_digit()) && !number.is_empty() {
+                sockets.insert(columns[6].to_string(), format!(":{}", number));
+            }
+        }
+    }
+
+    sockets
+}
+
+fn display_from_sockets(proc_root: &Path, pid: u32, sockets: &HashMap<String, String>) -> Option<String> {
+    let fds = fs::read_dir(proc_root.join(pid.to_string()).join("fd")).ok()?;
+
+    let mut displays: Vec<String> = fds
+        .filter_map(|fd| fd.ok())
+        .filter_map(|fd| fs::read_link(fd.path()).ok())
+        .filter_map(|target| {
+            let target = target.to_string_lossy().into_owned();
+            let inode = target.strip_prefix("socket:[")?.strip_suffix(']')?.to_string();
+            sockets.get(&inode).cloned()
+        })
+        .collect();
+
+    displays.sort();
+    displays.into_iter().next()
+}
+
+pub fn scan(proc_root: &Path) -> Vec<XServer> {
+    let processes = read_processes(proc_root);
+    let sockets = read_x11_sockets(proc_root);
+
+    processes
+        .iter()
+        .filter(|p| SERVER_NAMES.contains(&executable_name(&p.args[0])))
+        .map(|p| {
+            let xauthority = argument_after(&p.args, "-auth").or_else(|| p.environ.get("XAUTHORITY").cloned());
+
+            let display = p.args.iter().skip(1).find(|a| is_display(a)).cloned()
+                .or_else(|| display_from_sockets(proc_root, p.pid, &sockets))
+                .or_else(|| {
+                    // A client that shares the server's cookie file knows which display it is
+                    let auth = xauthority.as_ref()?;
+                    processes.iter()
+                        .filter(|c| c.environ.get("XAUTHORITY") == Some(auth))
+                        .find_map(|c| c.environ.get("DISPLAY").cloned())
+                });
+
+            let xauthority = xauthority.or_else(|| {
+                let display = display.as_ref()?;
+                processes.iter()
+                    .filter(|c| c.environ.get("DISPLAY") == Some(display))
+                    .find_map(|c| c.environ.get("XAUTHORITY").cloned())
+            });
+
+            XServer {
+                pid: p.pid,
+                executable: executable_name(&p.args[0]).to_string(),
+                display,
+                xauthority,
+                seat: argument_after(&p.args, "-seat"),
+            }
+        })
+        .collect()
+}
+
+/*
+ * Picks the server nvidia-settings should talk to. If a display was configured, that one wins.
+ * Otherwise Xwayland is skipped, as it has no NV-CONTROL and the headless fallback can do better,
+ * and the first seat is preferred, then the lowest display number.
+ */
+pub fn select<'a>(servers: &'a [XServer], display: Option<&str>) -> Option<&'a XServer> {
+    if let Some(d) = display {
+        return servers.iter().find(|s| s.display.as_deref() == Some(d));
+    }
+
+    servers.iter().filter(|s| !s.is_xwayland()).min_by_key(|s| {
+        (
+            s.seat.as_deref().is_some_and(|seat| seat != "seat0"),
+            s.display.as_deref().and_then(|d| d.strip_prefix(':')?.split('.').next()?.parse::<u32>().ok()).unwrap_or(u32::MAX),
+        )
+    })
+}
+
+// Fills in the display and Xauthority that no config layer or argument has set
+pub fn discover(env: &mut Environment) {
+    let display_set = env.config.source("display") != Source::Default;
+    let xauthority_set = env.config.source("xauthority") != Source::Default;
+
+    if display_set && xauthority_set {
+        env.x_found = true;
+        return;
+    }
+
+    let servers = scan(Path::new("/proc"));
+
+    if env.debug {
+        for server in &servers {
+            println!("Found {} (pid {}) on {} seat {} with Xauthority {}", server.executable, server.pid,
+                     server.display.as_deref().unwrap_or("an unknown display"), server.seat.as_deref().unwrap_or("-"),
+                     server.xauthority.as_deref().unwrap_or("unknown"));
+        }
+    }
+
+    let server = match select(&servers, if display_set { Some(&env.display) } else { None }) {
+        Some(s) => s,
+        None => {
+            // A configured display may be on another machine, so it is trusted without a local server
+            env.x_found = display_set;
+            return;
+        }
+    };
+
+    env.x_found = true;
+
+    if !display_set {
+        if let Some(display) = &server.display {
+            env.display = display.clone();
+            env.config.set("display", display, Source::Discovered);
+        }
+    }
+
+    if !xauthority_set {
+        if let Some(xauthority) = &server.xauthority {
+            env.xauthority = xauthority.clone();
+            env.config.set("xauthority", xauthority, Source::Discovered);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+
+    struct FixtureProc {
+        root: PathBuf,
+    }
+
+    impl FixtureProc {
+        fn new(name: &str) -> FixtureProc {
+            let root = std::env::temp_dir().join(format!("tgh-proc-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(root.join("net")).unwrap();
+            fs::write(root.join("net").join("unix"), "Num       RefCount Protocol Flags    Type St Inode Path\n").unwrap();
+            FixtureProc { root }
+        }
+
+        fn process(&self, pid: u32, cmdline: &[&str], environ: &[&str]) -> &FixtureProc {
+            let dir = self.root.join(pid.to_string());
+            fs::create_dir_all(dir.join("fd")).unwrap();
+            fs::write(dir.join("cmdline"), cmdline.join("\0") + "\0").unwrap();
+            fs::write(dir.join("environ"), environ.join("\0") + "\0").unwrap();
+            self
+        }
+
+        fn socket(&self, pid: u32, fd: u32, inode: u32, path: &str) -> &FixtureProc {
+            symlink(format!("socket:[{}]", inode), self.root.join(pid.to_string()).join("fd").join(fd.to_string())).unwrap();
+            let mut unix = fs::read_to_string(self.root.join("net").join("unix")).unwrap();
+            unix.push_str(&format!("0000000000000000: 00000002 00000000 00010000 0001 01 {} {}\n", inode, path));
+            fs::write(self.root.join("net").join("unix"), unix).unwrap();
+            self
+        }
+    }
+
+    impl Drop for FixtureProc {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn gdm_display_comes_from_the_listening_socket() {
+        let proc = FixtureProc::new("gdm");
+        proc.process(1200, &["/usr/lib/xorg/Xorg", "vt2", "-displayfd", "3", "-auth", "/run/user/1000/gdm/Xauthority", "-nolisten", "tcp"], &[])
+            .socket(1200, 5, 41234, "@/tmp/.X11-unix/X1")
+            .socket(1200, 6, 41235, "/tmp/.X11-unix/X1")
+            .process(1300, &["/usr/bin/gnome-shell"], &["DISPLAY=:1", "XAUTHORITY=/run/user/1000/gdm/Xauthority"]);
+
+        let servers = scan(&proc.root);
+
+        assert_eq!(servers, vec![XServer {
+            pid: 1200,
+            executable: String::from("Xorg"),
+            display: Some(String::from(":1")),
+            xauthority: Some(String::from("/run/user/1000/gdm/Xauthority")),
+            seat: None,
+        }]);
+    }
+
+    #[test]
+    fn sddm_display_falls_back_to_a_client_with_the_same_cookie() {
+        let proc = FixtureProc::new("sddm");
+        proc.process(800, &["/usr/bin/X", "-nolisten", "tcp", "-auth", "/var/run/sddm/{0f1e}", "-background", "none", "-noreset", "-displayfd", "17", "-seat", "seat0", "vt1"], &[])
+            .process(900, &["/usr/bin/sddm-greeter"], &["DISPLAY=:0", "XAUTHORITY=/var/run/sddm/{0f1e}"]);
+
+        let servers = scan(&proc.root);
+
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].executable, "X");
+        assert_eq!(servers[0].display.as_deref(), Some(":0"));
+        assert_eq!(servers[0].xauthority.as_deref(), Some("/var/run/sddm/{0f1e}"));
+        assert_eq!(servers[0].seat.as_deref(), Some("seat0"));
+    }
+
+    #[test]
+    fn lightdm_multiseat_prefers_the_first_seat() {
+        let proc = FixtureProc::new("lightdm");
+        proc.process(700, &["/usr/lib/xorg/Xorg", "-core", ":1", "-seat", "seat1", "-auth", "/var/run/lightdm/root/:1", "-nolisten", "tcp", "vt8"], &[])
+            .process(650, &["/usr/lib/xorg/Xorg", "-core", ":0", "-seat", "seat0", "-auth", "/var/run/lightdm/root/:0", "-nolisten", "tcp", "vt7"], &[])
+            .process(10, &["/sbin/init"], &[]);
+
+        let servers = scan(&proc.root);
+        assert_eq!(servers.len(), 2);
+
+        let chosen = select(&servers, None).unwrap();
+        assert_eq!(chosen.display.as_deref(), Some(":0"));
+        assert_eq!(chosen.xauthority.as_deref(), Some("/var/run/lightdm/root/:0"));
+
+        let configured = select(&servers, Some(":1")).unwrap();
+        assert_eq!(configured.seat.as_deref(), Some("seat1"));
+        assert_eq!(configured.xauthority.as_deref(), Some("/var/run/lightdm/root/:1"));
+
+        assert_eq!(select(&servers, Some(":2")), None);
+    }
+
+    #[test]
+    fn xwayland_is_never_picked_for_nvidia_settings() {
+        let proc = FixtureProc::new("startx");
+        proc.process(2000, &["/usr/bin/Xwayland", ":0", "-rootless", "-auth", "/run/user/1000/.mutter-Xwaylandauth.AB12", "-listen", "4"], &[])
+            .process(2100, &["xinit", "/home/tim/.xinitrc", "--", "/usr/bin/X", ":1", "vt1", "-keeptty", "-auth", "/tmp/serverauth.Zx9"], &[])
+            .process(2101, &["/usr/lib/Xorg", ":1", "vt1", "-keeptty", "-auth", "/tmp/serverauth.Zx9"], &[]);
+
+        let servers = scan(&proc.root);
+        assert_eq!(servers.iter().map(|s| s.pid).collect::<Vec<u32>>(), vec![2000, 2101]);
+        assert!(servers[0].is_xwayland());
+
+        let chosen = select(&servers, None).unwrap();
+        assert_eq!(chosen.pid, 2101);
+        assert_eq!(chosen.display.as_deref(), Some(":1"));
+        assert_eq!(chosen.xauthority.as_deref(), Some("/tmp/serverauth.Zx9"));
+        assert!(select(&servers[..1], None).is_none());
+    }
+
+    #[test]
+    fn missing_auth_argument_uses_the_environment() {
+        let proc = FixtureProc::new("environ");
+        proc.process(300, &["Xorg", ":2"], &["HOME=/root", "XAUTHORITY=/root/.Xauthority"])
+            .process(400, &["Xorg", ":3"], &[])
+            .process(401, &["xterm"], &["DISPLAY=:3", "XAUTHORITY=/home/tim/.Xauthority"]);
+
+        let servers = scan(&proc.root);
+
+        assert_eq!(servers[0].xauthority.as_deref(), Some("/root/.Xauthority"));
+        assert_eq!(servers[1].xauthority.as_deref(), Some("/home/tim/.Xauthority"));
+    }
+
+    #[test]
+    fn unreadable_or_empty_proc_finds_nothing() {
+        assert!(scan(Path::new("/nonexistent/proc")).is_empty());
+
+        let proc = FixtureProc::new("empty");
+        proc.process(1, &["/sbin/init"], &[]);
+        assert!(scan(&proc.root).is_empty());
+        assert_eq!(select(&[], None), None);
+    }
+
+    #[test]
+    fn select_skips_displays_that_are_empty_or_malformed() {
+        let server = |pid: u32, display: &str| XServer {
+            pid,
+            executable: String::from("Xorg"),
+            display: Some(display.to_string()),
+            xauthority: None,
+            seat: None,
+        };
+        let servers = vec![server(1, ""), server(2, "é:0"), server(3, ":2")];
+
+        assert_eq!(select(&servers, None).map(|s| s.pid), Some(3));
+    }
+}